@@ -95,19 +95,25 @@ impl DataSource {
                         app_contains,
                         message_matches,
                         message_not_matches,
+                        tags,
                         max_results,
                     } => {
                         let query = db_handle
-                            .query(eigenlog::QueryParams {
-                                max_log_level,
-                                start_timestamp,
-                                end_timestamp,
-                                host_contains,
-                                app_contains,
-                                message_matches,
-                                message_not_matches,
-                                max_results,
-                            })
+                            .query(
+                                eigenlog::QueryParams {
+                                    max_log_level,
+                                    start_timestamp,
+                                    end_timestamp,
+                                    host_contains,
+                                    app_contains,
+                                    message_matches,
+                                    message_not_matches,
+                                    tags,
+                                    max_results,
+                                },
+                                // reading the database directly isn't limited to any API key's scope
+                                &Default::default(),
+                            )
                             .await?;
 
                         Ok(query.into())
@@ -123,6 +129,7 @@ impl DataSource {
                     proxy: eigenlog::BasicProxy::init("123".to_string()),
                     base_url,
                     serialization_format: eigenlog::SerializationFormat::Bincode,
+                    compression: None,
                 };
                 let client = reqwest::Client::new();
                 match cmd {
@@ -142,6 +149,7 @@ impl DataSource {
                         app_contains,
                         message_matches,
                         message_not_matches,
+                        tags,
                         max_results,
                     } => {
                         let query = api_config
@@ -155,6 +163,7 @@ impl DataSource {
                                     app_contains,
                                     message_matches,
                                     message_not_matches,
+                                    tags,
                                     max_results,
                                 },
                                 #[cfg(not(feature = "wasm"))]
@@ -287,6 +296,8 @@ enum Cmd {
         message_matches: Option<String>,
         #[structopt(short = "n", long = "not_matches")]
         message_not_matches: Option<String>,
+        #[structopt(short = "t", long = "tags")]
+        tags: Option<eigenlog::TagFilter>,
         #[structopt(short = "r", long = "rows")]
         max_results: Option<usize>,
     },
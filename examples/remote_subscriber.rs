@@ -12,6 +12,7 @@ async fn main() -> anyhow::Result<()> {
         base_url: reqwest::Url::parse("http://127.0.0.1:8080/log")?,
         proxy: eigenlog::BasicProxy::init("123".to_string()),
         serialization_format: eigenlog::SerializationFormat::Bincode,
+        compression: None,
     };
     let host = "local"
         .parse::<eigenlog::Host>()
@@ -20,7 +21,7 @@ async fn main() -> anyhow::Result<()> {
         .parse::<eigenlog::App>()
         .map_err(|e| anyhow::anyhow!(e))?;
 
-    let (subscriber, data_sender) = subscriber::Subscriber::new_remote(
+    let (subscriber, data_sender, shutdown_handle) = subscriber::Subscriber::new_remote(
         error_handler,
         api_config,
         host,
@@ -32,6 +33,7 @@ async fn main() -> anyhow::Result<()> {
             info: 100,
             error: 1,
             warn: 1,
+            ..Default::default()
         },
     );
 
@@ -47,24 +49,23 @@ async fn main() -> anyhow::Result<()> {
             tokio::time::sleep(std::time::Duration::from_millis(1)).await;
         }
         println!("Generated 10000 logs");
-        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
     };
 
-    match future::select(Box::pin(log_generator), Box::pin(data_sender.run())).await {
-        future::Either::Left((_, _)) => {
-            eprintln!("Log generator exited");
-        }
-        future::Either::Right((_, _)) => {
-            eprintln!("Data sender exited");
-        }
-    }
-
-    // tokio::spawn(async move {
-    //     log_generator.await;
-    //     // process::exit(1);
-    // });
-
-    // data_sender.run().await;
+    // the data sender runs until the shutdown has sent what is left
+    let shutdown = async {
+        log_generator.await;
+        shutdown_handle
+            .shutdown(std::time::Duration::from_secs(10))
+            .await
+    };
+    let (_, report) = future::join(data_sender.run(), shutdown).await;
+    let report = report?;
+    eprintln!(
+        "Shut down with {} log messages undelivered, {} spilled and {} abandoned",
+        report.undelivered.len(),
+        report.spilled,
+        report.abandoned
+    );
 
     Ok(())
 }
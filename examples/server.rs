@@ -14,13 +14,62 @@ async fn main() -> anyhow::Result<()> {
         IntoIterator::into_iter(["123".to_string()]).collect::<collections::BTreeSet<String>>(),
     );
 
+    let hub = server::TailHub::new(1024);
+
     let info = server::create_info_endpoint(db.clone(), api_keys.clone());
-    let submit = server::create_submission_endpoint(db.clone(), api_keys.clone());
+    let limits = server::RateLimiter::unlimited();
+    let dedup = server::DedupWindow::default();
+    let submit = server::create_submission_endpoint(
+        hub.storage(db.clone()),
+        api_keys.clone(),
+        limits.clone(),
+        dedup.clone(),
+    );
+    let submit_batch = server::create_submit_batch_endpoint(
+        hub.storage(db.clone()),
+        api_keys.clone(),
+        limits,
+        dedup,
+    );
     let query = server::create_query_endpoint(db.clone(), api_keys.clone());
     let detail = server::create_detail_endpoint(db.clone(), api_keys.clone());
+    #[cfg(feature = "otlp")]
+    let submit_batch = submit_batch.or(server::create_otlp_endpoint(
+        hub.storage(db.clone()),
+        api_keys.clone(),
+        server::RateLimiter::unlimited(),
+    ));
+    #[cfg(feature = "loki")]
+    let submit_batch = submit_batch.or(server::create_loki_endpoint(
+        hub.storage(db.clone()),
+        api_keys.clone(),
+        server::RateLimiter::unlimited(),
+    ));
+    #[cfg(feature = "syslog")]
+    {
+        let syslog = server::SyslogListener::new(hub.storage(db.clone()));
+        tokio::spawn(syslog.clone().run_udp("127.0.0.1:5514"));
+        tokio::spawn(syslog.run_tcp("127.0.0.1:5514"));
+    }
+    #[cfg(feature = "gelf")]
+    {
+        let gelf =
+            server::GelfListener::new(hub.storage(db.clone())).with_app_field("_container_name");
+        tokio::spawn(gelf.clone().run_udp("127.0.0.1:12201"));
+        tokio::spawn(gelf.run_tcp("127.0.0.1:12201"));
+    }
+    #[cfg(feature = "fluent")]
+    tokio::spawn(server::FluentListener::new(hub.storage(db.clone())).run("127.0.0.1:24224"));
+    let tail = server::create_tail_endpoint(hub, api_keys.clone());
     warp::serve(
         warp::path(BASE_URL)
-            .and(info.or(query).or(submit).or(detail))
+            .and(
+                info.or(query)
+                    .or(submit)
+                    .or(submit_batch)
+                    .or(detail)
+                    .or(tail),
+            )
             .with(warp::log("server")),
     )
     .bind(([127u8, 0, 0, 1], 8080u16))
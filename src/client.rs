@@ -1,6 +1,7 @@
 use super::*;
 #[cfg(feature = "bincode")]
 use bincode_crate as bincode;
+use futures_util::{future, stream, Stream, StreamExt};
 #[cfg(not(feature = "wasm"))]
 use std::time;
 
@@ -8,35 +9,13 @@ impl<T> ApiConfig<T>
 where
     T: ConnectionProxy,
 {
-    #[cfg(all(feature = "json", not(feature = "bincode")))]
-    pub async fn query(
-        &self,
-        client: &reqwest::Client,
-        params: &QueryParams,
-        #[cfg(not(feature = "wasm"))] timeout: time::Duration,
-    ) -> Result<Vec<QueryResponse>> {
-        let url = self.base_url.join("query")?;
-
-        let req = client.get(url);
-
-        let req = self.proxy.clone().proxy(req).await?;
-
-        let query = req
-            .header(
-                header::ACCEPT,
-                header::HeaderValue::from_static(APPLICATION_JSON),
-            )
-            .query(&params);
-
-        #[cfg(not(feature = "wasm"))]
-        let query = query.timeout(timeout);
-
-        let resp = query.send().await?.error_for_status()?.json().await?;
-
-        Ok(resp)
+    fn accept_encoding(&self, req: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match self.compression {
+            Some(compression) => req.header(header::ACCEPT_ENCODING, compression.header_value()),
+            None => req,
+        }
     }
 
-    #[cfg(feature = "bincode")]
     pub async fn query(
         &self,
         client: &reqwest::Client,
@@ -49,21 +28,19 @@ where
 
         let req = self.proxy.clone().proxy(req).await?;
 
+        let req = self.accept_encoding(req);
+
         let query = req
-            .header(
-                header::ACCEPT,
-                header::HeaderValue::from_static(OCTET_STREAM),
-            )
+            .header(header::ACCEPT, self.serialization_format.header_value())
             .query(&params);
 
         #[cfg(not(feature = "wasm"))]
         let query = query.timeout(timeout);
 
-        let resp = query.send().await?.error_for_status()?.bytes().await?;
-        Ok(bincode::deserialize(&resp)?)
+        let resp = read_body(check_status(query.send().await?).await?).await?;
+        self.serialization_format.deserialize(&resp)
     }
 
-    #[cfg(all(feature = "json", not(feature = "bincode")))]
     pub async fn detail(
         &self,
         client: &reqwest::Client,
@@ -79,49 +56,17 @@ where
 
         let req = self.proxy.clone().proxy(req).await?;
 
-        let resp = req
-            .header(
-                header::ACCEPT,
-                header::HeaderValue::from_static(APPLICATION_JSON),
-            )
-            .send()
-            .await?
-            .error_for_status()?
-            .json()
-            .await?;
-        Ok(resp)
-    }
-
-    #[cfg(feature = "bincode")]
-    pub async fn detail(
-        &self,
-        client: &reqwest::Client,
-        host: &Host,
-        app: &App,
-        level: Level,
-    ) -> Result<LogTreeDetail> {
-        let url = self
-            .base_url
-            .join(&format!("detail/{host}/{app}/{level}"))?;
-
-        let req = client.get(url);
-
-        let req = self.proxy.clone().proxy(req).await?;
+        let req = self.accept_encoding(req);
 
         let resp = req
-            .header(
-                header::ACCEPT,
-                header::HeaderValue::from_static(OCTET_STREAM),
-            )
+            .header(header::ACCEPT, self.serialization_format.header_value())
             .send()
-            .await?
-            .error_for_status()?
-            .bytes()
             .await?;
-        Ok(bincode::deserialize(&resp)?)
+
+        let resp = read_body(check_status(resp).await?).await?;
+        self.serialization_format.deserialize(&resp)
     }
 
-    #[cfg(all(feature = "json", not(feature = "bincode")))]
     pub async fn info(
         &self,
         client: &reqwest::Client,
@@ -132,25 +77,27 @@ where
 
         let req = self.proxy.clone().proxy(req).await?;
 
+        let req = self.accept_encoding(req);
+
         let resp = req
-            .header(
-                header::ACCEPT,
-                header::HeaderValue::from_static(APPLICATION_JSON),
-            )
+            .header(header::ACCEPT, self.serialization_format.header_value())
             .send()
-            .await?
-            .error_for_status()?
-            .json()
             .await?;
-        Ok(resp)
+
+        let resp = read_body(check_status(resp).await?).await?;
+        self.serialization_format.deserialize(&resp)
     }
 
-    #[cfg(feature = "bincode")]
-    pub async fn info(
+    /// Follows newly submitted logs which match `params`. The stream ends when the server closes the connection.
+    ///
+    /// Needs the `json` feature, as the server always sends the rows of the event stream as JSON.
+    #[cfg(feature = "json")]
+    pub async fn tail(
         &self,
         client: &reqwest::Client,
-    ) -> Result<Vec<result::Result<LogTreeInfo, ParseLogTreeInfoError>>> {
-        let url = self.base_url.join("info")?;
+        params: &TailParams,
+    ) -> Result<impl Stream<Item = Result<QueryResponse>>> {
+        let url = self.base_url.join("tail")?;
 
         let req = client.get(url);
 
@@ -159,13 +106,62 @@ where
         let resp = req
             .header(
                 header::ACCEPT,
-                header::HeaderValue::from_static(OCTET_STREAM),
+                header::HeaderValue::from_static(TEXT_EVENT_STREAM),
             )
+            .query(&params)
             .send()
-            .await?
-            .error_for_status()?
-            .bytes()
             .await?;
-        Ok(bincode::deserialize(&resp)?)
+
+        let resp = check_status(resp).await?;
+
+        let rows = resp
+            .bytes_stream()
+            .scan(Vec::new(), |buffer, chunk| {
+                let rows = match chunk {
+                    Ok(chunk) => {
+                        buffer.extend_from_slice(&chunk);
+                        take_events(buffer)
+                    }
+                    Err(e) => vec![Err(e.into())],
+                };
+                future::ready(Some(stream::iter(rows)))
+            })
+            .flatten();
+
+        Ok(rows)
     }
 }
+
+#[cfg(feature = "json")]
+const TEXT_EVENT_STREAM: &str = "text/event-stream";
+
+/// Removes each complete server-sent event from the front of `buffer`,
+/// decoding the rows and skipping comments and other event types.
+#[cfg(feature = "json")]
+fn take_events(buffer: &mut Vec<u8>) -> Vec<Result<QueryResponse>> {
+    let mut rows = Vec::new();
+
+    while let Some(end) = buffer.windows(2).position(|w| w == b"\n\n") {
+        let event = buffer.drain(..end + 2).collect::<Vec<u8>>();
+        let event = String::from_utf8_lossy(&event);
+
+        let mut name = None;
+        let mut data = String::new();
+        for line in event.lines() {
+            if let Some(n) = line.strip_prefix("event:") {
+                name = Some(n.trim_start());
+            } else if let Some(d) = line.strip_prefix("data:") {
+                if !data.is_empty() {
+                    data.push('\n');
+                }
+                data.push_str(d.strip_prefix(' ').unwrap_or(d));
+            }
+        }
+
+        if name == Some(TAIL_EVENT) && !data.is_empty() {
+            rows.push(serde_json::from_str(&data).map_err(Error::from));
+        }
+    }
+
+    rows
+}
@@ -11,7 +11,7 @@
 
 use http::header;
 use once_cell::sync as once_cell;
-use std::{collections, error, fmt, iter, result, str, sync};
+use std::{cmp, collections, error, fmt, iter, result, str, sync};
 
 #[cfg(feature = "client")]
 pub mod client;
@@ -19,10 +19,18 @@ pub mod client;
 pub mod server;
 #[cfg(any(feature = "remote-subscriber", feature = "local-subscriber"))]
 pub mod subscriber;
+#[cfg(all(
+    feature = "tracing",
+    any(feature = "remote-subscriber", feature = "local-subscriber")
+))]
+pub mod tracing;
 
 #[cfg(any(feature = "server", feature = "local-subscriber"))]
 pub mod storage;
 
+#[cfg(feature = "protobuf")]
+pub mod proto;
+
 const fn check_bincode_or_json() {
     #[cfg(not(any(feature = "bincode", feature = "json")))]
     compile_error!("eigenlog: must select at least one of `json` or `bincode`");
@@ -36,8 +44,20 @@ const fn check_client_or_server_or_subscriber() {
     )))]
     compile_error!("eigenlog: must select at least one of `client`, `server` or `subscriber`");
 }
+const fn check_tracing_has_subscriber() {
+    #[cfg(all(
+        feature = "tracing",
+        not(any(feature = "remote-subscriber", feature = "local-subscriber"))
+    ))]
+    compile_error!("eigenlog: `tracing` needs one of `remote-subscriber` or `local-subscriber`");
+}
 
 const API_KEY_HEADER: &str = "x-api-key";
+/// Identifies a submission, so the server can recognise a client retrying it
+const BATCH_ID_HEADER: &str = "x-batch-id";
+
+/// Name of the server-sent event carrying each row on the `/tail` endpoint
+const TAIL_EVENT: &str = "log";
 
 #[cfg(feature = "json")]
 const APPLICATION_JSON: &str = "application/json";
@@ -45,6 +65,23 @@ const APPLICATION_JSON: &str = "application/json";
 #[cfg(feature = "bincode")]
 const OCTET_STREAM: &str = "application/octet-stream";
 
+#[cfg(feature = "msgpack")]
+const APPLICATION_MSGPACK: &str = "application/msgpack";
+
+#[cfg(feature = "cbor")]
+const APPLICATION_CBOR: &str = "application/cbor";
+
+#[cfg(feature = "protobuf")]
+const APPLICATION_PROTOBUF: &str = "application/x-protobuf";
+
+const IDENTITY: &str = "identity";
+
+#[cfg(feature = "gzip")]
+const GZIP: &str = "gzip";
+
+#[cfg(feature = "zstd")]
+const ZSTD: &str = "zstd";
+
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct LogData {
     pub message: String,
@@ -61,29 +98,70 @@ impl<'a> From<&log::Record<'a>> for LogData {
             code_line: log.line(),
             code_module: log.module_path().map(ToString::to_string),
             message: log.args().to_string(),
-            tags: iter::once(("target".to_string(), log.target().to_string())).collect(),
+            tags: key_value_tags(log.key_values())
+                .chain(iter::once(("target".to_string(), log.target().to_string())))
+                .collect(),
+        }
+    }
+}
+
+/// The structured key-values of a record, such as `user_id = 5` in
+/// `log::info!(user_id = 5; "...")`, as tags. Numbers and booleans are written the way
+/// [`TagFilter`] reads them back, so they can be compared as numbers.
+fn key_value_tags(source: &dyn log::kv::Source) -> impl Iterator<Item = (String, String)> {
+    struct Collect(Vec<(String, String)>);
+
+    impl<'kvs> log::kv::VisitSource<'kvs> for Collect {
+        fn visit_pair(
+            &mut self,
+            key: log::kv::Key<'kvs>,
+            value: log::kv::Value<'kvs>,
+        ) -> result::Result<(), log::kv::Error> {
+            self.0.push((key.to_string(), value.to_string()));
+            Ok(())
         }
     }
+
+    let mut tags = Collect(Vec::new());
+    // collecting into a `Vec` never fails
+    let _ = source.visit(&mut tags);
+    tags.0.into_iter()
 }
 
 pub type LogBatch = collections::BTreeMap<ulid::Ulid, LogData>;
 
-#[derive(Clone, Debug, Copy)]
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
 pub enum SerializationFormat {
     #[cfg(feature = "bincode")]
     Bincode,
     #[cfg(feature = "json")]
     Json,
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+    #[cfg(feature = "cbor")]
+    Cbor,
+    /// Only supported for `LogBatch` and `Vec<QueryResponse>`, see `proto/eigenlog.proto`
+    #[cfg(feature = "protobuf")]
+    Protobuf,
 }
 
 impl str::FromStr for SerializationFormat {
     type Err = Error;
     fn from_str(s: &str) -> Result<Self> {
-        match s {
+        // ignore any parameters, such as `; charset=utf-8`
+        match s.split(';').next().unwrap_or_default().trim() {
             #[cfg(feature = "json")]
             APPLICATION_JSON => Ok(SerializationFormat::Json),
-            #[cfg(feautre = "bincode")]
+            #[cfg(feature = "bincode")]
             OCTET_STREAM => Ok(SerializationFormat::Bincode),
+            #[cfg(feature = "msgpack")]
+            APPLICATION_MSGPACK | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Ok(SerializationFormat::MessagePack)
+            }
+            #[cfg(feature = "cbor")]
+            APPLICATION_CBOR => Ok(SerializationFormat::Cbor),
+            #[cfg(feature = "protobuf")]
+            APPLICATION_PROTOBUF | "application/protobuf" => Ok(SerializationFormat::Protobuf),
             otherwise => Err(Error::UnsupportedSerializationMimeType(
                 otherwise.to_string(),
             )),
@@ -98,19 +176,351 @@ impl SerializationFormat {
             SerializationFormat::Bincode => header::HeaderValue::from_static(OCTET_STREAM),
             #[cfg(feature = "json")]
             SerializationFormat::Json => header::HeaderValue::from_static(APPLICATION_JSON),
+            #[cfg(feature = "msgpack")]
+            SerializationFormat::MessagePack => {
+                header::HeaderValue::from_static(APPLICATION_MSGPACK)
+            }
+            #[cfg(feature = "cbor")]
+            SerializationFormat::Cbor => header::HeaderValue::from_static(APPLICATION_CBOR),
+            #[cfg(feature = "protobuf")]
+            SerializationFormat::Protobuf => header::HeaderValue::from_static(APPLICATION_PROTOBUF),
         }
     }
-    fn serialize<T>(&self, t: T) -> Result<Vec<u8>>
+    fn serialize<T>(&self, t: &T) -> Result<Vec<u8>>
     where
-        T: serde::Serialize,
+        T: Payload,
     {
         match self {
             #[cfg(feature = "bincode")]
-            SerializationFormat::Bincode => Ok(bincode_crate::serialize(&t)?),
+            SerializationFormat::Bincode => Ok(bincode_crate::serialize(t)?),
             #[cfg(feature = "json")]
-            SerializationFormat::Json => Ok(serde_json::to_vec(&t)?),
+            SerializationFormat::Json => Ok(serde_json::to_vec(t)?),
+            #[cfg(feature = "msgpack")]
+            SerializationFormat::MessagePack => Ok(rmp_serde::to_vec_named(t)?),
+            #[cfg(feature = "cbor")]
+            SerializationFormat::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::ser::into_writer(t, &mut bytes)?;
+                Ok(bytes)
+            }
+            #[cfg(feature = "protobuf")]
+            SerializationFormat::Protobuf => t.to_protobuf(),
+        }
+    }
+    fn deserialize<T>(&self, bytes: &[u8]) -> Result<T>
+    where
+        T: Payload,
+    {
+        match self {
+            #[cfg(feature = "bincode")]
+            SerializationFormat::Bincode => Ok(bincode_crate::deserialize(bytes)?),
+            #[cfg(feature = "json")]
+            SerializationFormat::Json => Ok(serde_json::from_slice(bytes)?),
+            #[cfg(feature = "msgpack")]
+            SerializationFormat::MessagePack => Ok(rmp_serde::from_slice(bytes)?),
+            #[cfg(feature = "cbor")]
+            SerializationFormat::Cbor => Ok(ciborium::de::from_reader(bytes)?),
+            #[cfg(feature = "protobuf")]
+            SerializationFormat::Protobuf => T::from_protobuf(bytes),
+        }
+    }
+}
+
+impl SerializationFormat {
+    /// Every enabled format, the default format first
+    fn all() -> impl Iterator<Item = SerializationFormat> {
+        let default = SerializationFormat::default();
+        iter::once(default).chain(
+            IntoIterator::into_iter([
+                #[cfg(feature = "json")]
+                SerializationFormat::Json,
+                #[cfg(feature = "bincode")]
+                SerializationFormat::Bincode,
+                #[cfg(feature = "msgpack")]
+                SerializationFormat::MessagePack,
+                #[cfg(feature = "cbor")]
+                SerializationFormat::Cbor,
+                #[cfg(feature = "protobuf")]
+                SerializationFormat::Protobuf,
+            ])
+            .filter(move |f| *f != default),
+        )
+    }
+
+    /// Picks the format for a reply from the request's `Accept` header, taking into account
+    /// q-values and wildcards. A missing header gets the default format, while a header that
+    /// matches none of the enabled formats is [`Error::NotAcceptable`].
+    pub fn negotiate(accept: Option<&str>) -> Result<SerializationFormat> {
+        let accept = match accept.map(str::trim) {
+            None | Some("") => return Ok(SerializationFormat::default()),
+            Some(accept) => accept,
+        };
+
+        let ranges = accept
+            .split(',')
+            .map(|range| {
+                let mut parts = range.split(';');
+                let media_type = parts.next().unwrap_or_default().trim().to_lowercase();
+                let weight = parts
+                    .filter_map(|p| p.trim().strip_prefix("q="))
+                    .find_map(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                (media_type, weight)
+            })
+            .collect::<Vec<_>>();
+
+        let mut best: Option<(SerializationFormat, f32)> = None;
+        for format in SerializationFormat::all() {
+            let header_value = format.header_value();
+            let media_type = header_value.to_str().unwrap_or_default();
+            let main_type = media_type.split('/').next().unwrap_or_default();
+
+            // the most specific matching range decides the weight of each format
+            let weight = ranges
+                .iter()
+                .filter_map(|(range, weight)| {
+                    let specificity = if range.parse::<SerializationFormat>().ok() == Some(format) {
+                        3
+                    } else if range.strip_suffix("/*") == Some(main_type) {
+                        2
+                    } else if range == "*/*" || range == "*" {
+                        1
+                    } else {
+                        return None;
+                    };
+                    Some((specificity, *weight))
+                })
+                .max_by_key(|(specificity, _)| *specificity)
+                .map(|(_, weight)| weight);
+
+            if let Some(weight) = weight {
+                if weight > 0.0 && best.map(|(_, w)| weight > w).unwrap_or(true) {
+                    best = Some((format, weight));
+                }
+            }
+        }
+
+        best.map(|(f, _)| f)
+            .ok_or_else(|| Error::NotAcceptable(accept.to_string()))
+    }
+}
+
+/// Types which are sent as the body of a request or reply. Every type can be sent
+/// using the serde based formats, while only some have a protobuf encoding.
+pub trait Payload: serde::Serialize + serde::de::DeserializeOwned {
+    #[cfg(feature = "protobuf")]
+    fn to_protobuf(&self) -> Result<Vec<u8>> {
+        Err(Error::UnsupportedSerializationMimeType(
+            APPLICATION_PROTOBUF.to_string(),
+        ))
+    }
+    #[cfg(feature = "protobuf")]
+    fn from_protobuf(_bytes: &[u8]) -> Result<Self> {
+        Err(Error::UnsupportedSerializationMimeType(
+            APPLICATION_PROTOBUF.to_string(),
+        ))
+    }
+}
+
+impl Payload for () {}
+
+impl Payload for ErrorResponse {}
+
+impl Payload for LogTreeDetail {}
+
+impl Payload for Vec<result::Result<LogTreeInfo, ParseLogTreeInfoError>> {}
+
+impl Payload for LogBatch {
+    #[cfg(feature = "protobuf")]
+    fn to_protobuf(&self) -> Result<Vec<u8>> {
+        Ok(prost::Message::encode_to_vec(&proto::LogBatch::from(self)))
+    }
+    #[cfg(feature = "protobuf")]
+    fn from_protobuf(bytes: &[u8]) -> Result<Self> {
+        <proto::LogBatch as prost::Message>::decode(bytes)?.try_into()
+    }
+}
+
+impl Payload for Vec<QueryResponse> {
+    #[cfg(feature = "protobuf")]
+    fn to_protobuf(&self) -> Result<Vec<u8>> {
+        let rows = proto::QueryResponses {
+            rows: self.iter().map(proto::QueryResponse::from).collect(),
+        };
+        Ok(prost::Message::encode_to_vec(&rows))
+    }
+    #[cfg(feature = "protobuf")]
+    fn from_protobuf(bytes: &[u8]) -> Result<Self> {
+        <proto::QueryResponses as prost::Message>::decode(bytes)?
+            .rows
+            .into_iter()
+            .map(QueryResponse::try_from)
+            .collect()
+    }
+}
+
+impl Payload for SubmitAck {
+    #[cfg(feature = "protobuf")]
+    fn to_protobuf(&self) -> Result<Vec<u8>> {
+        Ok(prost::Message::encode_to_vec(&proto::SubmitAck::from(self)))
+    }
+    #[cfg(feature = "protobuf")]
+    fn from_protobuf(bytes: &[u8]) -> Result<Self> {
+        <proto::SubmitAck as prost::Message>::decode(bytes)?.try_into()
+    }
+}
+
+impl Payload for Vec<StreamBatch> {
+    #[cfg(feature = "protobuf")]
+    fn to_protobuf(&self) -> Result<Vec<u8>> {
+        let batch = proto::SubmitBatch {
+            streams: self.iter().map(proto::StreamBatch::from).collect(),
+        };
+        Ok(prost::Message::encode_to_vec(&batch))
+    }
+    #[cfg(feature = "protobuf")]
+    fn from_protobuf(bytes: &[u8]) -> Result<Self> {
+        <proto::SubmitBatch as prost::Message>::decode(bytes)?
+            .streams
+            .into_iter()
+            .map(StreamBatch::try_from)
+            .collect()
+    }
+}
+
+impl Payload for Vec<StreamResult> {
+    #[cfg(feature = "protobuf")]
+    fn to_protobuf(&self) -> Result<Vec<u8>> {
+        let results = proto::SubmitBatchResults {
+            results: self.iter().map(proto::StreamResult::from).collect(),
+        };
+        Ok(prost::Message::encode_to_vec(&results))
+    }
+    #[cfg(feature = "protobuf")]
+    fn from_protobuf(bytes: &[u8]) -> Result<Self> {
+        <proto::SubmitBatchResults as prost::Message>::decode(bytes)?
+            .results
+            .into_iter()
+            .map(StreamResult::try_from)
+            .collect()
+    }
+}
+
+/// Used when the other side hasn't told us which format it wants,
+/// for example when replying with an error to an unparseable `Accept` header.
+impl Default for SerializationFormat {
+    #[cfg(feature = "json")]
+    fn default() -> SerializationFormat {
+        SerializationFormat::Json
+    }
+    #[cfg(not(feature = "json"))]
+    fn default() -> SerializationFormat {
+        SerializationFormat::Bincode
+    }
+}
+
+/// Compression applied to request and response bodies, negotiated with the
+/// `Content-Encoding` and `Accept-Encoding` headers.
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+pub enum Compression {
+    #[cfg(feature = "gzip")]
+    Gzip,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Compression {
+    /// Every enabled compression, in order of preference
+    fn all() -> impl Iterator<Item = Compression> {
+        IntoIterator::into_iter([
+            #[cfg(feature = "zstd")]
+            Compression::Zstd,
+            #[cfg(feature = "gzip")]
+            Compression::Gzip,
+        ])
+    }
+    fn name(&self) -> &'static str {
+        match *self {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => GZIP,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => ZSTD,
         }
     }
+    fn header_value(&self) -> header::HeaderValue {
+        header::HeaderValue::from_static(self.name())
+    }
+    #[cfg_attr(not(any(feature = "gzip", feature = "zstd")), allow(unused_variables))]
+    fn compress(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        match *self {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => {
+                use std::io::Write;
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(bytes)?;
+                Ok(encoder.finish()?)
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Ok(zstd_crate::encode_all(bytes, 0)?),
+        }
+    }
+    /// Decompresses `bytes`, failing once the output grows past `limit` bytes
+    #[cfg_attr(
+        not(any(feature = "gzip", feature = "zstd")),
+        allow(unused_variables, unreachable_code)
+    )]
+    fn decompress(&self, bytes: &[u8], limit: u64) -> Result<Vec<u8>> {
+        use std::io::Read;
+        let decoder: Box<dyn Read + '_> = match *self {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => Box::new(flate2::read::GzDecoder::new(bytes)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Box::new(zstd_crate::stream::read::Decoder::new(bytes)?),
+        };
+        let mut decoded = Vec::new();
+        decoder
+            .take(limit.saturating_add(1))
+            .read_to_end(&mut decoded)?;
+        if decoded.len() as u64 > limit {
+            return Err(Error::InvalidRequestBody(format!(
+                "Decompressed body is larger than {} bytes",
+                limit
+            )));
+        }
+        Ok(decoded)
+    }
+    /// Parses a `Content-Encoding` header, where `None` means the body isn't compressed
+    fn from_content_encoding(s: &str) -> Result<Option<Compression>> {
+        match s.trim() {
+            "" | IDENTITY => Ok(None),
+            otherwise => Compression::all()
+                .find(|c| c.name() == otherwise)
+                .map(Some)
+                .ok_or_else(|| Error::UnsupportedContentEncoding(otherwise.to_string())),
+        }
+    }
+    /// Picks the supported encoding with the highest weight from an `Accept-Encoding` header
+    fn from_accept_encoding(s: &str) -> Option<Compression> {
+        let mut best: Option<(Compression, f32)> = None;
+        for item in s.split(',') {
+            let mut parts = item.split(';');
+            let coding = parts.next().unwrap_or_default().trim();
+            let weight = parts
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            let compression = Compression::all().find(|c| coding == "*" || c.name() == coding);
+
+            if let Some(compression) = compression {
+                if weight > 0.0 && best.map(|(_, w)| weight > w).unwrap_or(true) {
+                    best = Some((compression, weight));
+                }
+            }
+        }
+        best.map(|(c, _)| c)
+    }
 }
 
 #[cfg(any(feature = "client", feature = "remote-subscriber"))]
@@ -151,6 +561,58 @@ impl ConnectionProxy for BasicProxy {
     }
 }
 
+#[cfg(any(feature = "client", feature = "remote-subscriber"))]
+/// Returns the response if it was successful, otherwise decodes the error sent by the server
+async fn check_status(response: reqwest::Response) -> Result<reqwest::Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let format = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<SerializationFormat>().ok());
+
+    let retry_after = response
+        .headers()
+        .get(header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+
+    let body = read_body(response).await?;
+
+    match format.and_then(|f| f.deserialize::<ErrorResponse>(&body).ok()) {
+        Some(mut error) => {
+            error.retry_after_seconds = retry_after.or(error.retry_after_seconds);
+            Err(Error::Server(error))
+        }
+        None => Err(Error::UnexpectedStatus(
+            status.as_u16(),
+            String::from_utf8_lossy(&body).to_string(),
+        )),
+    }
+}
+
+#[cfg(any(feature = "client", feature = "remote-subscriber"))]
+/// Reads the body of a response, decompressing it according to its `Content-Encoding`
+async fn read_body(response: reqwest::Response) -> Result<Vec<u8>> {
+    let compression = response
+        .headers()
+        .get(header::CONTENT_ENCODING)
+        .map(|v| Compression::from_content_encoding(v.to_str().unwrap_or_default()))
+        .transpose()?
+        .flatten();
+
+    let body = response.bytes().await?;
+
+    match compression {
+        Some(compression) => compression.decompress(&body, u64::MAX),
+        None => Ok(body.to_vec()),
+    }
+}
+
 #[cfg(any(feature = "client", feature = "remote-subscriber"))]
 pub struct ApiConfig<T>
 where
@@ -160,6 +622,8 @@ where
     pub base_url: reqwest::Url,
     pub proxy: sync::Arc<T>,
     pub serialization_format: SerializationFormat,
+    /// when set, submitted batches are compressed and compressed replies are requested
+    pub compression: Option<Compression>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
@@ -195,6 +659,7 @@ impl TreeName {
 }
 
 // this is because the display impl is inefficient
+#[allow(clippy::to_string_trait_impl)]
 impl ToString for TreeName {
     fn to_string(&self) -> String {
         self.level.get_tree_name(&self.host, &self.app)
@@ -234,9 +699,191 @@ pub struct QueryParams {
     pub app_contains: Option<App>,
     pub message_matches: Option<String>,
     pub message_not_matches: Option<String>,
+    /// will only return logs whose tags match, see [`TagFilter`] for the syntax
+    pub tags: Option<TagFilter>,
     pub max_results: Option<usize>,
 }
 
+/// Filters applied to the live stream of submitted logs. These mirror the
+/// [`QueryParams`] fields that make sense for logs that have not been stored yet.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+pub struct TailParams {
+    /// will only return logs equal to or more significant than this level,
+    /// where `Trace` is the least significant and `Error` is the most significant
+    pub max_log_level: Option<Level>,
+    pub host_contains: Option<Host>,
+    pub app_contains: Option<App>,
+    pub message_matches: Option<String>,
+    pub message_not_matches: Option<String>,
+    pub tags: Option<TagFilter>,
+}
+
+/// Conditions on the tags of a log, all of which have to hold, written as `key=value`
+/// separated by commas, such as `user_id=5,region!=eu`. The other comparisons are `>`, `>=`,
+/// `<` and `<=`, which only hold when both the tag and the value are numbers. Equality is
+/// numeric too when both sides are numbers, so `latency=1.0` matches a tag of `1`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TagFilter {
+    conditions: Vec<TagCondition>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct TagCondition {
+    key: String,
+    comparison: Comparison,
+    value: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Comparison {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+impl Comparison {
+    // longest first, so that `>=` isn't read as `>` followed by `=`
+    const ALL: [(&'static str, Comparison); 6] = [
+        ("!=", Comparison::Ne),
+        (">=", Comparison::Ge),
+        ("<=", Comparison::Le),
+        ("=", Comparison::Eq),
+        (">", Comparison::Gt),
+        ("<", Comparison::Lt),
+    ];
+
+    fn as_str(self) -> &'static str {
+        Comparison::ALL
+            .iter()
+            .find(|(_, c)| *c == self)
+            .map(|(s, _)| *s)
+            .unwrap_or_default()
+    }
+}
+
+impl TagFilter {
+    pub fn matches(&self, tags: &collections::HashMap<String, String>) -> bool {
+        self.conditions
+            .iter()
+            .all(|condition| condition.matches(tags))
+    }
+}
+
+impl TagCondition {
+    fn matches(&self, tags: &collections::HashMap<String, String>) -> bool {
+        let tag = match tags.get(&self.key) {
+            Some(tag) => tag,
+            // a missing tag isn't equal to anything
+            None => return self.comparison == Comparison::Ne,
+        };
+        let ordering = match (tag.parse::<f64>(), self.value.parse::<f64>()) {
+            (Ok(tag), Ok(value)) => tag.partial_cmp(&value),
+            _ => match self.comparison {
+                Comparison::Eq | Comparison::Ne => Some(tag.as_str().cmp(self.value.as_str())),
+                _ => None,
+            },
+        };
+        match (self.comparison, ordering) {
+            (Comparison::Ne, ordering) => ordering != Some(cmp::Ordering::Equal),
+            (_, None) => false,
+            (Comparison::Eq, Some(ordering)) => ordering.is_eq(),
+            (Comparison::Gt, Some(ordering)) => ordering.is_gt(),
+            (Comparison::Ge, Some(ordering)) => ordering.is_ge(),
+            (Comparison::Lt, Some(ordering)) => ordering.is_lt(),
+            (Comparison::Le, Some(ordering)) => ordering.is_le(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TagFilterParseError {
+    msg: String,
+}
+
+impl std::str::FromStr for TagFilter {
+    type Err = TagFilterParseError;
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let conditions = s
+            .split(',')
+            .map(str::trim)
+            .filter(|condition| !condition.is_empty())
+            .map(|condition| {
+                let (at, op, comparison) = Comparison::ALL
+                    .iter()
+                    .filter_map(|(op, comparison)| Some((condition.find(op)?, *op, *comparison)))
+                    // the first operator in the condition, the longest one if several start there
+                    .min_by_key(|(at, op, _)| (*at, usize::MAX - op.len()))
+                    .ok_or_else(|| TagFilterParseError {
+                        msg: format!("`{}` has no comparison, such as `=`", condition),
+                    })?;
+                let key = condition[..at].trim();
+                if key.is_empty() {
+                    return Err(TagFilterParseError {
+                        msg: format!("`{}` has no tag name", condition),
+                    });
+                }
+                Ok(TagCondition {
+                    key: key.to_string(),
+                    comparison,
+                    value: condition[at + op.len()..].trim().to_string(),
+                })
+            })
+            .collect::<result::Result<Vec<_>, _>>()?;
+        Ok(TagFilter { conditions })
+    }
+}
+
+impl fmt::Display for TagFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, condition) in self.conditions.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(
+                f,
+                "{}{}{}",
+                condition.key,
+                condition.comparison.as_str(),
+                condition.value
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl serde::Serialize for TagFilter {
+    fn serialize<S>(&self, serializer: S) -> result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for TagFilter {
+    fn deserialize<D>(deserializer: D) -> result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let st = String::deserialize(deserializer)?;
+
+        let filter = st.parse().map_err(serde::de::Error::custom)?;
+
+        Ok(filter)
+    }
+}
+
+impl fmt::Display for TagFilterParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "tag filter parse error: {}", self.msg)
+    }
+}
+
+impl error::Error for TagFilterParseError {}
+
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct LogTreeDetailParams {
     /// will only return logs equal to or more significant than this level,
@@ -255,7 +902,39 @@ pub struct QueryResponse {
     pub data: LogData,
 }
 
-#[derive(Clone, Debug)]
+/// The logs of a single host, app and level, as sent to `/submit-batch`.
+/// The body of that endpoint is a list of these, one per stream.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct StreamBatch {
+    pub host: Host,
+    pub app: App,
+    pub level: Level,
+    pub batch: LogBatch,
+}
+
+/// Outcome of storing one [`StreamBatch`], in the same order as they were submitted
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct StreamResult {
+    pub host: Host,
+    pub app: App,
+    pub level: Level,
+    /// `None` when the batch was stored
+    #[serde(default)]
+    pub error: Option<ErrorResponse>,
+    #[serde(default)]
+    pub ack: SubmitAck,
+}
+
+/// Which rows of a submitted batch were stored. Rows which the server had already stored
+/// are not stored again, so a batch can safely be retried when its reply was lost.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SubmitAck {
+    pub accepted: Vec<ulid::Ulid>,
+    pub rejected: Vec<ulid::Ulid>,
+    pub already_present: Vec<ulid::Ulid>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Host {
     name: String,
 }
@@ -328,7 +1007,7 @@ impl fmt::Display for HostParseError {
 
 impl error::Error for HostParseError {}
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct App {
     name: String,
 }
@@ -398,7 +1077,9 @@ impl fmt::Display for AppParseError {
 
 impl error::Error for AppParseError {}
 
-#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(
+    serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
 pub enum Level {
     Trace = 4,
     Debug = 3,
@@ -437,6 +1118,18 @@ impl From<log::Level> for Level {
     }
 }
 
+impl From<Level> for log::Level {
+    fn from(lvl: Level) -> Self {
+        match lvl {
+            Level::Trace => log::Level::Trace,
+            Level::Debug => log::Level::Debug,
+            Level::Info => log::Level::Info,
+            Level::Warn => log::Level::Warn,
+            Level::Error => log::Level::Error,
+        }
+    }
+}
+
 impl std::str::FromStr for Level {
     type Err = String;
     fn from_str(s: &str) -> result::Result<Self, Self::Err> {
@@ -475,6 +1168,30 @@ pub enum Error {
     #[error("Serde Json: {0}")]
     SerdeJson(#[from] serde_json::Error),
 
+    #[cfg(feature = "msgpack")]
+    #[error("MessagePack encode: {0}")]
+    MessagePackEncode(#[from] rmp_serde::encode::Error),
+
+    #[cfg(feature = "msgpack")]
+    #[error("MessagePack decode: {0}")]
+    MessagePackDecode(#[from] rmp_serde::decode::Error),
+
+    #[cfg(feature = "cbor")]
+    #[error("Cbor encode: {0}")]
+    CborEncode(#[from] ciborium::ser::Error<std::io::Error>),
+
+    #[cfg(feature = "cbor")]
+    #[error("Cbor decode: {0}")]
+    CborDecode(#[from] ciborium::de::Error<std::io::Error>),
+
+    #[cfg(feature = "protobuf")]
+    #[error("Protobuf decode: {0}")]
+    ProtobufDecode(#[from] prost::DecodeError),
+
+    #[cfg(feature = "protobuf")]
+    #[error("Protobuf: {0}")]
+    Protobuf(String),
+
     #[error("Error sending flush response")]
     FlushResponse(#[from] std::sync::mpsc::SendError<()>),
 
@@ -489,6 +1206,27 @@ pub enum Error {
     #[error("API key `{0}` is not valid")]
     InvalidApiKey(String),
 
+    #[error("API key is not permitted to access {0}")]
+    Forbidden(String),
+
+    #[error("Parse API key file: {0}")]
+    ParseKeyFile(String),
+
+    #[error("Invalid request body: {0}")]
+    InvalidRequestBody(String),
+
+    #[error("Rate limit exceeded, retry after {0:?}")]
+    RateLimited(std::time::Duration),
+
+    #[error("{0} rows are being stored by another request, retry once it finished")]
+    SubmissionInProgress(usize),
+
+    #[error("Server error: {0}")]
+    Server(ErrorResponse),
+
+    #[error("Server responded with status {0}: {1}")]
+    UnexpectedStatus(u16, String),
+
     #[error("Submission of content type `{0}` is not valid")]
     InvalidSubmissionContentType(String),
 
@@ -509,6 +1247,9 @@ pub enum Error {
     #[error("Sled: {0}")]
     Sled(#[from] sled::Error),
 
+    #[error("Io: {0}")]
+    Io(#[from] std::io::Error),
+
     #[error("Ulid: {0}")]
     Ulid(#[from] ulid::MonotonicError),
 
@@ -518,6 +1259,12 @@ pub enum Error {
     #[error("Unsupported serialization mime type of: {0}")]
     UnsupportedSerializationMimeType(String),
 
+    #[error("None of the accepted media types `{0}` are supported")]
+    NotAcceptable(String),
+
+    #[error("Unsupported content encoding of: {0}")]
+    UnsupportedContentEncoding(String),
+
     #[cfg(feature = "server")]
     #[error("Warp: {0}")]
     Warp(#[from] warp::Error),
@@ -534,10 +1281,110 @@ pub enum Error {
     #[error("Log subscriber was closed")]
     LogSubscriberClosed,
 
+    #[error("Log subscriber did not shut down in time")]
+    ShutdownTimeout,
+
     #[error("Custom: {0}")]
     Custom(String),
 }
 
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::InvalidApiKey(_) => ErrorKind::Unauthorized,
+            Error::Forbidden(_) => ErrorKind::Forbidden,
+            Error::InvalidRequestBody(_) | Error::Regex(_) => ErrorKind::BadRequest,
+            Error::UnsupportedSerializationMimeType(_)
+            | Error::InvalidSubmissionContentType(_)
+            | Error::UnsupportedContentEncoding(_) => ErrorKind::UnsupportedMediaType,
+            Error::MissingEntity(_) => ErrorKind::NotFound,
+            Error::NotAcceptable(_) => ErrorKind::NotAcceptable,
+            Error::SubmissionInProgress(_) => ErrorKind::Conflict,
+            Error::RateLimited(_) => ErrorKind::TooManyRequests,
+            Error::Server(e) => e.kind,
+            _ => ErrorKind::Internal,
+        }
+    }
+
+    /// How long the server asked us to wait before trying again
+    pub fn retry_after(&self) -> Option<std::time::Duration> {
+        match self {
+            Error::RateLimited(d) => Some(*d),
+            Error::Server(e) => e.retry_after_seconds.map(std::time::Duration::from_secs),
+            _ => None,
+        }
+    }
+
+    /// Whether the request may succeed if sent again, as opposed to being refused outright
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            #[cfg(feature = "reqwest")]
+            Error::Reqwest(e) => !e.is_builder(),
+            Error::Io(_) | Error::RateLimited(_) | Error::SubmissionInProgress(_) => true,
+            Error::Server(e) => e.kind.is_retryable(),
+            Error::UnexpectedStatus(status, _) => {
+                *status >= 500 || *status == 408 || *status == 409 || *status == 429
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Broad category of an error, which the server uses to pick the HTTP status of the reply
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// 400
+    BadRequest,
+    /// 401
+    Unauthorized,
+    /// 403
+    Forbidden,
+    /// 404
+    NotFound,
+    /// 406
+    NotAcceptable,
+    /// 415
+    UnsupportedMediaType,
+    /// 409, when another request is storing the same rows
+    Conflict,
+    /// 429
+    TooManyRequests,
+    /// 500
+    Internal,
+}
+
+impl ErrorKind {
+    /// Server side failures, rate limits and conflicting submissions are temporary, the rest
+    /// are down to the request
+    pub fn is_retryable(self) -> bool {
+        matches!(
+            self,
+            ErrorKind::Conflict | ErrorKind::TooManyRequests | ErrorKind::Internal
+        )
+    }
+}
+
+/// The body of every unsuccessful reply from the server
+#[derive(thiserror::Error, Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[error("{kind:?}: {message}")]
+pub struct ErrorResponse {
+    pub kind: ErrorKind,
+    pub message: String,
+    /// set along with the `Retry-After` header when the request was rate limited
+    #[serde(default)]
+    pub retry_after_seconds: Option<u64>,
+}
+
+impl From<&Error> for ErrorResponse {
+    fn from(e: &Error) -> ErrorResponse {
+        ErrorResponse {
+            kind: e.kind(),
+            message: e.to_string(),
+            retry_after_seconds: e.retry_after().map(|d| d.as_secs_f64().ceil() as u64),
+        }
+    }
+}
+
 #[derive(thiserror::Error, Debug, serde::Deserialize, serde::Serialize)]
 #[error("Parse log tree info: {0}")]
 pub struct ParseLogTreeInfoError(pub String);
@@ -552,9 +1399,144 @@ mod tests {
         assert!("abc-123".parse::<Host>().is_err());
     }
 
+    #[cfg(all(feature = "json", feature = "bincode"))]
+    #[test]
+    fn test_negotiate() {
+        let negotiate = |accept| SerializationFormat::negotiate(accept).ok();
+
+        assert_eq!(negotiate(None), Some(SerializationFormat::Json));
+        assert_eq!(negotiate(Some("*/*")), Some(SerializationFormat::Json));
+        assert_eq!(
+            negotiate(Some("application/octet-stream")),
+            Some(SerializationFormat::Bincode)
+        );
+        assert_eq!(
+            negotiate(Some("application/json, */*;q=0.8")),
+            Some(SerializationFormat::Json)
+        );
+        assert_eq!(
+            negotiate(Some("application/json;q=0.5, application/octet-stream")),
+            Some(SerializationFormat::Bincode)
+        );
+        assert_eq!(
+            negotiate(Some("text/html, application/xhtml+xml, */*;q=0.8")),
+            Some(SerializationFormat::Json)
+        );
+        assert_eq!(
+            negotiate(Some("application/*, application/json;q=0")),
+            Some(SerializationFormat::Bincode)
+        );
+        assert_eq!(negotiate(Some("text/html")), None);
+    }
+
+    #[test]
+    fn test_serialization_formats() {
+        let formats = [
+            #[cfg(feature = "bincode")]
+            OCTET_STREAM,
+            #[cfg(feature = "json")]
+            "application/json; charset=utf-8",
+            #[cfg(feature = "msgpack")]
+            APPLICATION_MSGPACK,
+            #[cfg(feature = "cbor")]
+            APPLICATION_CBOR,
+            #[cfg(feature = "protobuf")]
+            APPLICATION_PROTOBUF,
+        ];
+
+        let mut gen = ulid::Generator::new();
+        let batch = iter::repeat_with(|| {
+            let data = LogData {
+                message: "abc".to_string(),
+                code_module: Some("def".to_string()),
+                code_file: None,
+                code_line: Some(5),
+                tags: iter::once(("target".to_string(), "ghi".to_string())).collect(),
+            };
+            (gen.generate().unwrap(), data)
+        })
+        .take(3)
+        .collect::<LogBatch>();
+
+        for format in formats {
+            let format = format.parse::<SerializationFormat>().unwrap();
+            let bytes = format.serialize(&batch).unwrap();
+            let decoded = format.deserialize::<LogBatch>(&bytes).unwrap();
+            assert_eq!(
+                decoded.keys().collect::<Vec<_>>(),
+                batch.keys().collect::<Vec<_>>()
+            );
+            assert!(decoded
+                .values()
+                .all(|d| d.message == "abc" && d.code_line == Some(5) && d.tags.len() == 1));
+        }
+
+        assert!("text/plain".parse::<SerializationFormat>().is_err());
+    }
+
+    #[cfg(all(feature = "gzip", feature = "zstd"))]
+    #[test]
+    fn test_compression() {
+        assert_eq!(
+            Compression::from_accept_encoding("gzip, deflate, br"),
+            Some(Compression::Gzip)
+        );
+        assert_eq!(
+            Compression::from_accept_encoding("gzip;q=0.5, zstd"),
+            Some(Compression::Zstd)
+        );
+        assert_eq!(Compression::from_accept_encoding("*;q=0, br"), None);
+        assert!(Compression::from_content_encoding("br").is_err());
+
+        let body = b"compress me ".repeat(100);
+        for compression in Compression::all() {
+            let compressed = compression.compress(&body).unwrap();
+            assert!(compressed.len() < body.len());
+            assert_eq!(compression.decompress(&compressed, 1200).unwrap(), body);
+            assert!(compression.decompress(&compressed, 1199).is_err());
+        }
+    }
+
     #[test]
     fn test_app() {
         assert!("abc123".parse::<App>().is_ok());
         assert!("abc-123".parse::<App>().is_err());
     }
+
+    #[test]
+    fn test_key_value_tags() {
+        let kvs: &[(&str, log::kv::Value)] = &[
+            ("user_id", 5.into()),
+            ("latency", 1.5.into()),
+            ("cached", false.into()),
+            ("region", "eu".into()),
+        ];
+        let data = LogData::from(
+            &log::Record::builder()
+                .target("abc")
+                .args(format_args!("def"))
+                .key_values(&kvs)
+                .build(),
+        );
+        assert_eq!(data.tags.len(), 5);
+        assert_eq!(data.tags["target"], "abc");
+        assert_eq!(data.tags["user_id"], "5");
+        assert_eq!(data.tags["latency"], "1.5");
+        assert_eq!(data.tags["cached"], "false");
+        assert_eq!(data.tags["region"], "eu");
+
+        let matches = |filter: &str| filter.parse::<TagFilter>().unwrap().matches(&data.tags);
+        assert!(matches("user_id=5, region=eu"));
+        assert!(matches("user_id=5.0,latency>=1.5,latency<2"));
+        assert!(matches("region!=us,missing!=1"));
+        assert!(!matches("user_id>5"));
+        assert!(!matches("region>1"));
+        assert!(!matches("missing=1"));
+        assert!(matches(""));
+
+        let filter = "user_id>=5,region!=us".parse::<TagFilter>().unwrap();
+        assert_eq!(filter.to_string(), "user_id>=5,region!=us");
+        assert!("user_id".parse::<TagFilter>().is_err());
+        assert!("=5".parse::<TagFilter>().is_err());
+    }
 }
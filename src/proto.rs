@@ -0,0 +1,299 @@
+//! Message types matching `proto/eigenlog.proto`, along with conversions to and from the
+//! types used in the rest of the crate.
+
+use super::*;
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct LogData {
+    #[prost(string, tag = "1")]
+    pub message: String,
+    #[prost(string, optional, tag = "2")]
+    pub code_module: Option<String>,
+    #[prost(uint32, optional, tag = "3")]
+    pub code_line: Option<u32>,
+    #[prost(string, optional, tag = "4")]
+    pub code_file: Option<String>,
+    #[prost(map = "string, string", tag = "5")]
+    pub tags: collections::HashMap<String, String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct LogEntry {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(message, optional, tag = "2")]
+    pub data: Option<LogData>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct LogBatch {
+    #[prost(message, repeated, tag = "1")]
+    pub entries: Vec<LogEntry>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct QueryResponse {
+    #[prost(string, tag = "1")]
+    pub host: String,
+    #[prost(string, tag = "2")]
+    pub app: String,
+    #[prost(string, tag = "3")]
+    pub level: String,
+    #[prost(string, tag = "4")]
+    pub id: String,
+    #[prost(message, optional, tag = "5")]
+    pub data: Option<LogData>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct QueryResponses {
+    #[prost(message, repeated, tag = "1")]
+    pub rows: Vec<QueryResponse>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StreamBatch {
+    #[prost(string, tag = "1")]
+    pub host: String,
+    #[prost(string, tag = "2")]
+    pub app: String,
+    #[prost(string, tag = "3")]
+    pub level: String,
+    #[prost(message, repeated, tag = "4")]
+    pub entries: Vec<LogEntry>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SubmitBatch {
+    #[prost(message, repeated, tag = "1")]
+    pub streams: Vec<StreamBatch>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StreamError {
+    #[prost(string, tag = "1")]
+    pub kind: String,
+    #[prost(string, tag = "2")]
+    pub message: String,
+    #[prost(uint64, optional, tag = "3")]
+    pub retry_after_seconds: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StreamResult {
+    #[prost(string, tag = "1")]
+    pub host: String,
+    #[prost(string, tag = "2")]
+    pub app: String,
+    #[prost(string, tag = "3")]
+    pub level: String,
+    #[prost(message, optional, tag = "4")]
+    pub error: Option<StreamError>,
+    #[prost(message, optional, tag = "5")]
+    pub ack: Option<SubmitAck>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SubmitAck {
+    #[prost(string, repeated, tag = "1")]
+    pub accepted: Vec<String>,
+    #[prost(string, repeated, tag = "2")]
+    pub rejected: Vec<String>,
+    #[prost(string, repeated, tag = "3")]
+    pub already_present: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SubmitBatchResults {
+    #[prost(message, repeated, tag = "1")]
+    pub results: Vec<StreamResult>,
+}
+
+impl From<&crate::LogData> for LogData {
+    fn from(data: &crate::LogData) -> LogData {
+        LogData {
+            message: data.message.clone(),
+            code_module: data.code_module.clone(),
+            code_line: data.code_line,
+            code_file: data.code_file.clone(),
+            tags: data.tags.clone(),
+        }
+    }
+}
+
+impl From<LogData> for crate::LogData {
+    fn from(data: LogData) -> crate::LogData {
+        crate::LogData {
+            message: data.message,
+            code_module: data.code_module,
+            code_line: data.code_line,
+            code_file: data.code_file,
+            tags: data.tags,
+        }
+    }
+}
+
+impl From<&crate::LogBatch> for LogBatch {
+    fn from(batch: &crate::LogBatch) -> LogBatch {
+        LogBatch {
+            entries: batch
+                .iter()
+                .map(|(id, data)| LogEntry {
+                    id: id.to_string(),
+                    data: Some(data.into()),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<LogBatch> for crate::LogBatch {
+    type Error = Error;
+    fn try_from(batch: LogBatch) -> Result<crate::LogBatch> {
+        batch
+            .entries
+            .into_iter()
+            .map(|e| Ok((parse_ulid(&e.id)?, e.data.unwrap_or_default().into())))
+            .collect()
+    }
+}
+
+impl From<&crate::QueryResponse> for QueryResponse {
+    fn from(row: &crate::QueryResponse) -> QueryResponse {
+        QueryResponse {
+            host: row.host.to_string(),
+            app: row.app.to_string(),
+            level: row.level.to_string(),
+            id: row.id.to_string(),
+            data: Some((&row.data).into()),
+        }
+    }
+}
+
+impl TryFrom<QueryResponse> for crate::QueryResponse {
+    type Error = Error;
+    fn try_from(row: QueryResponse) -> Result<crate::QueryResponse> {
+        Ok(crate::QueryResponse {
+            host: parse_host(&row.host)?,
+            app: parse_app(&row.app)?,
+            level: row.level.parse().map_err(Error::Protobuf)?,
+            id: parse_ulid(&row.id)?,
+            data: row.data.unwrap_or_default().into(),
+        })
+    }
+}
+
+impl From<&crate::StreamBatch> for StreamBatch {
+    fn from(stream: &crate::StreamBatch) -> StreamBatch {
+        StreamBatch {
+            host: stream.host.to_string(),
+            app: stream.app.to_string(),
+            level: stream.level.to_string(),
+            entries: LogBatch::from(&stream.batch).entries,
+        }
+    }
+}
+
+impl TryFrom<StreamBatch> for crate::StreamBatch {
+    type Error = Error;
+    fn try_from(stream: StreamBatch) -> Result<crate::StreamBatch> {
+        Ok(crate::StreamBatch {
+            host: parse_host(&stream.host)?,
+            app: parse_app(&stream.app)?,
+            level: stream.level.parse().map_err(Error::Protobuf)?,
+            batch: LogBatch {
+                entries: stream.entries,
+            }
+            .try_into()?,
+        })
+    }
+}
+
+impl From<&crate::StreamResult> for StreamResult {
+    fn from(result: &crate::StreamResult) -> StreamResult {
+        StreamResult {
+            host: result.host.to_string(),
+            app: result.app.to_string(),
+            level: result.level.to_string(),
+            error: result.error.as_ref().map(|e| StreamError {
+                kind: format!("{:?}", e.kind),
+                message: e.message.clone(),
+                retry_after_seconds: e.retry_after_seconds,
+            }),
+            ack: Some((&result.ack).into()),
+        }
+    }
+}
+
+impl TryFrom<StreamResult> for crate::StreamResult {
+    type Error = Error;
+    fn try_from(result: StreamResult) -> Result<crate::StreamResult> {
+        let error = match result.error {
+            Some(e) => Some(ErrorResponse {
+                kind: parse_error_kind(&e.kind)?,
+                message: e.message,
+                retry_after_seconds: e.retry_after_seconds,
+            }),
+            None => None,
+        };
+        Ok(crate::StreamResult {
+            host: parse_host(&result.host)?,
+            app: parse_app(&result.app)?,
+            level: result.level.parse().map_err(Error::Protobuf)?,
+            error,
+            ack: result.ack.unwrap_or_default().try_into()?,
+        })
+    }
+}
+
+impl From<&crate::SubmitAck> for SubmitAck {
+    fn from(ack: &crate::SubmitAck) -> SubmitAck {
+        let strings = |ids: &[ulid::Ulid]| ids.iter().map(ulid::Ulid::to_string).collect();
+        SubmitAck {
+            accepted: strings(&ack.accepted),
+            rejected: strings(&ack.rejected),
+            already_present: strings(&ack.already_present),
+        }
+    }
+}
+
+impl TryFrom<SubmitAck> for crate::SubmitAck {
+    type Error = Error;
+    fn try_from(ack: SubmitAck) -> Result<crate::SubmitAck> {
+        let ulids = |ids: Vec<String>| ids.iter().map(|id| parse_ulid(id)).collect::<Result<_>>();
+        Ok(crate::SubmitAck {
+            accepted: ulids(ack.accepted)?,
+            rejected: ulids(ack.rejected)?,
+            already_present: ulids(ack.already_present)?,
+        })
+    }
+}
+
+fn parse_error_kind(kind: &str) -> Result<ErrorKind> {
+    Ok(match kind {
+        "BadRequest" => ErrorKind::BadRequest,
+        "Unauthorized" => ErrorKind::Unauthorized,
+        "Forbidden" => ErrorKind::Forbidden,
+        "NotFound" => ErrorKind::NotFound,
+        "NotAcceptable" => ErrorKind::NotAcceptable,
+        "UnsupportedMediaType" => ErrorKind::UnsupportedMediaType,
+        "Conflict" => ErrorKind::Conflict,
+        "TooManyRequests" => ErrorKind::TooManyRequests,
+        "Internal" => ErrorKind::Internal,
+        other => return Err(Error::Protobuf(format!("unknown error kind `{other}`"))),
+    })
+}
+
+fn parse_host(host: &str) -> Result<Host> {
+    host.parse()
+        .map_err(|e: HostParseError| Error::Protobuf(e.to_string()))
+}
+
+fn parse_app(app: &str) -> Result<App> {
+    app.parse()
+        .map_err(|e: AppParseError| Error::Protobuf(e.to_string()))
+}
+
+fn parse_ulid(id: &str) -> Result<ulid::Ulid> {
+    ulid::Ulid::from_string(id).map_err(|e| Error::Protobuf(format!("invalid ulid `{id}`: {e}")))
+}
@@ -1,6 +1,6 @@
 use super::*;
 use futures_util::FutureExt;
-use std::{collections, convert, result, sync};
+use std::{borrow, collections, convert, result, sync};
 use warp::{
     http::{self, header},
     hyper::{self, body},
@@ -9,173 +9,572 @@ use warp::{
 
 use bincode_crate as bincode;
 
+mod auth;
+mod dedup;
+#[cfg(feature = "fluent")]
+mod fluent;
+#[cfg(feature = "gelf")]
+mod gelf;
+mod ingest;
+#[cfg(feature = "hashed-api-keys")]
+mod key_file;
+#[cfg(feature = "loki")]
+mod loki;
+#[cfg(feature = "otlp")]
+mod otlp;
+mod rate_limit;
+#[cfg(feature = "syslog")]
+mod syslog;
+mod tail;
+
+pub use auth::{ApiKey, ApiKeyStore, Role};
+pub use dedup::DedupWindow;
+#[cfg(feature = "fluent")]
+pub use fluent::{FluentListener, TagMapping};
+#[cfg(feature = "gelf")]
+pub use gelf::GelfListener;
+#[cfg(feature = "hashed-api-keys")]
+pub use key_file::KeyFile;
+#[cfg(feature = "loki")]
+pub use loki::create_loki_endpoint;
+#[cfg(feature = "otlp")]
+pub use otlp::create_otlp_endpoint;
+pub use rate_limit::{Limit, RateLimiter};
+#[cfg(feature = "syslog")]
+pub use syslog::SyslogListener;
+pub use tail::{create_tail_endpoint, TailHub, Tailed};
+
+/// The largest request body accepted by the submit endpoints, as sent
+const MAX_BODY: u64 = 16 * 1024 * 1024;
+
+/// The largest a request body may grow to once its `Content-Encoding` is undone
+const MAX_DECOMPRESSED: u64 = 64 * 1024 * 1024;
+
 fn add<C: Clone + Send>(
     c: C,
 ) -> impl warp::Filter<Extract = (C,), Error = convert::Infallible> + Clone {
     warp::any().map(move || c.clone())
 }
 
-pub enum AppReply<T: serde::Serialize> {
-    #[cfg(feature = "json")]
-    Json(T),
-    Bincode(T),
+pub enum AppReply<T: Payload> {
+    Data(T, SerializationFormat),
     Empty,
-    Error(String),
+    Error(ErrorResponse, SerializationFormat),
+}
+
+impl<T: Payload> AppReply<T> {
+    fn into_parts(self) -> (http::StatusCode, header::HeaderMap, Vec<u8>) {
+        let mut headers = header::HeaderMap::new();
+        match self {
+            AppReply::Data(data, format) => match format.serialize(&data) {
+                Ok(body) => {
+                    headers.insert(header::CONTENT_TYPE, format.header_value());
+                    (http::StatusCode::OK, headers, body)
+                }
+                Err(e) => e.into_reply::<T>(format).into_parts(),
+            },
+            AppReply::Empty => (http::StatusCode::OK, headers, Vec::new()),
+            AppReply::Error(e, format) => {
+                // not every format can encode errors, so fall back to the default
+                let (format, body) = match format.serialize(&e) {
+                    Ok(body) => (format, body),
+                    Err(_) => {
+                        let format = SerializationFormat::default();
+                        let body = format
+                            .serialize(&e)
+                            .expect("ErrorResponse Serialize should succeed");
+                        (format, body)
+                    }
+                };
+                headers.insert(header::CONTENT_TYPE, format.header_value());
+                if let Some(retry_after) = e.retry_after_seconds {
+                    headers.insert(header::RETRY_AFTER, header::HeaderValue::from(retry_after));
+                }
+                (e.kind.status_code(), headers, body)
+            }
+        }
+    }
+}
+
+fn into_response(
+    status: http::StatusCode,
+    headers: header::HeaderMap,
+    body: Vec<u8>,
+) -> warp::reply::Response {
+    let mut response = http::Response::new(hyper::Body::from(body));
+    *response.status_mut() = status;
+    *response.headers_mut() = headers;
+    response
+}
+
+impl<T: Payload + Send> warp::Reply for AppReply<T> {
+    fn into_response(self) -> warp::reply::Response {
+        let (status, headers, body) = self.into_parts();
+        into_response(status, headers, body)
+    }
+}
+
+/// An [`AppReply`] with its body compressed according to the request's `Accept-Encoding`
+pub struct CompressedReply<T: Payload> {
+    reply: AppReply<T>,
+    compression: Option<Compression>,
 }
 
-impl<T: serde::Serialize + Send> warp::Reply for AppReply<T> {
+impl<T: Payload + Send> warp::Reply for CompressedReply<T> {
     fn into_response(self) -> warp::reply::Response {
+        let (status, mut headers, body) = self.reply.into_parts();
+        headers.insert(
+            header::VARY,
+            header::HeaderValue::from_static(header::ACCEPT_ENCODING.as_str()),
+        );
+        match self.compression {
+            Some(compression) if !body.is_empty() => match compression.compress(&body) {
+                Ok(compressed) => {
+                    headers.insert(header::CONTENT_ENCODING, compression.header_value());
+                    into_response(status, headers, compressed)
+                }
+                Err(e) => {
+                    log::error!("Sending uncompressed reply, failed to compress: {}", e);
+                    into_response(status, headers, body)
+                }
+            },
+            _ => into_response(status, headers, body),
+        }
+    }
+}
+
+/// Wraps the reply of each read endpoint so that it is compressed when the client accepts it
+fn compressed<T: Payload>(
+    accept_encoding: Option<String>,
+) -> impl FnOnce(AppReply<T>) -> CompressedReply<T> {
+    let compression = accept_encoding
+        .as_deref()
+        .and_then(Compression::from_accept_encoding);
+    move |reply| CompressedReply { reply, compression }
+}
+
+impl ErrorKind {
+    pub fn status_code(&self) -> http::StatusCode {
         match self {
-            #[cfg(feature = "json")]
-            AppReply::Json(j) => warp::reply::json(&j).into_response(),
-            AppReply::Bincode(i) => http::Response::new(hyper::Body::from(
-                bincode::serialize(&i).expect("Bincode Serialize should succeed"),
-            )),
-            AppReply::Empty => http::Response::default(),
-            AppReply::Error(e) => e.into_response(),
+            ErrorKind::BadRequest => http::StatusCode::BAD_REQUEST,
+            ErrorKind::Unauthorized => http::StatusCode::UNAUTHORIZED,
+            ErrorKind::Forbidden => http::StatusCode::FORBIDDEN,
+            ErrorKind::NotFound => http::StatusCode::NOT_FOUND,
+            ErrorKind::NotAcceptable => http::StatusCode::NOT_ACCEPTABLE,
+            ErrorKind::UnsupportedMediaType => http::StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ErrorKind::Conflict => http::StatusCode::CONFLICT,
+            ErrorKind::TooManyRequests => http::StatusCode::TOO_MANY_REQUESTS,
+            ErrorKind::Internal => http::StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 }
 
-// make a new version for each return type?
-// need a new into_reply as well
-pub fn error_to_reply<T: serde::Serialize>(
-    maybe_err: Result<AppReply<T>>,
-) -> result::Result<AppReply<T>, convert::Infallible> {
-    match maybe_err {
+/// Turns an error into a reply, encoded in the given format.
+pub fn error_to_reply<T: Payload>(
+    format: SerializationFormat,
+) -> impl FnOnce(Result<AppReply<T>>) -> result::Result<AppReply<T>, convert::Infallible> {
+    move |maybe_err| match maybe_err {
         Ok(r) => Ok(r),
-        Err(e) => Ok(e.into_reply()),
+        Err(e) => Ok(e.into_reply(format)),
     }
 }
 
+/// The format errors are replied in, falling back to the default when nothing is acceptable
+fn negotiated(accept: &Option<String>) -> SerializationFormat {
+    SerializationFormat::negotiate(accept.as_deref()).unwrap_or_default()
+}
+
 impl Error {
-    pub fn into_reply<T: serde::Serialize>(self) -> AppReply<T> {
-        AppReply::Error(self.to_string())
+    pub fn into_reply<T: Payload>(self, format: SerializationFormat) -> AppReply<T> {
+        if self.kind() == ErrorKind::Internal {
+            log::error!("Internal error handling request: {}", self);
+        }
+        AppReply::Error(ErrorResponse::from(&self), format)
     }
 }
 
 // this is ok as it is an internal function
 #[allow(clippy::too_many_arguments)]
-async fn submit<S>(
+async fn submit<S, K>(
     host: Host,
     app: App,
     level: Level,
     api_key: String,
-    content_type: SerializationFormat,
+    content_type: String,
+    content_encoding: Option<String>,
+    accept: Option<String>,
+    batch_id: Option<String>,
     bytes: body::Bytes,
     storage: S,
-    api_keys: sync::Arc<collections::BTreeSet<String>>,
-) -> Result<AppReply<()>>
+    api_keys: sync::Arc<K>,
+    limits: RateLimiter,
+    dedup: DedupWindow,
+) -> Result<AppReply<SubmitAck>>
 where
     S: storage::Storage,
+    K: ApiKeyStore + ?Sized + 'static,
 {
     // ensure the request's API key is allowed
-    if !api_keys.contains(&api_key) {
-        return Err(Error::InvalidApiKey(api_key));
+    let key = auth::authorize(&*api_keys, api_key, Role::Submit).await?;
+    auth::authorize_tree(&key, &host, &app)?;
+
+    let (batch, body_len): (LogBatch, _) = decode_body(&content_type, content_encoding, &bytes)?;
+    let format = reply_format(accept, &content_type)?;
+
+    if let Some(batch_id) = &batch_id {
+        let stream = iter::once((&host, &app, &level, &batch));
+        if let Some(mut results) = dedup.replayed(batch_id, stream) {
+            log::debug!("Batch {} was submitted again", batch_id);
+            return Ok(AppReply::Data(results.remove(0).ack, format));
+        }
     }
 
-    let batch: LogBatch = match content_type {
-        SerializationFormat::Bincode => bincode::deserialize(&bytes)?,
-        #[cfg(feature = "json")]
-        SerializationFormat::Json => serde_json::from_slice(&bytes)?,
+    let stored = store_stream(
+        &storage,
+        &dedup,
+        &limits,
+        &key,
+        &host,
+        &app,
+        level.clone(),
+        batch,
+        body_len,
+    )
+    .await;
+    match stored {
+        (ack, None) => {
+            if let Some(batch_id) = batch_id {
+                let result = StreamResult {
+                    host,
+                    app,
+                    level,
+                    error: None,
+                    ack: ack.clone(),
+                };
+                dedup.remember(batch_id, &[result]);
+            }
+            Ok(AppReply::Data(ack, format))
+        }
+        (_, Some(e)) => Err(e),
+    }
+}
+
+/// Replies are in the format the body was sent in, unless the client asked for another
+fn reply_format(accept: Option<String>, content_type: &str) -> Result<SerializationFormat> {
+    match accept {
+        Some(accept) => SerializationFormat::negotiate(Some(&accept)),
+        None => content_type.parse(),
+    }
+}
+
+/// Stores the rows of a batch which haven't been stored already, charging the rate limits
+/// for those rows only. The rows are rejected when rate limited or when the storage fails,
+/// in which case the error is returned along with the ack. `body_len` is the share of the
+/// request body taken up by the batch.
+// this is ok as it is an internal function
+#[allow(clippy::too_many_arguments)]
+async fn store_stream<S: storage::Storage>(
+    storage: &S,
+    dedup: &DedupWindow,
+    limits: &RateLimiter,
+    key: &ApiKey,
+    host: &Host,
+    app: &App,
+    level: Level,
+    batch: LogBatch,
+    body_len: usize,
+) -> (SubmitAck, Option<Error>) {
+    let rows = batch.len().max(1);
+    let dedup::Reserved {
+        fresh,
+        already_present,
+        in_flight,
+        reservation,
+    } = dedup.reserve(host, app, &level, batch);
+    let ids = fresh.keys().copied().collect::<Vec<_>>();
+
+    let stored = if fresh.is_empty() {
+        Ok(())
+    } else {
+        match limits.check(key, host, app, fresh.len(), body_len * fresh.len() / rows) {
+            Ok(()) => storage.submit(host, app, level.clone(), fresh).await,
+            Err(e) => Err(e),
+        }
     };
 
-    storage.submit(&host, &app, level, batch).await?;
+    // rows another request is storing are rejected, so the client retries them until that
+    // request either stored them or failed
+    let conflict = (!in_flight.is_empty()).then(|| Error::SubmissionInProgress(in_flight.len()));
+
+    match stored {
+        Ok(()) => {
+            reservation.commit();
+            (
+                SubmitAck {
+                    accepted: ids,
+                    rejected: in_flight,
+                    already_present,
+                },
+                conflict,
+            )
+        }
+        // dropping the reservation releases the rows, so a retry can store them
+        Err(e) => (
+            SubmitAck {
+                rejected: ids.into_iter().chain(in_flight).collect(),
+                already_present,
+                ..Default::default()
+            },
+            Some(e),
+        ),
+    }
+}
+
+/// Decompresses and deserializes a request body, also returning its decompressed length
+fn decode_body<T: Payload>(
+    content_type: &str,
+    content_encoding: Option<String>,
+    bytes: &[u8],
+) -> Result<(T, usize)> {
+    let content_type: SerializationFormat = content_type.parse()?;
+
+    let bytes = decompress_body(content_encoding, bytes)?;
+
+    let payload = content_type
+        .deserialize(&bytes)
+        .map_err(|e| Error::InvalidRequestBody(e.to_string()))?;
+
+    Ok((payload, bytes.len()))
+}
+
+/// Undoes the request's `Content-Encoding`, if any
+fn decompress_body(
+    content_encoding: Option<String>,
+    bytes: &[u8],
+) -> Result<borrow::Cow<'_, [u8]>> {
+    let compression = content_encoding
+        .as_deref()
+        .map(Compression::from_content_encoding)
+        .transpose()?
+        .flatten();
+
+    match compression {
+        Some(compression) => compression
+            .decompress(bytes, MAX_DECOMPRESSED)
+            .map(borrow::Cow::Owned)
+            .map_err(|e| match e {
+                Error::InvalidRequestBody(_) => e,
+                e => Error::InvalidRequestBody(e.to_string()),
+            }),
+        None => Ok(borrow::Cow::Borrowed(bytes)),
+    }
+}
+
+// this is ok as it is an internal function
+#[allow(clippy::too_many_arguments)]
+async fn submit_batch<S, K>(
+    api_key: String,
+    content_type: String,
+    content_encoding: Option<String>,
+    accept: Option<String>,
+    batch_id: Option<String>,
+    bytes: body::Bytes,
+    storage: S,
+    api_keys: sync::Arc<K>,
+    limits: RateLimiter,
+    dedup: DedupWindow,
+) -> Result<AppReply<Vec<StreamResult>>>
+where
+    S: storage::Storage,
+    K: ApiKeyStore + ?Sized + 'static,
+{
+    let key = auth::authorize(&*api_keys, api_key, Role::Submit).await?;
+
+    let (streams, body_len): (Vec<StreamBatch>, _) =
+        decode_body(&content_type, content_encoding, &bytes)?;
 
-    Ok(AppReply::Empty)
+    let format = reply_format(accept, &content_type)?;
+
+    if let Some(batch_id) = &batch_id {
+        let replayed = streams
+            .iter()
+            .map(|s| (&s.host, &s.app, &s.level, &s.batch));
+        if let Some(results) = dedup.replayed(batch_id, replayed) {
+            log::debug!("Batch {} was submitted again", batch_id);
+            return Ok(AppReply::Data(results, format));
+        }
+    }
+
+    let total_rows = streams.iter().map(|s| s.batch.len()).sum::<usize>().max(1);
+
+    let mut results = Vec::with_capacity(streams.len());
+    for StreamBatch {
+        host,
+        app,
+        level,
+        batch,
+    } in streams
+    {
+        // each stream is charged its share of the body, as the limits are per host and app
+        let stream_len = body_len * batch.len() / total_rows;
+
+        let (ack, error) = match auth::authorize_tree(&key, &host, &app) {
+            Ok(()) => {
+                store_stream(
+                    &storage,
+                    &dedup,
+                    &limits,
+                    &key,
+                    &host,
+                    &app,
+                    level.clone(),
+                    batch,
+                    stream_len,
+                )
+                .await
+            }
+            Err(e) => (
+                SubmitAck {
+                    rejected: batch.into_keys().collect(),
+                    ..Default::default()
+                },
+                Some(e),
+            ),
+        };
+
+        let error = error.map(|e| {
+            if e.kind() == ErrorKind::Internal {
+                log::error!("Internal error storing batch for {}/{}: {}", host, app, e);
+            }
+            ErrorResponse::from(&e)
+        });
+
+        results.push(StreamResult {
+            host,
+            app,
+            level,
+            error,
+            ack,
+        });
+    }
+
+    if let Some(batch_id) = batch_id {
+        dedup.remember(batch_id, &results);
+    }
+
+    Ok(AppReply::Data(results, format))
 }
 
-async fn query<S>(
+async fn query<S, K>(
     api_key: String,
-    accept: SerializationFormat,
+    accept: Option<String>,
     params: QueryParams,
     storage: S,
-    api_keys: sync::Arc<collections::BTreeSet<String>>,
+    api_keys: sync::Arc<K>,
     // vec QueryResponse isn't that nice, but
     // it is the best option when using JSON serialization.
     // for Bincode or RON there could be another endpoint.
 ) -> Result<AppReply<Vec<QueryResponse>>>
 where
     S: storage::Storage,
+    K: ApiKeyStore + ?Sized + 'static,
 {
     // ensure the request's API key is allowed
-    if !api_keys.contains(&api_key) {
-        return Err(Error::InvalidApiKey(api_key));
-    }
+    let key = auth::authorize(&*api_keys, api_key, Role::Read).await?;
+
+    // the key's scope is applied by the storage, so that `max_results` counts only the rows
+    // the key may see
+    let scope = storage::Scope {
+        hosts: key.hosts.clone(),
+        apps: key.apps.clone(),
+    };
 
     // later this function should access the db via a channel to bridge sync and async
     // this will avoid blocking the runtime
     // in the short term we will leave it like this
-    let response = storage.query(params).await?;
+    let response = storage
+        .query(params, &scope)
+        .await?
+        .into_iter()
+        .filter(|row| key.permits(&row.host, &row.app))
+        .collect::<Vec<_>>();
 
-    match accept {
-        SerializationFormat::Bincode => Ok(AppReply::Bincode(response)),
-        #[cfg(feature = "json")]
-        SerializationFormat::Json => Ok(AppReply::Json(response)),
-    }
+    Ok(AppReply::Data(
+        response,
+        SerializationFormat::negotiate(accept.as_deref())?,
+    ))
 }
 
-async fn detail<S>(
+async fn detail<S, K>(
     host: Host,
     app: App,
     level: Level,
     api_key: String,
-    accept: SerializationFormat,
+    accept: Option<String>,
     storage: S,
-    api_keys: sync::Arc<collections::BTreeSet<String>>,
+    api_keys: sync::Arc<K>,
 ) -> Result<AppReply<LogTreeDetail>>
 where
     S: storage::Storage,
+    K: ApiKeyStore + ?Sized + 'static,
 {
     // ensure the request's API key is allowed
-    if !api_keys.contains(&api_key) {
-        return Err(Error::InvalidApiKey(api_key));
-    }
+    let key = auth::authorize(&*api_keys, api_key, Role::Read).await?;
+    auth::authorize_tree(&key, &host, &app)?;
 
     let response = storage.detail(&host, &app, level).await?;
 
-    match accept {
-        SerializationFormat::Bincode => Ok(AppReply::Bincode(response)),
-        #[cfg(feature = "json")]
-        SerializationFormat::Json => Ok(AppReply::Json(response)),
-    }
+    Ok(AppReply::Data(
+        response,
+        SerializationFormat::negotiate(accept.as_deref())?,
+    ))
 }
 
-async fn info<S>(
+async fn info<S, K>(
     api_key: String,
-    accept: SerializationFormat,
+    accept: Option<String>,
     storage: S,
-    api_keys: sync::Arc<collections::BTreeSet<String>>,
+    api_keys: sync::Arc<K>,
     // vec LogTreeInfo isn't that nice, but
     // it is the best option when using JSON serialization.
     // for Bincode or RON there could be another endpoint.
 ) -> Result<AppReply<Vec<result::Result<LogTreeInfo, ParseLogTreeInfoError>>>>
 where
     S: storage::Storage,
+    K: ApiKeyStore + ?Sized + 'static,
 {
     // ensure the request's API key is allowed
-    if !api_keys.contains(&api_key) {
-        return Err(Error::InvalidApiKey(api_key));
-    }
+    let key = auth::authorize(&*api_keys, api_key, Role::Read).await?;
 
-    let db_info = storage.info().await?;
+    let db_info = storage
+        .info()
+        .await?
+        .into_iter()
+        .filter(|i| match i {
+            Ok(i) => key.permits(&i.host, &i.app),
+            // these can't be attributed to a host and app, so only unrestricted keys see them
+            Err(_) => key.hosts.is_none() && key.apps.is_none(),
+        })
+        .collect::<Vec<_>>();
 
-    match accept {
-        SerializationFormat::Bincode => Ok(AppReply::Bincode(db_info)),
-        #[cfg(feature = "json")]
-        SerializationFormat::Json => Ok(AppReply::Json(db_info)),
-    }
+    Ok(AppReply::Data(
+        db_info,
+        SerializationFormat::negotiate(accept.as_deref())?,
+    ))
 }
 
 // These endpoints are kept seperate as sometimes only one may be needed
 // for example if using local-subscriber, people may want query to add to their own app
 // if providing a submission endpoint, the app may not necessarily need to provider query as well.
-pub fn create_submission_endpoint<S>(
+pub fn create_submission_endpoint<S, K>(
     storage: S,
-    api_keys: sync::Arc<collections::BTreeSet<String>>,
-) -> impl warp::Filter<Extract = (AppReply<()>,), Error = warp::Rejection> + Clone
+    api_keys: sync::Arc<K>,
+    limits: RateLimiter,
+    dedup: DedupWindow,
+) -> impl warp::Filter<Extract = (AppReply<SubmitAck>,), Error = warp::Rejection> + Clone
 where
     S: storage::Storage,
+    K: ApiKeyStore + ?Sized + 'static,
 {
     warp::path("submit")
         .and(warp::post())
@@ -185,40 +584,136 @@ where
         .and(warp::path::end())
         .and(warp::header(API_KEY_HEADER))
         .and(warp::header(header::CONTENT_TYPE.as_str()))
+        .and(warp::header::optional(header::CONTENT_ENCODING.as_str()))
+        .and(warp::header::optional(header::ACCEPT.as_str()))
+        .and(warp::header::optional(BATCH_ID_HEADER))
+        .and(warp::body::content_length_limit(MAX_BODY))
         .and(warp::body::bytes()) // LogBatch payload
         .and(add(storage))
         .and(add(api_keys))
-        .and_then(|host, app, level, key, content_type, batch, db, keys| {
-            submit(host, app, level, key, content_type, batch, db, keys).map(error_to_reply)
-        })
+        .and(add(limits))
+        .and(add(dedup))
+        .and_then(
+            |host,
+             app,
+             level,
+             key,
+             content_type: String,
+             encoding,
+             accept,
+             batch_id,
+             batch,
+             db,
+             keys,
+             limits,
+             dedup| {
+                let on_error = error_to_reply(content_type.parse().unwrap_or_default());
+                submit(
+                    host,
+                    app,
+                    level,
+                    key,
+                    content_type,
+                    encoding,
+                    accept,
+                    batch_id,
+                    batch,
+                    db,
+                    keys,
+                    limits,
+                    dedup,
+                )
+                .map(on_error)
+            },
+        )
 }
 
-pub fn create_query_endpoint<S>(
+/// Stores the batches of many host, app and level streams in one request, replying with
+/// the outcome of each stream
+pub fn create_submit_batch_endpoint<S, K>(
     storage: S,
-    api_keys: sync::Arc<collections::BTreeSet<String>>,
-) -> impl warp::Filter<Extract = (AppReply<Vec<QueryResponse>>,), Error = warp::Rejection> + Clone
+    api_keys: sync::Arc<K>,
+    limits: RateLimiter,
+    dedup: DedupWindow,
+) -> impl warp::Filter<Extract = (AppReply<Vec<StreamResult>>,), Error = warp::Rejection> + Clone
 where
     S: storage::Storage,
+    K: ApiKeyStore + ?Sized + 'static,
+{
+    warp::path("submit-batch")
+        .and(warp::post())
+        .and(warp::path::end())
+        .and(warp::header(API_KEY_HEADER))
+        .and(warp::header(header::CONTENT_TYPE.as_str()))
+        .and(warp::header::optional(header::CONTENT_ENCODING.as_str()))
+        .and(warp::header::optional(header::ACCEPT.as_str()))
+        .and(warp::header::optional(BATCH_ID_HEADER))
+        .and(warp::body::content_length_limit(MAX_BODY))
+        .and(warp::body::bytes()) // Vec<StreamBatch> payload
+        .and(add(storage))
+        .and(add(api_keys))
+        .and(add(limits))
+        .and(add(dedup))
+        .and_then(
+            |key,
+             content_type: String,
+             encoding,
+             accept,
+             batch_id,
+             streams,
+             db,
+             keys,
+             limits,
+             dedup| {
+                let on_error = error_to_reply(content_type.parse().unwrap_or_default());
+                submit_batch(
+                    key,
+                    content_type,
+                    encoding,
+                    accept,
+                    batch_id,
+                    streams,
+                    db,
+                    keys,
+                    limits,
+                    dedup,
+                )
+                .map(on_error)
+            },
+        )
+}
+
+pub fn create_query_endpoint<S, K>(
+    storage: S,
+    api_keys: sync::Arc<K>,
+) -> impl warp::Filter<Extract = (CompressedReply<Vec<QueryResponse>>,), Error = warp::Rejection> + Clone
+where
+    S: storage::Storage,
+    K: ApiKeyStore + ?Sized + 'static,
 {
     warp::path("query")
         .and(warp::get())
         .and(warp::path::end())
         .and(warp::header(API_KEY_HEADER))
-        .and(warp::header(header::ACCEPT.as_str()))
+        .and(warp::header::optional(header::ACCEPT.as_str()))
+        .and(warp::header::optional(header::ACCEPT_ENCODING.as_str()))
         .and(warp::query())
         .and(add(storage))
         .and(add(api_keys))
-        .and_then(|key, accept, params, db, keys| {
-            query(key, accept, params, db, keys).map(error_to_reply)
+        .and_then(|key, accept: Option<String>, encoding, params, db, keys| {
+            let on_error = error_to_reply(negotiated(&accept));
+            let compress = compressed(encoding);
+            query(key, accept, params, db, keys).map(|r| on_error(r).map(compress))
         })
 }
 
-pub fn create_detail_endpoint<S>(
+pub fn create_detail_endpoint<S, K>(
     storage: S,
-    api_keys: sync::Arc<collections::BTreeSet<String>>,
-) -> impl warp::Filter<Extract = (AppReply<LogTreeDetail>,), Error = warp::Rejection> + Clone
+    api_keys: sync::Arc<K>,
+) -> impl warp::Filter<Extract = (CompressedReply<LogTreeDetail>,), Error = warp::Rejection> + Clone
 where
     S: storage::Storage,
+    K: ApiKeyStore + ?Sized + 'static,
 {
     warp::path("detail")
         .and(warp::get())
@@ -227,30 +722,282 @@ where
         .and(warp::path::param()) // Level
         .and(warp::path::end())
         .and(warp::header(API_KEY_HEADER))
-        .and(warp::header(header::ACCEPT.as_str()))
+        .and(warp::header::optional(header::ACCEPT.as_str()))
+        .and(warp::header::optional(header::ACCEPT_ENCODING.as_str()))
         .and(add(storage))
         .and(add(api_keys))
-        .and_then(|host, app, level, key, accept, db, keys| {
-            detail(host, app, level, key, accept, db, keys).map(error_to_reply)
-        })
+        .and_then(
+            |host, app, level, key, accept: Option<String>, encoding, db, keys| {
+                let on_error = error_to_reply(negotiated(&accept));
+                let compress = compressed(encoding);
+                detail(host, app, level, key, accept, db, keys).map(|r| on_error(r).map(compress))
+            },
+        )
 }
 
-pub fn create_info_endpoint<S>(
+pub fn create_info_endpoint<S, K>(
     storage: S,
-    api_keys: sync::Arc<collections::BTreeSet<String>>,
+    api_keys: sync::Arc<K>,
 ) -> impl warp::Filter<
-    Extract = (AppReply<Vec<result::Result<LogTreeInfo, ParseLogTreeInfoError>>>,),
+    Extract = (CompressedReply<Vec<result::Result<LogTreeInfo, ParseLogTreeInfoError>>>,),
     Error = warp::Rejection,
 > + Clone
 where
     S: storage::Storage,
+    K: ApiKeyStore + ?Sized + 'static,
 {
     warp::path("info")
         .and(warp::get())
         .and(warp::path::end())
         .and(warp::header(API_KEY_HEADER))
-        .and(warp::header(header::ACCEPT.as_str()))
+        .and(warp::header::optional(header::ACCEPT.as_str()))
+        .and(warp::header::optional(header::ACCEPT_ENCODING.as_str()))
         .and(add(storage))
         .and(add(api_keys))
-        .and_then(|key, accept, db, keys| info(key, accept, db, keys).map(error_to_reply))
+        .and_then(|key, accept: Option<String>, encoding, db, keys| {
+            let on_error = error_to_reply(negotiated(&accept));
+            let compress = compressed(encoding);
+            info(key, accept, db, keys).map(|r| on_error(r).map(compress))
+        })
+}
+
+#[cfg(all(test, feature = "sled", feature = "json"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_error_status() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let api_keys = sync::Arc::new(
+            iter::once("123".to_string()).collect::<collections::BTreeSet<String>>(),
+        );
+        let endpoint = create_query_endpoint(db, api_keys);
+
+        let response = warp::test::request()
+            .path("/query")
+            .header(API_KEY_HEADER, "456")
+            .header(header::ACCEPT, APPLICATION_JSON)
+            .reply(&endpoint)
+            .await;
+        assert_eq!(response.status(), http::StatusCode::UNAUTHORIZED);
+        let error: ErrorResponse = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(error.kind, ErrorKind::Unauthorized);
+
+        let response = warp::test::request()
+            .path("/query")
+            .header(API_KEY_HEADER, "123")
+            .header(header::ACCEPT, "text/plain")
+            .reply(&endpoint)
+            .await;
+        assert_eq!(response.status(), http::StatusCode::NOT_ACCEPTABLE);
+
+        let response = warp::test::request()
+            .path("/query")
+            .header(API_KEY_HEADER, "123")
+            .reply(&endpoint)
+            .await;
+        assert_eq!(response.status(), http::StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            SerializationFormat::default().header_value()
+        );
+
+        let response = warp::test::request()
+            .path("/query?message_matches=(")
+            .header(API_KEY_HEADER, "123")
+            .header(header::ACCEPT, APPLICATION_JSON)
+            .reply(&endpoint)
+            .await;
+        assert_eq!(response.status(), http::StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_submit_batch() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let api_keys = sync::Arc::new(collections::BTreeMap::from([(
+            "123".to_string(),
+            ApiKey {
+                apps: Some(iter::once("allowed".parse().unwrap()).collect()),
+                ..ApiKey::new(Role::Submit)
+            },
+        )]));
+        // only enough quota for the rows of the first request
+        let limits = RateLimiter::new(Limit {
+            daily_rows: Some(2),
+            ..Default::default()
+        });
+        let endpoint =
+            create_submit_batch_endpoint(db.clone(), api_keys, limits, DedupWindow::default());
+
+        let stream = |app: &str, level| StreamBatch {
+            host: "host".parse().unwrap(),
+            app: app.parse().unwrap(),
+            level,
+            batch: iter::once((
+                ulid::Ulid::new(),
+                LogData {
+                    message: "message".to_string(),
+                    code_module: None,
+                    code_line: None,
+                    code_file: None,
+                    tags: Default::default(),
+                },
+            ))
+            .collect(),
+        };
+        let streams = vec![
+            stream("allowed", Level::Info),
+            stream("denied", Level::Info),
+            stream("allowed", Level::Error),
+        ];
+
+        let body = serde_json::to_vec(&streams).unwrap();
+        let submit = |batch_id| {
+            let (body, endpoint) = (body.clone(), &endpoint);
+            async move {
+                let response = warp::test::request()
+                    .method("POST")
+                    .path("/submit-batch")
+                    .header(API_KEY_HEADER, "123")
+                    .header(BATCH_ID_HEADER, batch_id)
+                    .header(header::CONTENT_TYPE, APPLICATION_JSON)
+                    .body(body)
+                    .reply(endpoint)
+                    .await;
+                assert_eq!(response.status(), http::StatusCode::OK);
+                serde_json::from_slice::<Vec<StreamResult>>(response.body()).unwrap()
+            }
+        };
+
+        let results = submit("batch").await;
+        let kinds = results
+            .iter()
+            .map(|r| r.error.as_ref().map(|e| e.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, vec![None, Some(ErrorKind::Forbidden), None]);
+        assert_eq!(
+            results[0].ack.accepted,
+            streams[0].batch.keys().copied().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            results[1].ack.rejected,
+            streams[1].batch.keys().copied().collect::<Vec<_>>()
+        );
+
+        // replaying the batch gets the same reply
+        let replayed = submit("batch").await;
+        assert_eq!(
+            replayed.iter().map(|r| &r.ack).collect::<Vec<_>>(),
+            results.iter().map(|r| &r.ack).collect::<Vec<_>>()
+        );
+
+        // the same rows in another batch aren't stored again, nor charged to the quota
+        let resent = submit("other").await;
+        assert!(resent[0].error.is_none());
+        assert!(resent[0].ack.accepted.is_empty());
+        assert_eq!(resent[0].ack.already_present, results[0].ack.accepted);
+
+        let stored = storage::Storage::query(
+            &db,
+            QueryParams {
+                max_log_level: Some(Level::Trace),
+                ..Default::default()
+            },
+            &storage::Scope::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(stored.len(), 2);
+    }
+
+    #[cfg(feature = "gzip")]
+    #[tokio::test]
+    async fn test_oversized_body() {
+        use std::io::Write;
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let api_keys = sync::Arc::new(
+            iter::once("123".to_string()).collect::<collections::BTreeSet<String>>(),
+        );
+        let endpoint = create_submission_endpoint(
+            db,
+            api_keys,
+            RateLimiter::unlimited(),
+            DedupWindow::default(),
+        );
+        let submit = |encoding: Option<&str>, body: Vec<u8>| {
+            let mut request = warp::test::request()
+                .method("POST")
+                .path("/submit/host/app/Info")
+                .header(API_KEY_HEADER, "123")
+                .header(header::CONTENT_TYPE, APPLICATION_JSON);
+            if let Some(encoding) = encoding {
+                request = request.header(header::CONTENT_ENCODING, encoding);
+            }
+            request.body(body).reply(&endpoint)
+        };
+
+        // a small body which inflates to more than the server will decompress
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        let zeros = vec![0; 1024 * 1024];
+        for _ in 0..=MAX_DECOMPRESSED / zeros.len() as u64 {
+            encoder.write_all(&zeros).unwrap();
+        }
+        let bomb = encoder.finish().unwrap();
+        assert!((bomb.len() as u64) < MAX_BODY);
+        let response = submit(Some(GZIP), bomb).await;
+        assert_eq!(response.status(), http::StatusCode::BAD_REQUEST);
+        let error: ErrorResponse = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(error.kind, ErrorKind::BadRequest);
+
+        let response = submit(None, vec![b' '; MAX_BODY as usize + 1]).await;
+        assert_eq!(response.status(), http::StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn test_scoped_query() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let host = "host".parse::<Host>().unwrap();
+        for (app, count) in [("a", 3), ("b", 1)] {
+            let mut generator = ulid::Generator::new();
+            let batch = (0..count)
+                .map(|i| {
+                    let data = LogData {
+                        message: format!("{}{}", app, i),
+                        code_module: None,
+                        code_line: None,
+                        code_file: None,
+                        tags: Default::default(),
+                    };
+                    (generator.generate().unwrap(), data)
+                })
+                .collect();
+            storage::Storage::submit(&db, &host, &app.parse().unwrap(), Level::Info, batch)
+                .await
+                .unwrap();
+        }
+        let api_keys = sync::Arc::new(collections::BTreeMap::from([(
+            "123".to_string(),
+            ApiKey {
+                apps: Some(iter::once("b".parse().unwrap()).collect()),
+                ..ApiKey::new(Role::Read)
+            },
+        )]));
+        let endpoint = create_query_endpoint(db, api_keys);
+
+        // the rows of other apps don't use up `max_results`
+        let response = warp::test::request()
+            .path("/query?max_results=1")
+            .header(API_KEY_HEADER, "123")
+            .header(header::ACCEPT, APPLICATION_JSON)
+            .reply(&endpoint)
+            .await;
+        assert_eq!(response.status(), http::StatusCode::OK);
+        let rows: Vec<QueryResponse> = serde_json::from_slice(response.body()).unwrap();
+        let messages = rows
+            .iter()
+            .map(|r| r.data.message.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(messages, ["b0"]);
+    }
 }
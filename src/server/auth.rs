@@ -0,0 +1,182 @@
+use super::*;
+
+/// What an API key is allowed to do. `Admin` keys may call every endpoint.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// may only call `/submit`
+    Submit,
+    /// may only call the read endpoints, `/query`, `/detail`, `/info` and `/tail`
+    Read,
+    Admin,
+}
+
+impl Role {
+    pub fn includes(&self, required: Role) -> bool {
+        *self == Role::Admin || *self == required
+    }
+}
+
+impl str::FromStr for Role {
+    type Err = String;
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_ref() {
+            "submit" => Role::Submit,
+            "read" => Role::Read,
+            "admin" => Role::Admin,
+            otherwise => return Err(format!("Unexpected API key role `{}`", otherwise)),
+        })
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Submit => write!(f, "submit"),
+            Role::Read => write!(f, "read"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// The permissions attached to an API key
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct ApiKey {
+    /// identifies the key in audit logs without revealing the key itself
+    #[serde(default)]
+    pub id: Option<String>,
+    pub role: Role,
+    /// when set, the key can only submit or read logs for these hosts
+    pub hosts: Option<collections::BTreeSet<Host>>,
+    /// when set, the key can only submit or read logs for these apps
+    pub apps: Option<collections::BTreeSet<App>>,
+}
+
+impl ApiKey {
+    pub fn new(role: Role) -> ApiKey {
+        ApiKey {
+            id: None,
+            role,
+            hosts: None,
+            apps: None,
+        }
+    }
+
+    pub fn permits(&self, host: &Host, app: &App) -> bool {
+        self.hosts
+            .as_ref()
+            .map(|h| h.contains(host))
+            .unwrap_or(true)
+            && self.apps.as_ref().map(|a| a.contains(app)).unwrap_or(true)
+    }
+}
+
+/// Looks up the permissions of the API key sent with each request
+#[async_trait::async_trait]
+pub trait ApiKeyStore: Send + Sync {
+    async fn get(&self, api_key: &str) -> Option<ApiKey>;
+}
+
+/// Every key in the set is treated as an `Admin` key
+#[async_trait::async_trait]
+impl ApiKeyStore for collections::BTreeSet<String> {
+    async fn get(&self, api_key: &str) -> Option<ApiKey> {
+        self.contains(api_key).then(|| ApiKey::new(Role::Admin))
+    }
+}
+
+#[async_trait::async_trait]
+impl ApiKeyStore for collections::BTreeMap<String, ApiKey> {
+    async fn get(&self, api_key: &str) -> Option<ApiKey> {
+        collections::BTreeMap::get(self, api_key).cloned()
+    }
+}
+
+const AUDIT_TARGET: &str = "eigenlog::audit";
+
+/// Ensures that `api_key` exists and has the `required` role
+pub(crate) async fn authorize<K>(api_keys: &K, api_key: String, required: Role) -> Result<ApiKey>
+where
+    K: ApiKeyStore + ?Sized,
+{
+    let key = match api_keys.get(&api_key).await {
+        Some(key) => key,
+        None => return Err(Error::InvalidApiKey(api_key)),
+    };
+
+    let id = key.id.as_deref().unwrap_or("unnamed");
+
+    if !key.role.includes(required) {
+        log::warn!(target: AUDIT_TARGET, "API key `{id}` was refused the `{required}` role");
+        return Err(Error::Forbidden(format!(
+            "endpoints requiring the `{required}` role"
+        )));
+    }
+
+    // granted on every request, so only refusals are worth more than a debug line
+    log::debug!(target: AUDIT_TARGET, "API key `{id}` was granted the `{required}` role");
+
+    Ok(key)
+}
+
+/// Ensures that an already authorized key may access the logs of `host` and `app`
+pub(crate) fn authorize_tree(key: &ApiKey, host: &Host, app: &App) -> Result<()> {
+    if key.permits(host, app) {
+        Ok(())
+    } else {
+        log::warn!(
+            target: AUDIT_TARGET,
+            "API key `{}` was refused access to `{host}` `{app}`",
+            key.id.as_deref().unwrap_or("unnamed")
+        );
+        Err(Error::Forbidden(format!("logs of `{host}` `{app}`")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_authorize() {
+        let host = "abc".parse::<Host>().unwrap();
+        let app = "def".parse::<App>().unwrap();
+        let other_app = "ghi".parse::<App>().unwrap();
+
+        let keys = IntoIterator::into_iter([
+            ("admin".to_string(), ApiKey::new(Role::Admin)),
+            ("read".to_string(), ApiKey::new(Role::Read)),
+            (
+                "submit".to_string(),
+                ApiKey {
+                    id: Some("submit".to_string()),
+                    role: Role::Submit,
+                    hosts: None,
+                    apps: Some(iter::once(app.clone()).collect()),
+                },
+            ),
+        ])
+        .collect::<collections::BTreeMap<_, _>>();
+
+        assert!(authorize(&keys, "admin".to_string(), Role::Submit)
+            .await
+            .is_ok());
+        assert!(authorize(&keys, "admin".to_string(), Role::Read)
+            .await
+            .is_ok());
+        assert!(authorize(&keys, "read".to_string(), Role::Read)
+            .await
+            .is_ok());
+        assert!(authorize(&keys, "read".to_string(), Role::Submit)
+            .await
+            .is_err());
+        assert!(authorize(&keys, "missing".to_string(), Role::Read)
+            .await
+            .is_err());
+
+        let submit = authorize(&keys, "submit".to_string(), Role::Submit)
+            .await
+            .unwrap();
+        assert!(authorize_tree(&submit, &host, &app).is_ok());
+        assert!(authorize_tree(&submit, &host, &other_app).is_err());
+    }
+}
@@ -0,0 +1,292 @@
+use super::*;
+use std::time;
+
+/// Remembers the rows and batches submitted recently, so that a client retrying a request
+/// whose reply it never saw doesn't store the same rows twice.
+///
+/// Rows are reserved while they are handed to the storage, so concurrent retries of one
+/// batch can't both store it, and only count as already present once stored. The reply to
+/// each batch is kept by its id, so a replay of the whole batch gets the same reply without
+/// being stored or charged again.
+#[derive(Clone)]
+pub struct DedupWindow {
+    window: time::Duration,
+    max_rows: usize,
+    state: sync::Arc<sync::Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    rows: collections::HashMap<RowKey, time::Instant>,
+    // rows which a request is storing right now, not yet known to be stored
+    in_flight: collections::HashSet<RowKey>,
+    batches: collections::HashMap<String, Vec<StreamResult>>,
+    // insertion order of the above, used to forget them once outside the window
+    order: collections::VecDeque<(time::Instant, Seen)>,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct RowKey {
+    host: Host,
+    app: App,
+    level: Level,
+    id: ulid::Ulid,
+}
+
+enum Seen {
+    Row(RowKey),
+    Batch(String),
+}
+
+/// The rows of a batch split by whether they were already submitted
+pub(crate) struct Reserved {
+    pub fresh: LogBatch,
+    pub already_present: Vec<ulid::Ulid>,
+    /// rows another request is storing, which may still fail, so have to be sent again
+    pub in_flight: Vec<ulid::Ulid>,
+    pub reservation: Reservation,
+}
+
+/// Holds the fresh rows of a batch while they are stored. They are released when this is
+/// dropped without being committed, so a retry can store them.
+pub(crate) struct Reservation {
+    dedup: DedupWindow,
+    host: Host,
+    app: App,
+    level: Level,
+    ids: Vec<ulid::Ulid>,
+}
+
+impl Reservation {
+    /// Marks the rows as stored, so they are reported as already present from now on
+    pub(crate) fn commit(mut self) {
+        let ids = std::mem::take(&mut self.ids);
+        let mut state = self.dedup.lock();
+        let now = time::Instant::now();
+        for id in ids {
+            let key = self.key(id);
+            state.in_flight.remove(&key);
+            state.rows.insert(key.clone(), now);
+            state.order.push_back((now, Seen::Row(key)));
+        }
+    }
+
+    fn key(&self, id: ulid::Ulid) -> RowKey {
+        RowKey {
+            host: self.host.clone(),
+            app: self.app.clone(),
+            level: self.level.clone(),
+            id,
+        }
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let mut state = self.dedup.lock();
+        for id in &self.ids {
+            state.in_flight.remove(&self.key(*id));
+        }
+    }
+}
+
+impl DedupWindow {
+    /// Rows are remembered for `window`, or until more than `max_rows` newer rows were seen
+    pub fn new(window: time::Duration, max_rows: usize) -> DedupWindow {
+        DedupWindow {
+            window,
+            max_rows,
+            state: Default::default(),
+        }
+    }
+
+    /// The reply to an earlier batch with this id, if the streams are the same as its
+    pub(crate) fn replayed<'a>(
+        &self,
+        batch_id: &str,
+        streams: impl ExactSizeIterator<Item = (&'a Host, &'a App, &'a Level, &'a LogBatch)>,
+    ) -> Option<Vec<StreamResult>> {
+        let mut state = self.lock();
+        self.expire(&mut state);
+        let results = state.batches.get(batch_id)?;
+        if results.len() != streams.len() {
+            return None;
+        }
+        let same = results
+            .iter()
+            .zip(streams)
+            .all(|(result, (host, app, level, batch))| {
+                let ack = &result.ack;
+                let mut ids = ack
+                    .accepted
+                    .iter()
+                    .chain(&ack.rejected)
+                    .chain(&ack.already_present)
+                    .collect::<Vec<_>>();
+                ids.sort();
+                result.host == *host
+                    && result.app == *app
+                    && result.level == *level
+                    && ids.into_iter().eq(batch.keys())
+            });
+        same.then(|| results.clone())
+    }
+
+    /// Keeps the reply to a batch, unless it has streams which are worth retrying
+    pub(crate) fn remember(&self, batch_id: String, results: &[StreamResult]) {
+        let retryable = results
+            .iter()
+            .any(|r| r.error.as_ref().is_some_and(|e| e.kind.is_retryable()));
+        if retryable {
+            return;
+        }
+        let mut state = self.lock();
+        let replaced = state.batches.insert(batch_id.clone(), results.to_vec());
+        if replaced.is_none() {
+            state
+                .order
+                .push_back((time::Instant::now(), Seen::Batch(batch_id)));
+        }
+    }
+
+    pub(crate) fn reserve(
+        &self,
+        host: &Host,
+        app: &App,
+        level: &Level,
+        batch: LogBatch,
+    ) -> Reserved {
+        let mut state = self.lock();
+        self.expire(&mut state);
+
+        let mut reserved = Reserved {
+            fresh: LogBatch::new(),
+            already_present: Vec::new(),
+            in_flight: Vec::new(),
+            reservation: Reservation {
+                dedup: self.clone(),
+                host: host.clone(),
+                app: app.clone(),
+                level: level.clone(),
+                ids: Vec::new(),
+            },
+        };
+        for (id, data) in batch {
+            let key = reserved.reservation.key(id);
+            if state.rows.contains_key(&key) {
+                reserved.already_present.push(id);
+            } else if state.in_flight.contains(&key) {
+                reserved.in_flight.push(id);
+            } else {
+                state.in_flight.insert(key);
+                reserved.reservation.ids.push(id);
+                reserved.fresh.insert(id, data);
+            }
+        }
+        reserved
+    }
+
+    fn lock(&self) -> sync::MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(sync::PoisonError::into_inner)
+    }
+
+    fn expire(&self, state: &mut State) {
+        let now = time::Instant::now();
+        while let Some((at, _)) = state.order.front() {
+            let expired = now.saturating_duration_since(*at) > self.window
+                || state.rows.len() > self.max_rows;
+            if !expired {
+                break;
+            }
+            match state.order.pop_front() {
+                Some((at, Seen::Row(key))) => {
+                    // an expired row may have been stored again since, which is kept
+                    if state.rows.get(&key) == Some(&at) {
+                        state.rows.remove(&key);
+                    }
+                }
+                Some((_, Seen::Batch(id))) => {
+                    state.batches.remove(&id);
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+/// Remembers rows for ten minutes, up to a million of them
+impl Default for DedupWindow {
+    fn default() -> DedupWindow {
+        DedupWindow::new(time::Duration::from_secs(600), 1_000_000)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dedup_window() {
+        let dedup = DedupWindow::new(time::Duration::from_secs(60), 1000);
+        let host: Host = "host".parse().unwrap();
+        let app: App = "app".parse().unwrap();
+        let data = || LogData {
+            message: "message".to_string(),
+            code_module: None,
+            code_line: None,
+            code_file: None,
+            tags: Default::default(),
+        };
+        let (a, b, c) = (ulid::Ulid::new(), ulid::Ulid::new(), ulid::Ulid::new());
+
+        let first = dedup.reserve(&host, &app, &Level::Info, [(a, data()), (b, data())].into());
+        assert_eq!(first.fresh.len(), 2);
+        assert!(first.already_present.is_empty());
+
+        // rows which are still being stored aren't reported as present, as storing may fail
+        let concurrent = dedup.reserve(&host, &app, &Level::Info, [(a, data())].into());
+        assert!(concurrent.fresh.is_empty());
+        assert!(concurrent.already_present.is_empty());
+        assert_eq!(concurrent.in_flight, vec![a]);
+        first.reservation.commit();
+
+        // a retry overlapping the first batch only stores the new row
+        let retry = dedup.reserve(&host, &app, &Level::Info, [(b, data()), (c, data())].into());
+        assert_eq!(retry.fresh.keys().copied().collect::<Vec<_>>(), vec![c]);
+        assert_eq!(retry.already_present, vec![b]);
+
+        // the same ids in another stream are different rows
+        let other = dedup.reserve(&host, &app, &Level::Warn, [(a, data())].into());
+        assert_eq!(other.fresh.len(), 1);
+
+        // rows which weren't stored can be submitted again
+        drop(retry);
+        let after_release = dedup.reserve(&host, &app, &Level::Info, [(c, data())].into());
+        assert_eq!(after_release.fresh.len(), 1);
+
+        // a batch is only replayed when it has the same streams and rows
+        let batch: LogBatch = [(a, data())].into();
+        let results = vec![StreamResult {
+            host: host.clone(),
+            app: app.clone(),
+            level: Level::Info,
+            error: None,
+            ack: SubmitAck {
+                accepted: vec![a],
+                ..Default::default()
+            },
+        }];
+        let stream = || iter::once((&host, &app, &Level::Info, &batch));
+        assert!(dedup.replayed("batch", stream()).is_none());
+        dedup.remember("batch".to_string(), &results);
+        assert_eq!(
+            dedup.replayed("batch", stream()).unwrap()[0].ack,
+            results[0].ack
+        );
+        let other: LogBatch = [(b, data())].into();
+        let other_stream = iter::once((&host, &app, &Level::Info, &other));
+        assert!(dedup.replayed("batch", other_stream).is_none());
+    }
+}
@@ -0,0 +1,523 @@
+use super::ingest::{self, Event};
+use super::*;
+use chrono::TimeZone;
+use rmpv::Value;
+use std::io::Read;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net,
+};
+
+// a connection buffering more than this without completing an entry is dropped, and
+// compressed entries may not unpack to more than this either
+const MAX_BUFFERED: usize = 64 * 1024 * 1024;
+
+// the extension type Fluentd uses for timestamps with nanoseconds
+const EVENT_TIME: i8 = 0;
+
+// record fields checked in order, the first one present wins
+const HOST_FIELDS: &[&str] = &["host", "hostname"];
+const LEVEL_FIELDS: &[&str] = &["level", "severity", "log_level"];
+const MESSAGE_FIELDS: &[&str] = &["message", "log", "msg"];
+
+/// How the Fluent tag of each event picks its [`Host`] and [`App`]. Tags are split on `.`
+/// and the parts are counted from zero.
+#[derive(Clone, Debug, Default)]
+pub struct TagMapping {
+    /// the part of the tag holding the host, otherwise the record's `host` or `hostname`
+    pub host_part: Option<usize>,
+    /// the part of the tag holding the app, otherwise the whole tag is used
+    pub app_part: Option<usize>,
+    /// tags which are mapped to a fixed host and app, ahead of the parts above
+    pub exact: collections::HashMap<String, (Host, App)>,
+}
+
+/// Receives events from Fluentd and Fluent Bit over the Forward protocol, in Message, Forward
+/// and (compressed) PackedForward mode. When the sender asks for acknowledgements, one is sent
+/// once the chunk has been stored.
+///
+/// The event's `level` (or `severity`) field becomes the [`Level`] and its `message` (or
+/// `log`) the message, the other fields are kept as tags along with the Fluent tag itself.
+/// The shared key handshake isn't supported, so the listener should only be reachable by
+/// trusted forwarders.
+#[derive(Clone)]
+pub struct FluentListener<S> {
+    storage: S,
+    default_host: Host,
+    default_app: App,
+    mapping: TagMapping,
+}
+
+impl<S> FluentListener<S> {
+    pub fn new(storage: S) -> FluentListener<S> {
+        FluentListener {
+            storage,
+            default_host: "unknown".parse().unwrap(),
+            default_app: "fluent".parse().unwrap(),
+            mapping: TagMapping::default(),
+        }
+    }
+
+    pub fn with_defaults(mut self, host: Host, app: App) -> FluentListener<S> {
+        self.default_host = host;
+        self.default_app = app;
+        self
+    }
+
+    pub fn with_tag_mapping(mut self, mapping: TagMapping) -> FluentListener<S> {
+        self.mapping = mapping;
+        self
+    }
+
+    /// Turns one entry of the stream into its events, along with the chunk id to acknowledge
+    fn events(&self, entry: Value) -> Result<(Vec<Event>, Option<String>)> {
+        let invalid =
+            |msg: &str| Error::InvalidRequestBody(format!("invalid forward entry: {}", msg));
+
+        let mut fields = match entry {
+            Value::Array(fields) if fields.len() >= 2 => fields.into_iter(),
+            _ => return Err(invalid("expected an array of the tag and its events")),
+        };
+        let tag = match fields.next() {
+            Some(Value::String(tag)) => tag.into_str().ok_or_else(|| invalid("tag isn't utf-8"))?,
+            _ => return Err(invalid("expected the tag")),
+        };
+
+        let (entries, option) = match fields.next() {
+            // Message mode: `[tag, time, record, option]`
+            Some(time @ (Value::Integer(_) | Value::Ext(..) | Value::F64(_))) => {
+                let record = fields.next().ok_or_else(|| invalid("missing the record"))?;
+                (vec![Value::Array(vec![time, record])], fields.next())
+            }
+            // Forward mode: `[tag, [[time, record], ...], option]`
+            Some(Value::Array(entries)) => (entries, fields.next()),
+            // PackedForward mode: `[tag, <[time, record] entries back to back>, option]`
+            Some(Value::Binary(packed)) => {
+                let option = fields.next();
+                (unpack(&packed, option.as_ref())?, option)
+            }
+            Some(Value::String(packed)) => {
+                let option = fields.next();
+                (unpack(packed.as_bytes(), option.as_ref())?, option)
+            }
+            _ => return Err(invalid("expected a time, entries or packed entries")),
+        };
+
+        let chunk = option
+            .as_ref()
+            .and_then(|o| map_get(o, "chunk"))
+            .and_then(Value::as_str)
+            .map(ToString::to_string);
+
+        let events = entries
+            .into_iter()
+            .map(|entry| match entry {
+                Value::Array(entry) if entry.len() == 2 => {
+                    let mut entry = entry.into_iter();
+                    let time = entry.next().unwrap_or(Value::Nil);
+                    let record = entry.next().unwrap_or(Value::Nil);
+                    Ok(self.event(&tag, &time, record))
+                }
+                _ => Err(invalid("expected a `[time, record]` entry")),
+            })
+            .collect::<Result<_>>()?;
+
+        Ok((events, chunk))
+    }
+
+    fn event(&self, tag: &str, time: &Value, record: Value) -> Event {
+        let mut record = match record {
+            Value::Map(fields) => fields
+                .into_iter()
+                .filter_map(|(k, v)| Some((k.as_str()?.to_string(), v)))
+                .collect::<collections::BTreeMap<_, _>>(),
+            _ => Default::default(),
+        };
+        let mut take = |names: &[&str]| {
+            names
+                .iter()
+                .find_map(|name| record.remove(*name))
+                .map(|v| value_to_string(&v))
+        };
+
+        let record_host = take(HOST_FIELDS);
+        let level = take(LEVEL_FIELDS).and_then(|l| ingest::level_from_name(&l));
+        let message = take(MESSAGE_FIELDS).unwrap_or_default();
+
+        let part = |index: Option<usize>| index.and_then(|i| tag.split('.').nth(i));
+        let (host, app) = match self.mapping.exact.get(tag) {
+            Some((host, app)) => (host.clone(), app.clone()),
+            None => (
+                ingest::host_or(
+                    part(self.mapping.host_part).or(record_host.as_deref()),
+                    &self.default_host,
+                ),
+                ingest::app_or(part(self.mapping.app_part).or(Some(tag)), &self.default_app),
+            ),
+        };
+
+        let mut tags = record
+            .iter()
+            .map(|(k, v)| (k.clone(), value_to_string(v)))
+            .collect::<collections::HashMap<_, _>>();
+        tags.insert("fluent_tag".to_string(), tag.to_string());
+
+        Event {
+            host,
+            app,
+            level: level.unwrap_or(Level::Info),
+            timestamp: event_time(time),
+            data: ingest::log_data(message, tags),
+        }
+    }
+}
+
+impl<S> FluentListener<S>
+where
+    S: storage::Storage + 'static,
+{
+    /// Accepts forwarders until the listener fails, each connection is handled separately
+    pub async fn run(self, addr: impl net::ToSocketAddrs) -> Result<()> {
+        let listener = net::TcpListener::bind(addr).await?;
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let listener = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = listener.handle(stream).await {
+                    log::warn!("Fluent connection from {} failed: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    async fn handle(&self, mut stream: net::TcpStream) -> Result<()> {
+        let mut decoder = Decoder::default();
+        loop {
+            while let Some(entry) = decoder.next_value()? {
+                let (events, chunk) = self.events(entry)?;
+                // without an ack the forwarder won't resend, so keep the connection going
+                if let Err(e) = ingest::store(&self.storage, events).await {
+                    log::error!("Failed to store fluent events: {}", e);
+                    continue;
+                }
+                if let Some(chunk) = chunk {
+                    let ack = Value::Map(vec![(Value::from("ack"), Value::from(chunk))]);
+                    let mut reply = Vec::new();
+                    rmpv::encode::write_value(&mut reply, &ack).map_err(|e| Error::Io(e.into()))?;
+                    stream.write_all(&reply).await?;
+                }
+            }
+
+            if decoder.buffered() > MAX_BUFFERED {
+                return Err(Error::InvalidRequestBody(
+                    "forward entry is too large".to_string(),
+                ));
+            }
+            decoder.compact();
+            if stream.read_buf(&mut decoder.buffer).await? == 0 {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Splits complete msgpack values off the bytes read from a connection. An entry arriving
+/// over many reads is scanned once, resuming where the previous read left off, and only
+/// decoded when all of it is buffered.
+#[derive(Default)]
+struct Decoder {
+    buffer: Vec<u8>,
+    // where the next value starts in the buffer
+    start: usize,
+    // how far the next value was scanned, and how many items are left at each depth
+    scanned: usize,
+    remaining: Vec<u64>,
+}
+
+impl Decoder {
+    /// Takes the next complete value, `None` if more data is needed
+    fn next_value(&mut self) -> Result<Option<Value>> {
+        let end = match self.scan()? {
+            Some(end) => end,
+            None => return Ok(None),
+        };
+        let mut reader = &self.buffer[self.start..end];
+        let value = rmpv::decode::read_value(&mut reader)
+            .map_err(|e| Error::InvalidRequestBody(e.to_string()))?;
+        self.start = end;
+        Ok(Some(value))
+    }
+
+    /// Bytes buffered which aren't part of a value taken yet
+    fn buffered(&self) -> usize {
+        self.buffer.len() - self.start
+    }
+
+    /// Drops the values already taken from the front of the buffer
+    fn compact(&mut self) {
+        self.buffer.drain(..self.start);
+        self.scanned -= self.start;
+        self.start = 0;
+    }
+
+    /// Finds the end of the next value, `None` if it isn't all buffered yet
+    fn scan(&mut self) -> Result<Option<usize>> {
+        if self.remaining.is_empty() {
+            self.scanned = self.start;
+            self.remaining.push(1);
+        }
+        loop {
+            let bytes = &self.buffer[self.scanned..];
+            let (len, items) = match item_len(bytes)? {
+                Some(item) if item.0 <= bytes.len() => item,
+                _ => return Ok(None),
+            };
+            self.scanned += len;
+            if let Some(left) = self.remaining.last_mut() {
+                *left -= 1;
+            }
+            if items > 0 {
+                self.remaining.push(items);
+            }
+            while self.remaining.last() == Some(&0) {
+                self.remaining.pop();
+            }
+            if self.remaining.is_empty() {
+                return Ok(Some(self.scanned));
+            }
+        }
+    }
+}
+
+/// The length of the msgpack item at the front of `bytes`, not counting the items an array
+/// or map holds, along with how many items it holds. `None` if the header is incomplete.
+fn item_len(bytes: &[u8]) -> Result<Option<(usize, u64)>> {
+    // reads the big endian length of `n` bytes following the marker
+    let length = |n: usize| -> Option<usize> {
+        let field = bytes.get(1..1 + n)?;
+        Some(field.iter().fold(0, |len, b| len << 8 | usize::from(*b)))
+    };
+    let marker = match bytes.first() {
+        Some(marker) => *marker,
+        None => return Ok(None),
+    };
+    let item = match marker {
+        0x00..=0x7f | 0xc0 | 0xc2 | 0xc3 | 0xe0..=0xff => Some((1, 0)),
+        0x80..=0x8f => Some((1, u64::from(marker & 0x0f) * 2)),
+        0x90..=0x9f => Some((1, u64::from(marker & 0x0f))),
+        0xa0..=0xbf => Some((1 + usize::from(marker & 0x1f), 0)),
+        // bin and str with a 1, 2 or 4 byte length
+        0xc4 | 0xd9 => length(1).map(|l| (2 + l, 0)),
+        0xc5 | 0xda => length(2).map(|l| (3 + l, 0)),
+        0xc6 | 0xdb => length(4).map(|l| (5 + l, 0)),
+        // ext with a length and then a type
+        0xc7 => length(1).map(|l| (3 + l, 0)),
+        0xc8 => length(2).map(|l| (4 + l, 0)),
+        0xc9 => length(4).map(|l| (6 + l, 0)),
+        0xca | 0xce | 0xd2 => Some((5, 0)),
+        0xcb | 0xcf | 0xd3 => Some((9, 0)),
+        0xcc | 0xd0 => Some((2, 0)),
+        0xcd | 0xd1 => Some((3, 0)),
+        // fixext, a type and then 1 to 16 bytes
+        0xd4 => Some((3, 0)),
+        0xd5 => Some((4, 0)),
+        0xd6 => Some((6, 0)),
+        0xd7 => Some((10, 0)),
+        0xd8 => Some((18, 0)),
+        0xdc => length(2).map(|n| (3, n as u64)),
+        0xdd => length(4).map(|n| (5, n as u64)),
+        0xde => length(2).map(|n| (3, n as u64 * 2)),
+        0xdf => length(4).map(|n| (5, n as u64 * 2)),
+        0xc1 => {
+            return Err(Error::InvalidRequestBody(
+                "reserved msgpack marker 0xc1".to_string(),
+            ))
+        }
+    };
+    Ok(item)
+}
+
+/// Reads the `[time, record]` entries of PackedForward mode, gunzipping them first if the
+/// option says they are compressed
+fn unpack(packed: &[u8], option: Option<&Value>) -> Result<Vec<Value>> {
+    let compressed = option
+        .and_then(|o| map_get(o, "compressed"))
+        .and_then(Value::as_str);
+
+    let mut decompressed = Vec::new();
+    let mut bytes = match compressed {
+        Some("gzip") => {
+            flate2::read::MultiGzDecoder::new(packed)
+                .take(MAX_BUFFERED as u64 + 1)
+                .read_to_end(&mut decompressed)?;
+            if decompressed.len() > MAX_BUFFERED {
+                return Err(Error::InvalidRequestBody(
+                    "compressed entries are too large".to_string(),
+                ));
+            }
+            &decompressed[..]
+        }
+        // clients may say outright that the entries aren't compressed
+        None | Some("text") => packed,
+        Some(other) => {
+            return Err(Error::UnsupportedContentEncoding(other.to_string()));
+        }
+    };
+
+    let mut entries = Vec::new();
+    while !bytes.is_empty() {
+        entries.push(
+            rmpv::decode::read_value(&mut bytes)
+                .map_err(|e| Error::InvalidRequestBody(e.to_string()))?,
+        );
+    }
+    Ok(entries)
+}
+
+fn map_get<'a>(map: &'a Value, key: &str) -> Option<&'a Value> {
+    map.as_map()?
+        .iter()
+        .find(|(k, _)| k.as_str() == Some(key))
+        .map(|(_, v)| v)
+}
+
+/// Times are either whole seconds or the `EventTime` extension of seconds and nanoseconds
+fn event_time(time: &Value) -> Option<chrono::DateTime<chrono::Utc>> {
+    match time {
+        Value::Integer(seconds) => chrono::Utc.timestamp_opt(seconds.as_i64()?, 0).single(),
+        Value::F64(seconds) => chrono::Utc
+            .timestamp_opt(seconds.trunc() as i64, (seconds.fract() * 1e9) as u32)
+            .single(),
+        Value::Ext(EVENT_TIME, bytes) if bytes.len() == 8 => {
+            let seconds = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            let nanos = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+            chrono::Utc.timestamp_opt(seconds.into(), nanos).single()
+        }
+        _ => None,
+    }
+}
+
+fn value_to_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.as_str().map(ToString::to_string).unwrap_or_default(),
+        Value::Binary(b) => String::from_utf8_lossy(b).into_owned(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn encode(value: &Value) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        rmpv::encode::write_value(&mut bytes, value).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_fluent_forward() {
+        let record = |message: &str| {
+            Value::Map(vec![
+                (Value::from("log"), Value::from(message)),
+                (Value::from("level"), Value::from("warn")),
+                (Value::from("hostname"), Value::from("node-1")),
+                (Value::from("pod"), Value::from("web-0")),
+            ])
+        };
+        let mut event_time = 1_700_000_000u32.to_be_bytes().to_vec();
+        event_time.extend_from_slice(&5u32.to_be_bytes());
+        let entry = |message| {
+            Value::Array(vec![
+                Value::Ext(EVENT_TIME, event_time.clone()),
+                record(message),
+            ])
+        };
+
+        let listener = FluentListener::new(()).with_tag_mapping(TagMapping {
+            app_part: Some(1),
+            ..Default::default()
+        });
+
+        // Message mode, with an ack requested
+        let (events, chunk) = listener
+            .events(Value::Array(vec![
+                Value::from("kube.checkout"),
+                Value::from(1_700_000_000),
+                record("first"),
+                Value::Map(vec![(Value::from("chunk"), Value::from("abc"))]),
+            ]))
+            .unwrap();
+        assert_eq!(chunk.as_deref(), Some("abc"));
+        let event = &events[0];
+        assert_eq!(event.host.to_string(), "node1");
+        assert_eq!(event.app.to_string(), "checkout");
+        assert_eq!(event.level, Level::Warn);
+        assert_eq!(event.data.message, "first");
+        assert_eq!(event.data.tags["pod"], "web-0");
+        assert_eq!(event.data.tags["fluent_tag"], "kube.checkout");
+
+        // Forward mode
+        let (events, chunk) = listener
+            .events(Value::Array(vec![
+                Value::from("kube.checkout"),
+                Value::Array(vec![entry("a"), entry("b")]),
+            ]))
+            .unwrap();
+        assert!(chunk.is_none());
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            events[1].timestamp.unwrap().timestamp_nanos_opt(),
+            Some(1_700_000_000_000_000_005)
+        );
+
+        // CompressedPackedForward mode
+        let mut packed = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        packed.write_all(&encode(&entry("c"))).unwrap();
+        packed.write_all(&encode(&entry("d"))).unwrap();
+        let (events, _) = listener
+            .events(Value::Array(vec![
+                Value::from("kube.checkout"),
+                Value::Binary(packed.finish().unwrap()),
+                Value::Map(vec![(Value::from("compressed"), Value::from("gzip"))]),
+            ]))
+            .unwrap();
+        let messages = events
+            .iter()
+            .map(|e| e.data.message.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(messages, vec!["c", "d"]);
+
+        // PackedForward mode, saying the entries are uncompressed
+        let (events, _) = listener
+            .events(Value::Array(vec![
+                Value::from("kube.checkout"),
+                Value::Binary(encode(&entry("e"))),
+                Value::Map(vec![(Value::from("compressed"), Value::from("text"))]),
+            ]))
+            .unwrap();
+        assert_eq!(events[0].data.message, "e");
+
+        // entries split across reads are only decoded once complete
+        let first = encode(&Value::Array(vec![Value::from("tag"), entry("f")]));
+        let second = encode(&Value::Array(vec![
+            Value::from("tag"),
+            Value::Array(vec![entry("g"), entry("h")]),
+            Value::Map(vec![(Value::from("size"), Value::from(u32::MAX))]),
+        ]));
+        let bytes = [first.clone(), second.clone()].concat();
+        let mut decoder = Decoder::default();
+        for byte in &bytes[..bytes.len() - 1] {
+            decoder.buffer.push(*byte);
+            if let Some(value) = decoder.next_value().unwrap() {
+                assert_eq!(encode(&value), first);
+                decoder.compact();
+            }
+        }
+        assert!(decoder.next_value().unwrap().is_none());
+        decoder.buffer.push(bytes[bytes.len() - 1]);
+        assert_eq!(encode(&decoder.next_value().unwrap().unwrap()), second);
+        assert_eq!(decoder.buffered(), 0);
+    }
+}
@@ -0,0 +1,335 @@
+use super::ingest::{self, Event};
+use super::*;
+use chrono::TimeZone;
+use std::{io::Read, time};
+use tokio::{io::AsyncBufReadExt, net};
+
+const MAX_DATAGRAM: usize = 65_535;
+
+const CHUNK_MAGIC: [u8; 2] = [0x1e, 0x0f];
+const MAX_CHUNKS: u8 = 128;
+// GELF says to discard messages whose chunks don't all arrive within five seconds
+const CHUNK_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+// a decompressed message may not be larger than this, to bound the memory used
+const MAX_MESSAGE: u64 = 8 * 1024 * 1024;
+
+/// Receives Graylog Extended Log Format messages, as sent by Docker's `gelf` logging driver.
+///
+/// Over UDP messages may be chunked and zlib or gzip compressed, over TCP each message is
+/// terminated by a null byte. The `host` becomes the [`Host`], the `_app` field (or the one
+/// set with [`GelfListener::with_app_field`]) the [`App`] and the syslog `level` the
+/// [`Level`]. The other additional fields are kept as tags without their leading underscore.
+#[derive(Clone)]
+pub struct GelfListener<S> {
+    storage: S,
+    default_host: Host,
+    default_app: App,
+    app_field: String,
+}
+
+impl<S> GelfListener<S> {
+    pub fn new(storage: S) -> GelfListener<S> {
+        GelfListener {
+            storage,
+            default_host: "unknown".parse().unwrap(),
+            default_app: "gelf".parse().unwrap(),
+            app_field: "_app".to_string(),
+        }
+    }
+
+    pub fn with_defaults(mut self, host: Host, app: App) -> GelfListener<S> {
+        self.default_host = host;
+        self.default_app = app;
+        self
+    }
+
+    /// The field the app is taken from, for example `_container_name` for Docker
+    pub fn with_app_field(mut self, field: impl Into<String>) -> GelfListener<S> {
+        self.app_field = field.into();
+        self
+    }
+
+    fn parse(&self, message: &[u8]) -> Result<Event> {
+        let fields: collections::BTreeMap<String, serde_json::Value> =
+            serde_json::from_slice(message)
+                .map_err(|e| Error::InvalidRequestBody(format!("invalid GELF message: {}", e)))?;
+
+        let string = |name: &str| fields.get(name).map(value_to_string);
+
+        let mut tags = collections::HashMap::new();
+        for (name, value) in &fields {
+            if let Some(tag) = name.strip_prefix('_') {
+                // `_id` is reserved by GELF, the app field is already used for the stream
+                if tag != "id" && *name != self.app_field {
+                    tags.insert(tag.to_string(), value_to_string(value));
+                }
+            }
+        }
+        if let Some(full_message) = string("full_message") {
+            tags.insert("full_message".to_string(), full_message);
+        }
+
+        Ok(Event {
+            host: ingest::host_or(string("host").as_deref(), &self.default_host),
+            app: ingest::app_or(string(&self.app_field).as_deref(), &self.default_app),
+            // GELF defaults to alert when no level is given
+            level: ingest::syslog_level(
+                fields
+                    .get("level")
+                    .and_then(serde_json::Value::as_u64)
+                    .unwrap_or(1)
+                    .min(7) as u8,
+            ),
+            timestamp: fields
+                .get("timestamp")
+                .and_then(serde_json::Value::as_f64)
+                .and_then(|seconds| {
+                    chrono::Utc
+                        .timestamp_opt(seconds.trunc() as i64, (seconds.fract() * 1e9) as u32)
+                        .single()
+                }),
+            data: ingest::log_data(string("short_message").unwrap_or_default(), tags),
+        })
+    }
+}
+
+impl<S> GelfListener<S>
+where
+    S: storage::Storage + 'static,
+{
+    /// Receives messages which are either whole or chunked across datagrams, until the socket fails
+    pub async fn run_udp(self, addr: impl net::ToSocketAddrs) -> Result<()> {
+        let socket = net::UdpSocket::bind(addr).await?;
+        let mut buffer = vec![0; MAX_DATAGRAM];
+        let mut chunks = Chunks::default();
+        loop {
+            let (len, peer) = socket.recv_from(&mut buffer).await?;
+            let datagram = &buffer[..len];
+
+            let message = if datagram.starts_with(&CHUNK_MAGIC) {
+                match chunks.add(datagram) {
+                    Ok(Some(message)) => message,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        log::warn!("Invalid GELF chunk from {}: {}", peer, e);
+                        continue;
+                    }
+                }
+            } else {
+                datagram.to_vec()
+            };
+
+            match decompress(&message) {
+                Ok(message) => self.receive(&message).await,
+                Err(e) => log::warn!("Invalid GELF message from {}: {}", peer, e),
+            }
+        }
+    }
+
+    /// Accepts connections sending null terminated, uncompressed messages
+    pub async fn run_tcp(self, addr: impl net::ToSocketAddrs) -> Result<()> {
+        let listener = net::TcpListener::bind(addr).await?;
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let listener = self.clone();
+            tokio::spawn(async move {
+                let mut reader = tokio::io::BufReader::new(stream);
+                loop {
+                    match read_message(&mut reader).await {
+                        Ok(Some(message)) => listener.receive(&message).await,
+                        Ok(None) => break,
+                        Err(e) => {
+                            log::warn!("GELF connection from {} failed: {}", peer, e);
+                            break;
+                        }
+                    }
+                }
+            });
+        }
+    }
+
+    async fn receive(&self, message: &[u8]) {
+        let message = match message.split_last() {
+            Some((0, message)) => message,
+            _ => message,
+        };
+        if message.iter().all(u8::is_ascii_whitespace) {
+            return;
+        }
+        let stored = match self.parse(message) {
+            Ok(event) => ingest::store(&self.storage, iter::once(event)).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = stored {
+            log::error!("Failed to store GELF message: {}", e);
+        }
+    }
+}
+
+/// Reads the next null terminated message, `None` once the connection is closed. Fails
+/// when no null byte comes within [`MAX_MESSAGE`] bytes, so the connection is dropped.
+async fn read_message<R>(reader: &mut R) -> Result<Option<Vec<u8>>>
+where
+    R: tokio::io::AsyncBufRead + Unpin,
+{
+    let mut message = Vec::new();
+    tokio::io::AsyncReadExt::take(&mut *reader, MAX_MESSAGE)
+        .read_until(b'\0', &mut message)
+        .await?;
+    if message.is_empty() {
+        return Ok(None);
+    }
+    if message.len() as u64 == MAX_MESSAGE && message.last() != Some(&0) {
+        return Err(Error::InvalidRequestBody(format!(
+            "GELF message is longer than {} bytes",
+            MAX_MESSAGE
+        )));
+    }
+    Ok(Some(message))
+}
+
+fn value_to_string(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Inflates zlib and gzip compressed messages, detected by their magic bytes
+fn decompress(message: &[u8]) -> Result<Vec<u8>> {
+    let mut decompressed = Vec::new();
+    match message {
+        [0x1f, 0x8b, ..] => flate2::read::GzDecoder::new(message)
+            .take(MAX_MESSAGE)
+            .read_to_end(&mut decompressed)?,
+        [0x78, ..] => flate2::read::ZlibDecoder::new(message)
+            .take(MAX_MESSAGE)
+            .read_to_end(&mut decompressed)?,
+        _ => return Ok(message.to_vec()),
+    };
+    Ok(decompressed)
+}
+
+/// Chunked messages which are still missing some of their chunks
+#[derive(Default)]
+struct Chunks {
+    pending: collections::HashMap<[u8; 8], Pending>,
+}
+
+struct Pending {
+    started: time::Instant,
+    chunks: Vec<Option<Vec<u8>>>,
+}
+
+impl Chunks {
+    /// `magic, message id (8 bytes), sequence number, sequence count, payload`.
+    /// Returns the whole message once its last chunk arrives.
+    fn add(&mut self, datagram: &[u8]) -> Result<Option<Vec<u8>>> {
+        let now = time::Instant::now();
+        self.pending
+            .retain(|_, p| now.saturating_duration_since(p.started) < CHUNK_TIMEOUT);
+
+        let invalid = |msg: &str| Error::InvalidRequestBody(msg.to_string());
+        if datagram.len() < 12 {
+            return Err(invalid("chunk is shorter than its header"));
+        }
+        let mut id = [0; 8];
+        id.copy_from_slice(&datagram[2..10]);
+        let (number, count) = (datagram[10], datagram[11]);
+        if count == 0 || count > MAX_CHUNKS || number >= count {
+            return Err(invalid("chunk has an invalid sequence number or count"));
+        }
+
+        let pending = self.pending.entry(id).or_insert_with(|| Pending {
+            started: now,
+            chunks: vec![None; count as usize],
+        });
+        if pending.chunks.len() != count as usize {
+            self.pending.remove(&id);
+            return Err(invalid("chunks of one message disagree on their count"));
+        }
+        pending.chunks[number as usize] = Some(datagram[12..].to_vec());
+
+        if pending.chunks.iter().all(Option::is_some) {
+            Ok(self
+                .pending
+                .remove(&id)
+                .map(|p| p.chunks.into_iter().flatten().flatten().collect()))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_gelf() {
+        let message = serde_json::json!({
+            "version": "1.1",
+            "host": "docker-host.example.org",
+            "short_message": "container started",
+            "timestamp": 1385053862.3072,
+            "level": 4,
+            "_container_name": "web",
+            "_image_name": "nginx",
+            "_id": "reserved",
+        })
+        .to_string();
+
+        // compress and split the message over three chunks, delivered out of order
+        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), Default::default());
+        encoder.write_all(message.as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let parts = compressed
+            .chunks(compressed.len() / 3 + 1)
+            .collect::<Vec<_>>();
+        let chunk = |i: usize| {
+            let mut datagram = CHUNK_MAGIC.to_vec();
+            datagram.extend_from_slice(b"messagid");
+            datagram.extend_from_slice(&[i as u8, parts.len() as u8]);
+            datagram.extend_from_slice(parts[i]);
+            datagram
+        };
+        let mut chunks = Chunks::default();
+        assert!(chunks.add(&chunk(2)).unwrap().is_none());
+        assert!(chunks.add(&chunk(0)).unwrap().is_none());
+        let reassembled = chunks.add(&chunk(1)).unwrap().unwrap();
+        assert_eq!(decompress(&reassembled).unwrap(), message.as_bytes());
+        assert!(chunks.pending.is_empty());
+
+        let listener = GelfListener::new(()).with_app_field("_container_name");
+        let event = listener.parse(message.as_bytes()).unwrap();
+        assert_eq!(event.host.to_string(), "dockerhostexampleorg");
+        assert_eq!(event.app.to_string(), "web");
+        assert_eq!(event.level, Level::Warn);
+        assert_eq!(event.data.message, "container started");
+        assert_eq!(event.data.tags["image_name"], "nginx");
+        assert!(!event.data.tags.contains_key("id"));
+        assert!(!event.data.tags.contains_key("container_name"));
+        assert_eq!(event.timestamp.unwrap().timestamp(), 1385053862);
+    }
+
+    #[tokio::test]
+    async fn test_read_message() {
+        let mut input: &[u8] = b"{\"a\":1}\0{\"b\":2}";
+        assert_eq!(
+            read_message(&mut input).await.unwrap().unwrap(),
+            b"{\"a\":1}\0"
+        );
+        assert_eq!(
+            read_message(&mut input).await.unwrap().unwrap(),
+            b"{\"b\":2}"
+        );
+        assert!(read_message(&mut input).await.unwrap().is_none());
+
+        // a message without a null byte is cut off rather than buffered forever
+        let long = vec![b'a'; MAX_MESSAGE as usize + 1];
+        assert!(read_message(&mut long.as_slice()).await.is_err());
+    }
+}
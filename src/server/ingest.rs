@@ -0,0 +1,130 @@
+//! Pieces shared by the receivers of other log protocols, which turn foreign events into
+//! the host, app and level streams the storage is organised by.
+
+use super::*;
+
+/// A log event received over another protocol
+pub(crate) struct Event {
+    pub host: Host,
+    pub app: App,
+    pub level: Level,
+    /// when the event happened, if the protocol says, otherwise when it was received
+    pub timestamp: Option<chrono::DateTime<chrono::Utc>>,
+    pub data: LogData,
+}
+
+/// Strips the characters a [`Host`] or [`App`] can't contain, for example the dots and dashes
+/// of domain names. `None` if nothing is left.
+pub(crate) fn sanitize(name: &str) -> Option<String> {
+    let name = name
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .collect::<String>();
+    if name.is_empty() {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+pub(crate) fn host_or(name: Option<&str>, default: &Host) -> Host {
+    name.and_then(sanitize)
+        .and_then(|n| n.parse().ok())
+        .unwrap_or_else(|| default.clone())
+}
+
+pub(crate) fn app_or(name: Option<&str>, default: &App) -> App {
+    name.and_then(sanitize)
+        .and_then(|n| n.parse().ok())
+        .unwrap_or_else(|| default.clone())
+}
+
+/// Maps a syslog severity (0 emergency to 7 debug) to a level
+pub(crate) fn syslog_level(severity: u8) -> Level {
+    match severity {
+        0..=3 => Level::Error,
+        4 => Level::Warn,
+        5 | 6 => Level::Info,
+        _ => Level::Debug,
+    }
+}
+
+pub(crate) fn log_data(message: String, tags: collections::HashMap<String, String>) -> LogData {
+    LogData {
+        message,
+        code_module: None,
+        code_line: None,
+        code_file: None,
+        tags,
+    }
+}
+
+/// Maps the common names of levels, as used in labels and fields, to a level
+pub(crate) fn level_from_name(name: &str) -> Option<Level> {
+    Some(match name.to_lowercase().as_str() {
+        "trace" => Level::Trace,
+        "debug" | "dbg" => Level::Debug,
+        "info" | "information" | "notice" => Level::Info,
+        "warn" | "warning" => Level::Warn,
+        "error" | "err" | "critical" | "crit" | "fatal" | "alert" | "emergency" | "emerg" => {
+            Level::Error
+        }
+        _ => return None,
+    })
+}
+
+/// Stores the events the API key may submit, after checking the rate limits of each of their
+/// streams. Returns the number of events that were rejected as the key didn't permit them.
+pub(crate) async fn submit<S>(
+    storage: &S,
+    key: &ApiKey,
+    limits: &RateLimiter,
+    events: Vec<Event>,
+    body_len: usize,
+) -> Result<usize>
+where
+    S: storage::Storage,
+{
+    let (permitted, rejected): (Vec<_>, Vec<_>) = events
+        .into_iter()
+        .partition(|e| key.permits(&e.host, &e.app));
+
+    // check every stream's limits before storing any, so a retry doesn't store rows twice
+    let mut rows = collections::BTreeMap::<_, usize>::new();
+    for event in &permitted {
+        *rows.entry((&event.host, &event.app)).or_default() += 1;
+    }
+    let total_rows = permitted.len().max(1);
+    for ((host, app), count) in rows {
+        limits.check(key, host, app, count, body_len * count / total_rows)?;
+    }
+
+    store(storage, permitted).await?;
+    Ok(rejected.len())
+}
+
+/// Stores the events grouped into one batch per stream, each event keyed by a ULID made
+/// from its timestamp
+pub(crate) async fn store<S>(storage: &S, events: impl IntoIterator<Item = Event>) -> Result<()>
+where
+    S: storage::Storage,
+{
+    let mut generator = ulid::Generator::new();
+    let mut streams = collections::BTreeMap::<_, LogBatch>::new();
+
+    for event in events {
+        let timestamp = event.timestamp.unwrap_or_else(chrono::Utc::now);
+        let id = generator
+            .generate_from_datetime(timestamp)
+            .unwrap_or_else(|_| ulid::Ulid::from_datetime(timestamp));
+        streams
+            .entry((event.host, event.app, event.level))
+            .or_default()
+            .insert(id, event.data);
+    }
+
+    for ((host, app, level), batch) in streams {
+        storage.submit(&host, &app, level, batch).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,250 @@
+use super::*;
+use sha2::Digest;
+use std::{fs, path, time};
+use subtle::ConstantTimeEq;
+
+/// An [`ApiKeyStore`] backed by a file of salted SHA-256 hashes, so that the
+/// keys themselves are never held by the server.
+///
+/// Each non-empty line that doesn't start with `#` describes one key:
+///
+/// ```text
+/// # id     role    salt (hex)  sha256(salt ++ key) (hex)  hosts     apps
+/// billing  submit  6f1c...     2b9a...                    *         billing,invoices
+/// ops      read    91ad...     c03e...                    web1,web2
+/// ```
+///
+/// `hosts` and `apps` are optional comma separated restrictions, where `*` or a
+/// missing column allows everything. Lines can be generated with [`KeyFile::entry`].
+///
+/// The file is checked for changes at most once per `reload_interval` and reloaded
+/// when its modification time changes. If the new contents fail to parse, the
+/// previously loaded keys are kept.
+pub struct KeyFile {
+    path: path::PathBuf,
+    reload_interval: time::Duration,
+    state: sync::RwLock<KeyFileState>,
+}
+
+struct KeyFileState {
+    keys: Vec<HashedKey>,
+    modified: Option<time::SystemTime>,
+    checked: time::Instant,
+}
+
+struct HashedKey {
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+    key: ApiKey,
+}
+
+impl KeyFile {
+    pub fn open(
+        path: impl Into<path::PathBuf>,
+        reload_interval: time::Duration,
+    ) -> Result<KeyFile> {
+        let path = path.into();
+        let modified = fs::metadata(&path)?.modified().ok();
+        let keys = parse_key_file(&fs::read_to_string(&path)?)?;
+
+        Ok(KeyFile {
+            path,
+            reload_interval,
+            state: sync::RwLock::new(KeyFileState {
+                keys,
+                modified,
+                checked: time::Instant::now(),
+            }),
+        })
+    }
+
+    /// Re-reads the file regardless of whether it has changed
+    pub async fn reload(&self) -> Result<()> {
+        let modified = tokio::fs::metadata(&self.path).await?.modified().ok();
+        let keys = parse_key_file(&tokio::fs::read_to_string(&self.path).await?)?;
+
+        let mut state = self.write();
+        state.keys = keys;
+        state.modified = modified;
+        state.checked = time::Instant::now();
+
+        Ok(())
+    }
+
+    /// Ids of the currently loaded keys
+    pub fn ids(&self) -> Vec<String> {
+        self.read()
+            .keys
+            .iter()
+            .filter_map(|k| k.key.id.clone())
+            .collect()
+    }
+
+    /// Creates a line for the key file, using a fresh random salt
+    pub fn entry(id: &str, role: Role, api_key: &str) -> String {
+        // the random component of a ulid is more than enough for a salt
+        let salt = u128::from(ulid::Ulid::new()).to_be_bytes();
+        format!(
+            "{id} {role} {} {}",
+            hex::encode(salt),
+            hex::encode(hash_api_key(&salt, api_key))
+        )
+    }
+
+    // a panic while holding the lock can't leave the keys inconsistent, so carry on
+    fn read(&self) -> sync::RwLockReadGuard<'_, KeyFileState> {
+        self.state
+            .read()
+            .unwrap_or_else(sync::PoisonError::into_inner)
+    }
+
+    fn write(&self) -> sync::RwLockWriteGuard<'_, KeyFileState> {
+        self.state
+            .write()
+            .unwrap_or_else(sync::PoisonError::into_inner)
+    }
+
+    async fn reload_if_changed(&self) {
+        if self.read().checked.elapsed() < self.reload_interval {
+            return;
+        }
+
+        let modified = tokio::fs::metadata(&self.path)
+            .await
+            .and_then(|m| m.modified())
+            .ok();
+        let changed = {
+            let mut state = self.write();
+            state.checked = time::Instant::now();
+            modified != state.modified
+        };
+
+        if changed {
+            if let Err(e) = self.reload().await {
+                log::error!(
+                    "Keeping previous API keys, failed to reload {}: {}",
+                    self.path.display(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl ApiKeyStore for KeyFile {
+    async fn get(&self, api_key: &str) -> Option<ApiKey> {
+        self.reload_if_changed().await;
+
+        self.read()
+            .keys
+            .iter()
+            .find(|k| bool::from(hash_api_key(&k.salt, api_key).ct_eq(&k.hash)))
+            .map(|k| k.key.clone())
+    }
+}
+
+fn hash_api_key(salt: &[u8], api_key: &str) -> Vec<u8> {
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(salt);
+    hasher.update(api_key.as_bytes());
+    hasher.finalize().to_vec()
+}
+
+fn parse_key_file(contents: &str) -> Result<Vec<HashedKey>> {
+    contents
+        .lines()
+        .enumerate()
+        .map(|(n, l)| (n + 1, l.trim()))
+        .filter(|(_, l)| !l.is_empty() && !l.starts_with('#'))
+        .map(|(n, l)| parse_key_line(l).map_err(|e| Error::ParseKeyFile(format!("line {n}: {e}"))))
+        .collect()
+}
+
+fn parse_key_line(line: &str) -> result::Result<HashedKey, String> {
+    let mut columns = line.split_whitespace();
+    let mut next = |name: &str| columns.next().ok_or(format!("missing `{name}` column"));
+
+    let id = next("id")?.to_string();
+    let role = next("role")?.parse()?;
+    let salt = hex::decode(next("salt")?).map_err(|e| format!("salt: {e}"))?;
+    let hash = hex::decode(next("hash")?).map_err(|e| format!("hash: {e}"))?;
+    let hosts = parse_restriction(columns.next())?;
+    let apps = parse_restriction(columns.next())?;
+
+    Ok(HashedKey {
+        salt,
+        hash,
+        key: ApiKey {
+            id: Some(id),
+            role,
+            hosts,
+            apps,
+        },
+    })
+}
+
+fn parse_restriction<T>(
+    column: Option<&str>,
+) -> result::Result<Option<collections::BTreeSet<T>>, String>
+where
+    T: str::FromStr + Ord,
+    T::Err: fmt::Display,
+{
+    match column {
+        None | Some("*") => Ok(None),
+        Some(list) => list
+            .split(',')
+            .map(|s| s.parse().map_err(|e: T::Err| e.to_string()))
+            .collect::<result::Result<_, _>>()
+            .map(Some),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[tokio::test]
+    async fn test_key_file() {
+        let path = std::env::temp_dir().join(format!("eigenlog-keys-{}", ulid::Ulid::new()));
+
+        fs::write(
+            &path,
+            format!(
+                "# comment\n{} * web\n",
+                KeyFile::entry("first", Role::Submit, "abc")
+            ),
+        )
+        .unwrap();
+
+        let keys = KeyFile::open(&path, time::Duration::ZERO).unwrap();
+        let key = keys.get("abc").await.unwrap();
+        assert_eq!(key.id.as_deref(), Some("first"));
+        assert_eq!(key.role, Role::Submit);
+        assert!(key.hosts.is_none());
+        assert_eq!(key.apps.unwrap().len(), 1);
+        assert!(keys.get("abcd").await.is_none());
+
+        // picked up by `get` once the modification time changes
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        writeln!(file, "{}", KeyFile::entry("second", Role::Read, "def")).unwrap();
+        file.set_modified(time::SystemTime::now() + time::Duration::from_secs(10))
+            .unwrap();
+        drop(file);
+
+        assert_eq!(keys.get("def").await.unwrap().role, Role::Read);
+        assert_eq!(keys.ids(), vec!["first".to_string(), "second".to_string()]);
+
+        // a file which no longer parses leaves the previous keys in place
+        fs::write(&path, "broken\n").unwrap();
+        let file = fs::File::options().append(true).open(&path).unwrap();
+        file.set_modified(time::SystemTime::now() + time::Duration::from_secs(20))
+            .unwrap();
+        drop(file);
+        assert!(keys.get("def").await.is_some());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
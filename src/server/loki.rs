@@ -0,0 +1,363 @@
+use super::ingest::{self, Event};
+use super::*;
+use chrono::TimeZone;
+
+const PROTOBUF: &str = "application/x-protobuf";
+const JSON: &str = "application/json";
+
+// labels checked in order for each part of a stream, the first one present wins
+const HOST_LABELS: &[&str] = &["host", "hostname", "instance"];
+const APP_LABELS: &[&str] = &["app", "service_name", "job"];
+const LEVEL_LABELS: &[&str] = &["level", "detected_level", "severity"];
+
+/// The messages of Loki's `logproto` used by the push API
+mod messages {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PushRequest {
+        #[prost(message, repeated, tag = "1")]
+        pub streams: Vec<Stream>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Stream {
+        /// in the Prometheus format, `{name="value", ...}`
+        #[prost(string, tag = "1")]
+        pub labels: String,
+        #[prost(message, repeated, tag = "2")]
+        pub entries: Vec<Entry>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Entry {
+        #[prost(message, optional, tag = "1")]
+        pub timestamp: Option<Timestamp>,
+        #[prost(string, tag = "2")]
+        pub line: String,
+        #[prost(message, repeated, tag = "3")]
+        pub structured_metadata: Vec<LabelPair>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Timestamp {
+        #[prost(int64, tag = "1")]
+        pub seconds: i64,
+        #[prost(int32, tag = "2")]
+        pub nanos: i32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct LabelPair {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(string, tag = "2")]
+        pub value: String,
+    }
+}
+
+use messages::*;
+
+/// Receives logs at `/loki/api/v1/push` the way Loki does, so Promtail, Vector and the Grafana
+/// agent can ship to eigenlog. Bodies are either snappy compressed protobuf or JSON.
+///
+/// The `host`, `app` and `level` labels (or their common alternatives) pick the stream each
+/// entry is stored in, the remaining labels and any structured metadata become tags. Entry
+/// timestamps are used for the ULIDs.
+pub fn create_loki_endpoint<S, K>(
+    storage: S,
+    api_keys: sync::Arc<K>,
+    limits: RateLimiter,
+) -> impl warp::Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone
+where
+    S: storage::Storage,
+    K: ApiKeyStore + ?Sized + 'static,
+{
+    warp::path("loki")
+        .and(warp::path("api"))
+        .and(warp::path("v1"))
+        .and(warp::path("push"))
+        .and(warp::post())
+        .and(warp::path::end())
+        .and(warp::header(API_KEY_HEADER))
+        .and(warp::header(header::CONTENT_TYPE.as_str()))
+        .and(warp::header::optional(header::CONTENT_ENCODING.as_str()))
+        .and(warp::body::bytes())
+        .and(add(storage))
+        .and(add(api_keys))
+        .and(add(limits))
+        .and_then(
+            |key, content_type, encoding, body, db, keys, limits| async move {
+                let reply = push(key, content_type, encoding, body, db, keys, limits).await;
+                Ok::<_, convert::Infallible>(reply.unwrap_or_else(|e| {
+                    warp::Reply::into_response(e.into_reply::<()>(Default::default()))
+                }))
+            },
+        )
+}
+
+// this is ok as it is an internal function
+#[allow(clippy::too_many_arguments)]
+async fn push<S, K>(
+    api_key: String,
+    content_type: String,
+    content_encoding: Option<String>,
+    bytes: body::Bytes,
+    storage: S,
+    api_keys: sync::Arc<K>,
+    limits: RateLimiter,
+) -> Result<warp::reply::Response>
+where
+    S: storage::Storage,
+    K: ApiKeyStore + ?Sized + 'static,
+{
+    let key = auth::authorize(&*api_keys, api_key, Role::Submit).await?;
+
+    let media_type = content_type.split(';').next().unwrap_or_default().trim();
+    let (request, body_len) = match media_type {
+        // the protobuf body is always snappy compressed, regardless of `Content-Encoding`
+        PROTOBUF => {
+            let bytes = decompress_snappy(&bytes)?;
+            let request = prost::Message::decode(&bytes[..])
+                .map_err(|e| Error::InvalidRequestBody(e.to_string()))?;
+            (request, bytes.len())
+        }
+        JSON => {
+            let bytes = decompress_body(content_encoding, &bytes)?;
+            (from_json(&bytes)?, bytes.len())
+        }
+        _ => return Err(Error::UnsupportedSerializationMimeType(content_type)),
+    };
+
+    let events = events(request)?;
+
+    // Loki has no partial success, so the whole push is refused if any stream isn't allowed
+    for event in &events {
+        auth::authorize_tree(&key, &event.host, &event.app)?;
+    }
+
+    ingest::submit(&storage, &key, &limits, events, body_len).await?;
+
+    Ok(into_response(
+        http::StatusCode::NO_CONTENT,
+        header::HeaderMap::new(),
+        Vec::new(),
+    ))
+}
+
+/// Undoes the raw snappy compression of a protobuf push, checking the length it claims
+/// before allocating for it
+fn decompress_snappy(bytes: &[u8]) -> Result<Vec<u8>> {
+    let len =
+        snap::raw::decompress_len(bytes).map_err(|e| Error::InvalidRequestBody(e.to_string()))?;
+    if len as u64 > MAX_DECOMPRESSED {
+        return Err(Error::InvalidRequestBody(format!(
+            "Decompressed body is larger than {} bytes",
+            MAX_DECOMPRESSED
+        )));
+    }
+    snap::raw::Decoder::new()
+        .decompress_vec(bytes)
+        .map_err(|e| Error::InvalidRequestBody(e.to_string()))
+}
+
+fn events(request: PushRequest) -> Result<Vec<Event>> {
+    let unknown_host = "unknown".parse().unwrap();
+    let unknown_app = "unknown".parse().unwrap();
+
+    let mut events = Vec::new();
+    for stream in request.streams {
+        let mut labels = parse_labels(&stream.labels)?;
+        let mut take = |names: &[&str]| names.iter().find_map(|name| labels.remove(*name));
+
+        let host = ingest::host_or(take(HOST_LABELS).as_deref(), &unknown_host);
+        let app = ingest::app_or(take(APP_LABELS).as_deref(), &unknown_app);
+        let level = take(LEVEL_LABELS)
+            .and_then(|l| ingest::level_from_name(&l))
+            .unwrap_or(Level::Info);
+
+        for entry in stream.entries {
+            let mut tags = labels.clone();
+            tags.extend(
+                entry
+                    .structured_metadata
+                    .into_iter()
+                    .map(|pair| (pair.name, pair.value)),
+            );
+            events.push(Event {
+                host: host.clone(),
+                app: app.clone(),
+                level: level.clone(),
+                timestamp: entry.timestamp.and_then(|t| {
+                    chrono::Utc
+                        .timestamp_opt(t.seconds, t.nanos.max(0) as u32)
+                        .single()
+                }),
+                data: ingest::log_data(entry.line, tags),
+            });
+        }
+    }
+    Ok(events)
+}
+
+/// Parses `{name="value", other="with \"quotes\""}`
+fn parse_labels(labels: &str) -> Result<collections::HashMap<String, String>> {
+    let invalid = || Error::InvalidRequestBody(format!("invalid stream labels `{}`", labels));
+
+    let mut rest = labels
+        .trim()
+        .strip_prefix('{')
+        .and_then(|l| l.strip_suffix('}'))
+        .ok_or_else(invalid)?;
+
+    let mut parsed = collections::HashMap::new();
+    loop {
+        rest = rest.trim_start_matches([' ', ',']);
+        if rest.is_empty() {
+            return Ok(parsed);
+        }
+        let (name, value) = rest.split_once('=').ok_or_else(invalid)?;
+        let value = value.trim_start().strip_prefix('"').ok_or_else(invalid)?;
+
+        let mut unescaped = String::new();
+        let mut chars = value.char_indices();
+        let end = loop {
+            match chars.next().ok_or_else(invalid)? {
+                (_, '\\') => match chars.next().ok_or_else(invalid)?.1 {
+                    'n' => unescaped.push('\n'),
+                    c => unescaped.push(c),
+                },
+                (i, '"') => break i + 1,
+                (_, c) => unescaped.push(c),
+            }
+        };
+
+        parsed.insert(name.trim().to_string(), unescaped);
+        rest = &value[end..];
+    }
+}
+
+/// Reads `{"streams": [{"stream": {labels}, "values": [["<unix nanos>", "line", {metadata}]]}]}`
+fn from_json(bytes: &[u8]) -> Result<PushRequest> {
+    #[derive(serde::Deserialize)]
+    struct Push {
+        streams: Vec<JsonStream>,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct JsonStream {
+        #[serde(default)]
+        stream: collections::BTreeMap<String, String>,
+        values: Vec<JsonEntry>,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct JsonEntry(
+        String,
+        String,
+        #[serde(default)] collections::BTreeMap<String, String>,
+    );
+
+    let push: Push =
+        serde_json::from_slice(bytes).map_err(|e| Error::InvalidRequestBody(e.to_string()))?;
+
+    push.streams
+        .into_iter()
+        .map(|stream| {
+            let labels = stream
+                .stream
+                .iter()
+                .map(|(name, value)| {
+                    let value = value
+                        .replace('\\', "\\\\")
+                        .replace('"', "\\\"")
+                        .replace('\n', "\\n");
+                    format!("{}=\"{}\"", name, value)
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            let entries = stream
+                .values
+                .into_iter()
+                .map(|JsonEntry(nanos, line, metadata)| {
+                    let nanos = nanos.parse::<i64>().map_err(|e| {
+                        Error::InvalidRequestBody(format!("invalid timestamp `{}`: {}", nanos, e))
+                    })?;
+                    Ok(Entry {
+                        timestamp: Some(Timestamp {
+                            seconds: nanos.div_euclid(1_000_000_000),
+                            nanos: nanos.rem_euclid(1_000_000_000) as i32,
+                        }),
+                        line,
+                        structured_metadata: metadata
+                            .into_iter()
+                            .map(|(name, value)| LabelPair { name, value })
+                            .collect(),
+                    })
+                })
+                .collect::<Result<_>>()?;
+            Ok(Stream {
+                labels: format!("{{{}}}", labels),
+                entries,
+            })
+        })
+        .collect::<Result<_>>()
+        .map(|streams| PushRequest { streams })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_loki_events() {
+        let labels = parse_labels(r#"{job="api", host="web-1", msg="say \"hi\"",}"#).unwrap();
+        assert_eq!(labels["job"], "api");
+        assert_eq!(labels["msg"], "say \"hi\"");
+        assert!(parse_labels(r#"{job="api}"#).is_err());
+
+        let json = serde_json::json!({
+            "streams": [{
+                "stream": {"host": "web-1", "job": "api", "level": "warning", "env": "prod"},
+                "values": [
+                    ["1700000000000000001", "first"],
+                    ["1700000000500000000", "second", {"trace_id": "abc"}]
+                ]
+            }]
+        });
+        let request = from_json(json.to_string().as_bytes()).unwrap();
+
+        // the snappy protobuf body decodes to the same request
+        let encoded = snap::raw::Encoder::new()
+            .compress_vec(&prost::Message::encode_to_vec(&request))
+            .unwrap();
+        let decoded = decompress_snappy(&encoded).unwrap();
+        assert_eq!(
+            <PushRequest as prost::Message>::decode(&decoded[..]).unwrap(),
+            request
+        );
+
+        // a header claiming more than the server decompresses is refused before allocating
+        let mut huge = Vec::new();
+        let mut len = MAX_DECOMPRESSED + 1;
+        while len >= 0x80 {
+            huge.push(len as u8 | 0x80);
+            len >>= 7;
+        }
+        huge.push(len as u8);
+        let error = decompress_snappy(&huge).unwrap_err().to_string();
+        assert!(error.contains("larger than"), "{}", error);
+
+        let events = events(request).unwrap();
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|e| e.host.to_string() == "web1"
+            && e.app.to_string() == "api"
+            && e.level == Level::Warn
+            && e.data.tags["env"] == "prod"
+            && !e.data.tags.contains_key("job")));
+        assert_eq!(events[0].data.message, "first");
+        assert_eq!(
+            events[0].timestamp.unwrap().timestamp_nanos_opt(),
+            Some(1700000000000000001)
+        );
+        assert_eq!(events[1].data.tags["trace_id"], "abc");
+    }
+}
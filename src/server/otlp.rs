@@ -0,0 +1,546 @@
+use super::ingest::{self, Event};
+use super::*;
+use chrono::TimeZone;
+
+const PROTOBUF: &str = "application/x-protobuf";
+const PROTOBUF_ALIAS: &str = "application/protobuf";
+const JSON: &str = "application/json";
+
+const HOST_NAME: &str = "host.name";
+const SERVICE_NAME: &str = "service.name";
+const CODE_FUNCTION: &str = "code.function";
+const CODE_FILEPATH: &str = "code.filepath";
+const CODE_LINENO: &str = "code.lineno";
+
+/// The parts of `opentelemetry/proto/collector/logs/v1/logs_service.proto` and the
+/// messages it uses which are needed to receive logs
+mod messages {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ExportLogsServiceRequest {
+        #[prost(message, repeated, tag = "1")]
+        pub resource_logs: Vec<ResourceLogs>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ExportLogsServiceResponse {
+        #[prost(message, optional, tag = "1")]
+        pub partial_success: Option<ExportLogsPartialSuccess>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ExportLogsPartialSuccess {
+        #[prost(int64, tag = "1")]
+        pub rejected_log_records: i64,
+        #[prost(string, tag = "2")]
+        pub error_message: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ResourceLogs {
+        #[prost(message, optional, tag = "1")]
+        pub resource: Option<Resource>,
+        #[prost(message, repeated, tag = "2")]
+        pub scope_logs: Vec<ScopeLogs>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Resource {
+        #[prost(message, repeated, tag = "1")]
+        pub attributes: Vec<KeyValue>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ScopeLogs {
+        #[prost(message, optional, tag = "1")]
+        pub scope: Option<InstrumentationScope>,
+        #[prost(message, repeated, tag = "2")]
+        pub log_records: Vec<LogRecord>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct InstrumentationScope {
+        #[prost(string, tag = "1")]
+        pub name: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct LogRecord {
+        #[prost(fixed64, tag = "1")]
+        pub time_unix_nano: u64,
+        #[prost(fixed64, tag = "11")]
+        pub observed_time_unix_nano: u64,
+        #[prost(int32, tag = "2")]
+        pub severity_number: i32,
+        #[prost(string, tag = "3")]
+        pub severity_text: String,
+        #[prost(message, optional, tag = "5")]
+        pub body: Option<AnyValue>,
+        #[prost(message, repeated, tag = "6")]
+        pub attributes: Vec<KeyValue>,
+        #[prost(bytes = "vec", tag = "9")]
+        pub trace_id: Vec<u8>,
+        #[prost(bytes = "vec", tag = "10")]
+        pub span_id: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct KeyValue {
+        #[prost(string, tag = "1")]
+        pub key: String,
+        #[prost(message, optional, tag = "2")]
+        pub value: Option<AnyValue>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct AnyValue {
+        #[prost(oneof = "Value", tags = "1, 2, 3, 4, 5, 6, 7")]
+        pub value: Option<Value>,
+    }
+
+    // the variants are named after the fields of the oneof
+    #[allow(clippy::enum_variant_names)]
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Value {
+        #[prost(string, tag = "1")]
+        StringValue(String),
+        #[prost(bool, tag = "2")]
+        BoolValue(bool),
+        #[prost(int64, tag = "3")]
+        IntValue(i64),
+        #[prost(double, tag = "4")]
+        DoubleValue(f64),
+        #[prost(message, tag = "5")]
+        ArrayValue(ArrayValue),
+        #[prost(message, tag = "6")]
+        KvlistValue(KeyValueList),
+        #[prost(bytes, tag = "7")]
+        BytesValue(Vec<u8>),
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ArrayValue {
+        #[prost(message, repeated, tag = "1")]
+        pub values: Vec<AnyValue>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct KeyValueList {
+        #[prost(message, repeated, tag = "1")]
+        pub values: Vec<KeyValue>,
+    }
+}
+
+use messages::*;
+
+/// Receives logs from OpenTelemetry exporters and collectors over OTLP/HTTP at `/v1/logs`,
+/// encoded as either protobuf or JSON.
+///
+/// The `host.name` and `service.name` resource attributes become the [`Host`] and [`App`],
+/// the severity number the [`Level`] and the log attributes the tags. Trace and span ids
+/// are kept in the `trace_id` and `span_id` tags, as hex, and bytes attributes are hex too
+/// whichever encoding they were sent in.
+pub fn create_otlp_endpoint<S, K>(
+    storage: S,
+    api_keys: sync::Arc<K>,
+    limits: RateLimiter,
+) -> impl warp::Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone
+where
+    S: storage::Storage,
+    K: ApiKeyStore + ?Sized + 'static,
+{
+    warp::path("v1")
+        .and(warp::path("logs"))
+        .and(warp::post())
+        .and(warp::path::end())
+        .and(warp::header(API_KEY_HEADER))
+        .and(warp::header(header::CONTENT_TYPE.as_str()))
+        .and(warp::header::optional(header::CONTENT_ENCODING.as_str()))
+        .and(warp::body::bytes())
+        .and(add(storage))
+        .and(add(api_keys))
+        .and(add(limits))
+        .and_then(
+            |key, content_type, encoding, body, db, keys, limits| async move {
+                let reply = export(key, content_type, encoding, body, db, keys, limits).await;
+                Ok::<_, convert::Infallible>(reply.unwrap_or_else(|e| {
+                    warp::Reply::into_response(e.into_reply::<()>(Default::default()))
+                }))
+            },
+        )
+}
+
+// this is ok as it is an internal function
+#[allow(clippy::too_many_arguments)]
+async fn export<S, K>(
+    api_key: String,
+    content_type: String,
+    content_encoding: Option<String>,
+    bytes: body::Bytes,
+    storage: S,
+    api_keys: sync::Arc<K>,
+    limits: RateLimiter,
+) -> Result<warp::reply::Response>
+where
+    S: storage::Storage,
+    K: ApiKeyStore + ?Sized + 'static,
+{
+    let key = auth::authorize(&*api_keys, api_key, Role::Submit).await?;
+
+    let media_type = content_type.split(';').next().unwrap_or_default().trim();
+    let json = match media_type {
+        PROTOBUF | PROTOBUF_ALIAS => false,
+        JSON => true,
+        _ => return Err(Error::UnsupportedSerializationMimeType(content_type)),
+    };
+
+    let bytes = decompress_body(content_encoding, &bytes)?;
+    let request = if json {
+        from_json(&bytes)?
+    } else {
+        prost::Message::decode(&bytes[..]).map_err(|e| Error::InvalidRequestBody(e.to_string()))?
+    };
+
+    let rejected = ingest::submit(&storage, &key, &limits, events(request), bytes.len()).await?;
+
+    let response = ExportLogsServiceResponse {
+        partial_success: (rejected > 0).then(|| ExportLogsPartialSuccess {
+            rejected_log_records: rejected as i64,
+            error_message:
+                "the API key is not permitted to submit logs for some of the hosts and apps"
+                    .to_string(),
+        }),
+    };
+
+    let (media_type, body) = if json {
+        (JSON, to_json(&response))
+    } else {
+        (PROTOBUF, prost::Message::encode_to_vec(&response))
+    };
+    let mut headers = header::HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static(media_type),
+    );
+    Ok(into_response(http::StatusCode::OK, headers, body))
+}
+
+fn events(request: ExportLogsServiceRequest) -> Vec<Event> {
+    let unknown_host = "unknown".parse().unwrap();
+    let unknown_app = "unknown".parse().unwrap();
+
+    let mut events = Vec::new();
+    for resource_logs in request.resource_logs {
+        let resource = resource_logs.resource.unwrap_or_default();
+        let attribute = |name| {
+            resource
+                .attributes
+                .iter()
+                .find(|kv| kv.key == name)
+                .map(|kv| any_value_to_string(kv.value.as_ref()))
+        };
+        let host = ingest::host_or(attribute(HOST_NAME).as_deref(), &unknown_host);
+        let app = ingest::app_or(attribute(SERVICE_NAME).as_deref(), &unknown_app);
+
+        for scope_logs in resource_logs.scope_logs {
+            let scope = scope_logs.scope.map(|s| s.name).filter(|n| !n.is_empty());
+
+            for record in scope_logs.log_records {
+                let mut data = ingest::log_data(
+                    any_value_to_string(record.body.as_ref()),
+                    Default::default(),
+                );
+                for kv in record.attributes {
+                    let value = any_value_to_string(kv.value.as_ref());
+                    match kv.key.as_str() {
+                        CODE_FUNCTION => data.code_module = Some(value),
+                        CODE_FILEPATH => data.code_file = Some(value),
+                        CODE_LINENO => data.code_line = value.parse().ok(),
+                        _ => {
+                            data.tags.insert(kv.key, value);
+                        }
+                    }
+                }
+                if let Some(scope) = &scope {
+                    data.tags.insert("scope".to_string(), scope.clone());
+                }
+                if !record.trace_id.is_empty() {
+                    data.tags
+                        .insert("trace_id".to_string(), hex::encode(&record.trace_id));
+                }
+                if !record.span_id.is_empty() {
+                    data.tags
+                        .insert("span_id".to_string(), hex::encode(&record.span_id));
+                }
+
+                let nanos = match record.time_unix_nano {
+                    0 => record.observed_time_unix_nano,
+                    nanos => nanos,
+                };
+
+                events.push(Event {
+                    host: host.clone(),
+                    app: app.clone(),
+                    level: level(record.severity_number, &record.severity_text),
+                    timestamp: (nanos != 0)
+                        .then(|| chrono::Utc.timestamp_nanos(nanos.min(i64::MAX as u64) as i64)),
+                    data,
+                });
+            }
+        }
+    }
+    events
+}
+
+/// Severity numbers come in groups of four per level, with 0 meaning it wasn't set
+fn level(severity_number: i32, severity_text: &str) -> Level {
+    match severity_number {
+        1..=4 => Level::Trace,
+        5..=8 => Level::Debug,
+        9..=12 => Level::Info,
+        13..=16 => Level::Warn,
+        17..=24 => Level::Error,
+        _ => severity_text.parse().unwrap_or(Level::Info),
+    }
+}
+
+fn any_value_to_string(value: Option<&AnyValue>) -> String {
+    match value.and_then(|v| v.value.as_ref()) {
+        Some(Value::StringValue(s)) => s.clone(),
+        Some(Value::BytesValue(b)) => hex::encode(b),
+        _ => any_value_to_json(value).to_string(),
+    }
+}
+
+fn any_value_to_json(value: Option<&AnyValue>) -> serde_json::Value {
+    match value.and_then(|v| v.value.as_ref()) {
+        None => serde_json::Value::Null,
+        Some(Value::StringValue(s)) => s.clone().into(),
+        Some(Value::BoolValue(b)) => (*b).into(),
+        Some(Value::IntValue(i)) => (*i).into(),
+        Some(Value::DoubleValue(d)) => (*d).into(),
+        Some(Value::BytesValue(b)) => hex::encode(b).into(),
+        Some(Value::ArrayValue(a)) => a.values.iter().map(Some).map(any_value_to_json).collect(),
+        Some(Value::KvlistValue(kvs)) => kvs
+            .values
+            .iter()
+            .map(|kv| (kv.key.clone(), any_value_to_json(kv.value.as_ref())))
+            .collect::<serde_json::Map<_, _>>()
+            .into(),
+    }
+}
+
+fn to_json(response: &ExportLogsServiceResponse) -> Vec<u8> {
+    let json = match &response.partial_success {
+        Some(partial) => serde_json::json!({
+            "partialSuccess": {
+                // int64 fields are encoded as strings in the OTLP JSON mapping
+                "rejectedLogRecords": partial.rejected_log_records.to_string(),
+                "errorMessage": partial.error_message,
+            }
+        }),
+        None => serde_json::json!({}),
+    };
+    json.to_string().into_bytes()
+}
+
+/// Reads the OTLP JSON encoding, which names fields in lowerCamelCase, writes 64 bit integers
+/// as strings and trace and span ids as hex.
+fn from_json(bytes: &[u8]) -> Result<ExportLogsServiceRequest> {
+    use serde_json::Value as Json;
+
+    fn field<'a>(json: &'a Json, name: &str) -> Option<&'a Json> {
+        json.get(name)
+    }
+    fn array<'a>(json: &'a Json, name: &str) -> impl Iterator<Item = &'a Json> {
+        field(json, name)
+            .and_then(Json::as_array)
+            .into_iter()
+            .flatten()
+    }
+    fn string(json: &Json, name: &str) -> String {
+        field(json, name)
+            .and_then(Json::as_str)
+            .unwrap_or_default()
+            .to_string()
+    }
+    fn int(json: &Json) -> Option<i64> {
+        json.as_i64().or_else(|| json.as_str()?.parse().ok())
+    }
+    fn key_values(json: &Json, name: &str) -> Vec<KeyValue> {
+        array(json, name)
+            .map(|kv| KeyValue {
+                key: string(kv, "key"),
+                value: field(kv, "value").map(any_value),
+            })
+            .collect()
+    }
+    fn any_value(json: &Json) -> AnyValue {
+        let value = if let Some(s) = field(json, "stringValue").and_then(Json::as_str) {
+            Some(Value::StringValue(s.to_string()))
+        } else if let Some(b) = field(json, "boolValue").and_then(Json::as_bool) {
+            Some(Value::BoolValue(b))
+        } else if let Some(i) = field(json, "intValue").and_then(int) {
+            Some(Value::IntValue(i))
+        } else if let Some(d) = field(json, "doubleValue").and_then(Json::as_f64) {
+            Some(Value::DoubleValue(d))
+        } else if let Some(a) = field(json, "arrayValue") {
+            Some(Value::ArrayValue(ArrayValue {
+                values: array(a, "values").map(any_value).collect(),
+            }))
+        } else if let Some(kvs) = field(json, "kvlistValue") {
+            Some(Value::KvlistValue(KeyValueList {
+                values: key_values(kvs, "values"),
+            }))
+        } else {
+            // bytes are base64 in JSON, decoded so they end up as hex like protobuf bytes
+            field(json, "bytesValue")
+                .and_then(Json::as_str)
+                .and_then(|b| {
+                    base64::Engine::decode(&base64::engine::general_purpose::STANDARD, b).ok()
+                })
+                .map(Value::BytesValue)
+        };
+        AnyValue { value }
+    }
+    fn severity_number(json: Option<&Json>) -> i32 {
+        let json = match json {
+            Some(json) => json,
+            None => return 0,
+        };
+        if let Some(number) = int(json) {
+            return number as i32;
+        }
+        // enums may also be written by name, such as `SEVERITY_NUMBER_WARN2`
+        let name = json
+            .as_str()
+            .and_then(|n| n.strip_prefix("SEVERITY_NUMBER_"))
+            .unwrap_or_default();
+        let base = name.trim_end_matches(|c: char| c.is_ascii_digit());
+        let offset = name[base.len()..].parse::<i32>().map_or(0, |n| n - 1);
+        let first = match base {
+            "TRACE" => 1,
+            "DEBUG" => 5,
+            "INFO" => 9,
+            "WARN" => 13,
+            "ERROR" => 17,
+            "FATAL" => 21,
+            _ => return 0,
+        };
+        first + offset
+    }
+
+    let json: Json =
+        serde_json::from_slice(bytes).map_err(|e| Error::InvalidRequestBody(e.to_string()))?;
+
+    let resource_logs = array(&json, "resourceLogs")
+        .map(|rl| ResourceLogs {
+            resource: field(rl, "resource").map(|r| Resource {
+                attributes: key_values(r, "attributes"),
+            }),
+            scope_logs: array(rl, "scopeLogs")
+                .map(|sl| ScopeLogs {
+                    scope: field(sl, "scope").map(|s| InstrumentationScope {
+                        name: string(s, "name"),
+                    }),
+                    log_records: array(sl, "logRecords")
+                        .map(|r| LogRecord {
+                            time_unix_nano: field(r, "timeUnixNano").and_then(int).unwrap_or(0)
+                                as u64,
+                            observed_time_unix_nano: field(r, "observedTimeUnixNano")
+                                .and_then(int)
+                                .unwrap_or(0)
+                                as u64,
+                            severity_number: severity_number(field(r, "severityNumber")),
+                            severity_text: string(r, "severityText"),
+                            body: field(r, "body").map(any_value),
+                            attributes: key_values(r, "attributes"),
+                            trace_id: hex::decode(string(r, "traceId")).unwrap_or_default(),
+                            span_id: hex::decode(string(r, "spanId")).unwrap_or_default(),
+                        })
+                        .collect(),
+                })
+                .collect(),
+        })
+        .collect();
+
+    Ok(ExportLogsServiceRequest { resource_logs })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_otlp_events() {
+        let json = serde_json::json!({
+            "resourceLogs": [{
+                "resource": {
+                    "attributes": [
+                        {"key": "host.name", "value": {"stringValue": "web-1.example.com"}},
+                        {"key": "service.name", "value": {"stringValue": "checkout"}}
+                    ]
+                },
+                "scopeLogs": [{
+                    "scope": {"name": "checkout.payments"},
+                    "logRecords": [{
+                        "timeUnixNano": "1544712660300000000",
+                        "severityNumber": 13,
+                        "body": {"stringValue": "card declined"},
+                        "attributes": [
+                            {"key": "attempt", "value": {"intValue": "3"}},
+                            {"key": "payload", "value": {"bytesValue": "3q2+7w=="}},
+                            {"key": "code.lineno", "value": {"intValue": 42}}
+                        ],
+                        "traceId": "5b8efff798038103d269b633813fc60c",
+                        "spanId": "eee19b7ec3c1b174"
+                    }, {
+                        "severityNumber": "SEVERITY_NUMBER_ERROR2",
+                        "body": {"kvlistValue": {"values": [{"key": "a", "value": {"boolValue": true}}]}}
+                    }]
+                }]
+            }]
+        });
+        let request = from_json(json.to_string().as_bytes()).unwrap();
+
+        // the protobuf encoding decodes to the same request
+        let encoded = prost::Message::encode_to_vec(&request);
+        let decoded: ExportLogsServiceRequest = prost::Message::decode(&encoded[..]).unwrap();
+        assert_eq!(decoded, request);
+
+        // and so to the same tags, bytes included
+        let from_protobuf = events(decoded);
+        let events = events(request);
+        assert_eq!(events.len(), 2);
+        for (json, protobuf) in events.iter().zip(&from_protobuf) {
+            assert_eq!(json.data.tags, protobuf.data.tags);
+        }
+        let bytes = AnyValue {
+            value: Some(Value::BytesValue(vec![0xde, 0xad, 0xbe, 0xef])),
+        };
+        assert_eq!(any_value_to_string(Some(&bytes)), "deadbeef");
+
+        let warn = &events[0];
+        assert_eq!(warn.host.to_string(), "web1examplecom");
+        assert_eq!(warn.app.to_string(), "checkout");
+        assert_eq!(warn.level, Level::Warn);
+        assert_eq!(warn.data.message, "card declined");
+        assert_eq!(warn.data.code_line, Some(42));
+        assert_eq!(warn.data.tags["attempt"], "3");
+        assert_eq!(warn.data.tags["payload"], "deadbeef");
+        assert_eq!(warn.data.tags["scope"], "checkout.payments");
+        assert_eq!(
+            warn.data.tags["trace_id"],
+            "5b8efff798038103d269b633813fc60c"
+        );
+        assert_eq!(warn.data.tags["span_id"], "eee19b7ec3c1b174");
+        assert_eq!(
+            warn.timestamp.unwrap().timestamp_nanos_opt(),
+            Some(1544712660300000000)
+        );
+
+        let error = &events[1];
+        assert_eq!(error.level, Level::Error);
+        assert_eq!(error.data.message, r#"{"a":true}"#);
+        assert!(error.timestamp.is_none());
+    }
+}
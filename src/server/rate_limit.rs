@@ -0,0 +1,278 @@
+use super::*;
+use std::time;
+
+/// Limits applied to the submissions of one host, app and API key combination.
+/// `None` means that dimension is not limited.
+#[derive(Clone, Debug, Default)]
+pub struct Limit {
+    pub rows_per_second: Option<f64>,
+    pub bytes_per_second: Option<f64>,
+    /// how many seconds worth of rows or bytes can be submitted at once,
+    /// after a period of inactivity
+    pub burst_seconds: f64,
+    /// maximum rows accepted per UTC day
+    pub daily_rows: Option<u64>,
+}
+
+/// Token bucket rate limits and daily quotas for the submission endpoint.
+///
+/// Every combination of host, app and API key id gets its own buckets, so one
+/// runaway app can't use up the allowance of the others.
+#[derive(Clone)]
+pub struct RateLimiter {
+    default: Limit,
+    overrides: sync::Arc<collections::HashMap<(Host, App), Limit>>,
+    state: sync::Arc<sync::Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    buckets: collections::HashMap<BucketKey, Buckets>,
+    /// the number of buckets at which the idle ones are next evicted
+    next_sweep: usize,
+}
+
+#[derive(PartialEq, Eq, Hash)]
+struct BucketKey {
+    host: Host,
+    app: App,
+    api_key: Option<String>,
+}
+
+struct Buckets {
+    rows: Bucket,
+    bytes: Bucket,
+    day: u64,
+    day_rows: u64,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: time::Instant,
+}
+
+impl Bucket {
+    fn full(rate: Option<f64>, burst_seconds: f64) -> Bucket {
+        Bucket {
+            tokens: rate.unwrap_or_default() * burst_seconds,
+            updated: time::Instant::now(),
+        }
+    }
+
+    fn is_full(&self, rate: Option<f64>, burst_seconds: f64, now: time::Instant) -> bool {
+        match rate {
+            Some(rate) => {
+                let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+                self.tokens + elapsed * rate >= rate * burst_seconds
+            }
+            None => true,
+        }
+    }
+
+    fn refill(&mut self, rate: f64, burst_seconds: f64, now: time::Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate * burst_seconds);
+        self.updated = now;
+    }
+
+    /// How long until `cost` tokens can be taken. A cost larger than the bucket
+    /// only needs a full bucket, leaving the bucket in debt afterwards.
+    fn wait_for(&self, cost: f64, rate: f64, burst_seconds: f64) -> Option<time::Duration> {
+        let needed = cost.min(rate * burst_seconds);
+        if self.tokens >= needed {
+            None
+        } else {
+            Some(time::Duration::from_secs_f64((needed - self.tokens) / rate))
+        }
+    }
+}
+
+impl Buckets {
+    /// Whether these buckets are the same as new ones, so can be dropped without
+    /// changing what is accepted
+    fn is_idle(&self, limit: &Limit, day: u64, now: time::Instant) -> bool {
+        (self.day != day || limit.daily_rows.is_none())
+            && self
+                .rows
+                .is_full(limit.rows_per_second, limit.burst_seconds, now)
+            && self
+                .bytes
+                .is_full(limit.bytes_per_second, limit.burst_seconds, now)
+    }
+}
+
+impl RateLimiter {
+    pub fn new(default: Limit) -> RateLimiter {
+        RateLimiter {
+            default,
+            overrides: Default::default(),
+            state: Default::default(),
+        }
+    }
+
+    /// Accepts every submission
+    pub fn unlimited() -> RateLimiter {
+        RateLimiter::new(Limit::default())
+    }
+
+    /// Uses a different limit for the given host and app
+    pub fn with_limit(mut self, host: Host, app: App, limit: Limit) -> RateLimiter {
+        sync::Arc::make_mut(&mut self.overrides).insert((host, app), limit);
+        self
+    }
+
+    /// Takes `rows` and `bytes` from the relevant buckets, or returns
+    /// [`Error::RateLimited`] with how long to wait before trying again.
+    pub fn check(
+        &self,
+        key: &ApiKey,
+        host: &Host,
+        app: &App,
+        rows: usize,
+        bytes: usize,
+    ) -> Result<()> {
+        let limit = self
+            .overrides
+            .get(&(host.clone(), app.clone()))
+            .unwrap_or(&self.default);
+
+        if limit.rows_per_second.is_none()
+            && limit.bytes_per_second.is_none()
+            && limit.daily_rows.is_none()
+        {
+            return Ok(());
+        }
+
+        let now = time::Instant::now();
+        let since_epoch = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap_or_default();
+        let day = since_epoch.as_secs() / SECONDS_PER_DAY;
+
+        let mut state = self
+            .state
+            .lock()
+            .unwrap_or_else(sync::PoisonError::into_inner);
+
+        // every host and app name gets its own buckets, so those which wouldn't limit
+        // anything any more are dropped, to keep clients making up names from growing the map
+        if state.buckets.len() >= state.next_sweep {
+            state.buckets.retain(|k, b| {
+                let limit = self
+                    .overrides
+                    .get(&(k.host.clone(), k.app.clone()))
+                    .unwrap_or(&self.default);
+                !b.is_idle(limit, day, now)
+            });
+            state.next_sweep = (state.buckets.len() * 2).max(MIN_SWEEP);
+        }
+
+        let buckets = state
+            .buckets
+            .entry(BucketKey {
+                host: host.clone(),
+                app: app.clone(),
+                api_key: key.id.clone(),
+            })
+            .or_insert_with(|| Buckets {
+                rows: Bucket::full(limit.rows_per_second, limit.burst_seconds),
+                bytes: Bucket::full(limit.bytes_per_second, limit.burst_seconds),
+                day,
+                day_rows: 0,
+            });
+
+        if buckets.day != day {
+            buckets.day = day;
+            buckets.day_rows = 0;
+        }
+
+        if let Some(daily_rows) = limit.daily_rows {
+            if buckets.day_rows + rows as u64 > daily_rows {
+                let retry_after = (day + 1) * SECONDS_PER_DAY - since_epoch.as_secs();
+                return Err(Error::RateLimited(time::Duration::from_secs(retry_after)));
+            }
+        }
+
+        let mut retry_after = None;
+        for (bucket, rate, cost) in [
+            (&mut buckets.rows, limit.rows_per_second, rows),
+            (&mut buckets.bytes, limit.bytes_per_second, bytes),
+        ] {
+            if let Some(rate) = rate {
+                bucket.refill(rate, limit.burst_seconds, now);
+                retry_after =
+                    retry_after.max(bucket.wait_for(cost as f64, rate, limit.burst_seconds));
+            }
+        }
+
+        if let Some(retry_after) = retry_after {
+            return Err(Error::RateLimited(retry_after));
+        }
+
+        // only take from the buckets once we know every limit allows the batch
+        if limit.rows_per_second.is_some() {
+            buckets.rows.tokens -= rows as f64;
+        }
+        if limit.bytes_per_second.is_some() {
+            buckets.bytes.tokens -= bytes as f64;
+        }
+        buckets.day_rows += rows as u64;
+
+        Ok(())
+    }
+}
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// the fewest buckets kept before looking for idle ones to evict
+const MIN_SWEEP: usize = 1024;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let host = "abc".parse::<Host>().unwrap();
+        let app = "def".parse::<App>().unwrap();
+        let other_app = "ghi".parse::<App>().unwrap();
+        let key = ApiKey::new(Role::Submit);
+
+        let limiter = RateLimiter::new(Limit {
+            rows_per_second: Some(1.0),
+            bytes_per_second: None,
+            burst_seconds: 10.0,
+            daily_rows: None,
+        })
+        .with_limit(
+            host.clone(),
+            other_app.clone(),
+            Limit {
+                daily_rows: Some(5),
+                ..Default::default()
+            },
+        );
+
+        assert!(limiter.check(&key, &host, &app, 6, 100).is_ok());
+        assert!(limiter.check(&key, &host, &app, 4, 100).is_ok());
+        match limiter.check(&key, &host, &app, 2, 100) {
+            Err(Error::RateLimited(retry_after)) => {
+                assert!(retry_after > time::Duration::from_millis(1500));
+                assert!(retry_after <= time::Duration::from_secs(2));
+            }
+            otherwise => panic!("expected rate limit, got {otherwise:?}"),
+        }
+
+        assert!(limiter.check(&key, &host, &other_app, 5, 100).is_ok());
+        assert!(limiter.check(&key, &host, &other_app, 1, 100).is_err());
+
+        // buckets for new app names are evicted once idle, but the limits in use are kept
+        for i in 0..MIN_SWEEP * 3 {
+            let app = format!("app{i}").parse::<App>().unwrap();
+            assert!(limiter.check(&key, &host, &app, 0, 0).is_ok());
+        }
+        assert!(limiter.state.lock().unwrap().buckets.len() <= MIN_SWEEP);
+        assert!(limiter.check(&key, &host, &app, 2, 100).is_err());
+        assert!(limiter.check(&key, &host, &other_app, 1, 100).is_err());
+    }
+}
@@ -0,0 +1,353 @@
+use super::ingest::{self, Event};
+use super::*;
+use chrono::{Datelike, TimeZone};
+use tokio::{io::AsyncBufReadExt, net};
+
+// a syslog message over UDP is at most one datagram
+const MAX_DATAGRAM: usize = 65_535;
+
+// messages over TCP are held to the same size, so a client which never ends its line can't
+// make the listener buffer without bound
+const MAX_LINE: u64 = MAX_DATAGRAM as u64;
+
+// RFC 3164 relays assume this priority (user.notice) for messages without one
+const DEFAULT_PRIORITY: u8 = 13;
+
+/// Receives syslog messages in either the RFC 5424 or the older RFC 3164 format and writes
+/// them to the storage.
+///
+/// The severity becomes the [`Level`], the hostname the [`Host`] and the APP-NAME (or TAG)
+/// the [`App`], both stripped of the characters they can't contain. Messages without either
+/// use the defaults. Structured data ends up in the tags as `{sd-id}.{param}`.
+///
+/// Listening on UDP and TCP are separate futures, which can be spawned next to `warp::serve`.
+#[derive(Clone)]
+pub struct SyslogListener<S> {
+    storage: S,
+    default_host: Host,
+    default_app: App,
+}
+
+impl<S> SyslogListener<S>
+where
+    S: storage::Storage + 'static,
+{
+    pub fn new(storage: S) -> SyslogListener<S> {
+        SyslogListener {
+            storage,
+            default_host: "unknown".parse().unwrap(),
+            default_app: "syslog".parse().unwrap(),
+        }
+    }
+
+    pub fn with_defaults(mut self, host: Host, app: App) -> SyslogListener<S> {
+        self.default_host = host;
+        self.default_app = app;
+        self
+    }
+
+    /// Receives one message per datagram, until the socket fails
+    pub async fn run_udp(self, addr: impl net::ToSocketAddrs) -> Result<()> {
+        let socket = net::UdpSocket::bind(addr).await?;
+        let mut buffer = vec![0; MAX_DATAGRAM];
+        loop {
+            let (len, _) = socket.recv_from(&mut buffer).await?;
+            self.receive(&buffer[..len]).await;
+        }
+    }
+
+    /// Accepts connections, each of which can use octet-counted framing (RFC 6587) or
+    /// send one message per line
+    pub async fn run_tcp(self, addr: impl net::ToSocketAddrs) -> Result<()> {
+        let listener = net::TcpListener::bind(addr).await?;
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let listener = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = listener.read_frames(stream).await {
+                    log::warn!("Syslog connection from {} failed: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    async fn read_frames(&self, stream: net::TcpStream) -> Result<()> {
+        let mut reader = tokio::io::BufReader::new(stream);
+        while let Some(frame) = read_frame(&mut reader).await? {
+            self.receive(&frame).await;
+        }
+        Ok(())
+    }
+
+    async fn receive(&self, message: &[u8]) {
+        let message = String::from_utf8_lossy(message);
+        let message = message.trim_end_matches(['\r', '\n', '\0']);
+        if message.is_empty() {
+            return;
+        }
+        let event = parse(message, &self.default_host, &self.default_app);
+        if let Err(e) = ingest::store(&self.storage, iter::once(event)).await {
+            log::error!("Failed to store syslog message: {}", e);
+        }
+    }
+}
+
+/// Reads the next message, `None` once the connection is closed
+async fn read_frame<R>(reader: &mut R) -> Result<Option<Vec<u8>>>
+where
+    R: tokio::io::AsyncBufRead + Unpin,
+{
+    let octet_counted = match reader.fill_buf().await?.first() {
+        None => return Ok(None),
+        Some(b) => b.is_ascii_digit(),
+    };
+
+    if octet_counted {
+        let len = read_line(reader, b' ').await?;
+        let len = str::from_utf8(&len)
+            .ok()
+            .and_then(|l| l.trim_end().parse::<usize>().ok())
+            .filter(|l| *l <= MAX_DATAGRAM)
+            .ok_or_else(|| Error::InvalidRequestBody("invalid syslog frame length".to_string()))?;
+        let mut frame = vec![0; len];
+        tokio::io::AsyncReadExt::read_exact(reader, &mut frame).await?;
+        Ok(Some(frame))
+    } else {
+        read_line(reader, b'\n').await.map(Some)
+    }
+}
+
+/// Reads up to and including `delimiter`, failing when it doesn't come within [`MAX_LINE`]
+async fn read_line<R>(reader: &mut R, delimiter: u8) -> Result<Vec<u8>>
+where
+    R: tokio::io::AsyncBufRead + Unpin,
+{
+    let mut line = Vec::new();
+    tokio::io::AsyncReadExt::take(&mut *reader, MAX_LINE)
+        .read_until(delimiter, &mut line)
+        .await?;
+    if line.len() as u64 == MAX_LINE && line.last() != Some(&delimiter) {
+        return Err(Error::InvalidRequestBody(format!(
+            "syslog message is longer than {} bytes",
+            MAX_LINE
+        )));
+    }
+    Ok(line)
+}
+
+fn parse(message: &str, default_host: &Host, default_app: &App) -> Event {
+    let (priority, rest) = parse_priority(message);
+    let mut tags = collections::HashMap::new();
+    tags.insert("facility".to_string(), (priority >> 3).to_string());
+
+    let (timestamp, host, app, message) = match rest.strip_prefix("1 ") {
+        Some(rest) => parse_rfc5424(rest, &mut tags),
+        None => parse_rfc3164(rest),
+    };
+
+    Event {
+        host: ingest::host_or(host, default_host),
+        app: ingest::app_or(app, default_app),
+        level: ingest::syslog_level(priority & 7),
+        timestamp,
+        data: ingest::log_data(message.to_string(), tags),
+    }
+}
+
+fn parse_priority(message: &str) -> (u8, &str) {
+    message
+        .strip_prefix('<')
+        .and_then(|m| m.split_once('>'))
+        .and_then(|(pri, rest)| Some((pri.parse().ok().filter(|p| *p < 192)?, rest)))
+        .unwrap_or((DEFAULT_PRIORITY, message))
+}
+
+type Parsed<'a> = (
+    Option<chrono::DateTime<chrono::Utc>>,
+    Option<&'a str>,
+    Option<&'a str>,
+    &'a str,
+);
+
+/// `TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA [MSG]`, after the version
+fn parse_rfc5424<'a>(rest: &'a str, tags: &mut collections::HashMap<String, String>) -> Parsed<'a> {
+    let mut fields = rest.splitn(6, ' ');
+    let mut next = || fields.next().filter(|f| *f != "-");
+
+    let timestamp = next()
+        .and_then(|t| chrono::DateTime::parse_from_rfc3339(t).ok())
+        .map(|t| t.with_timezone(&chrono::Utc));
+    let host = next();
+    let app = next();
+    if let Some(procid) = next() {
+        tags.insert("procid".to_string(), procid.to_string());
+    }
+    if let Some(msgid) = next() {
+        tags.insert("msgid".to_string(), msgid.to_string());
+    }
+
+    let rest = fields.next().unwrap_or_default();
+    let message = match rest.strip_prefix('-') {
+        Some(message) => message,
+        None => parse_structured_data(rest, tags),
+    };
+    let message = message.strip_prefix(' ').unwrap_or(message);
+
+    (timestamp, host, app, message.trim_start_matches('\u{feff}'))
+}
+
+/// Adds each `[id name="value" ...]` element to the tags, returning what follows them
+fn parse_structured_data<'a>(
+    mut rest: &'a str,
+    tags: &mut collections::HashMap<String, String>,
+) -> &'a str {
+    while let Some(element) = rest.strip_prefix('[') {
+        let (id, mut params) = element
+            .split_once([' ', ']'])
+            .map(|(id, _)| (id, &element[id.len()..]))
+            .unwrap_or((element, ""));
+
+        loop {
+            params = params.trim_start_matches(' ');
+            if let Some(after) = params.strip_prefix(']') {
+                rest = after;
+                break;
+            }
+            let (name, value) = match params.split_once("=\"") {
+                Some(param) => param,
+                // unterminated element, treat the remainder as the message
+                None => return params,
+            };
+            let mut unescaped = String::new();
+            let mut chars = value.char_indices();
+            let mut end = value.len();
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' => {
+                        if let Some((_, escaped)) = chars.next() {
+                            unescaped.push(escaped);
+                        }
+                    }
+                    '"' => {
+                        end = i + 1;
+                        break;
+                    }
+                    c => unescaped.push(c),
+                }
+            }
+            tags.insert(format!("{}.{}", id, name), unescaped);
+            params = &value[end..];
+        }
+    }
+    rest
+}
+
+/// `Mmm dd hh:mm:ss HOSTNAME TAG[PID]: MSG`, where everything but the message is optional
+fn parse_rfc3164(rest: &str) -> Parsed<'_> {
+    let timestamp = rest.get(..15).and_then(|t| {
+        let now = chrono::Utc::now();
+        let year = format!("{} {}", now.year(), t.replace("  ", " "));
+        let parsed = chrono::NaiveDateTime::parse_from_str(&year, "%Y %b %d %H:%M:%S").ok()?;
+        // the year isn't sent, so messages from late december may be received in january
+        let parsed = if parsed > now.naive_utc() + chrono::Duration::days(1) {
+            parsed.with_year(now.year() - 1)?
+        } else {
+            parsed
+        };
+        Some(chrono::Utc.from_utc_datetime(&parsed))
+    });
+
+    let rest = match timestamp {
+        Some(_) => rest[15..].trim_start(),
+        None => return (None, None, None, rest),
+    };
+
+    let (host, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+
+    let tag_len = rest
+        .find(|c: char| {
+            !(c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' || c == '/')
+        })
+        .unwrap_or(rest.len());
+    let (app, message) = match rest[tag_len..].chars().next() {
+        Some('[') | Some(':') if tag_len > 0 => {
+            let message = rest[tag_len..]
+                .split_once(':')
+                .map(|(_, m)| m.trim_start())
+                .unwrap_or_default();
+            (Some(&rest[..tag_len]), message)
+        }
+        _ => (None, rest),
+    };
+
+    (timestamp, Some(host), app, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_syslog() {
+        let host = "defaulthost".parse().unwrap();
+        let app = "defaultapp".parse().unwrap();
+
+        let event = parse(
+            r#"<165>1 2003-10-11T22:14:15.003Z mymachine.example.com evntslog - ID47 [exampleSDID@32473 iut="3" eventSource="Application \"x\""] An application event"#,
+            &host,
+            &app,
+        );
+        assert_eq!(event.host.to_string(), "mymachineexamplecom");
+        assert_eq!(event.app.to_string(), "evntslog");
+        assert_eq!(event.level, Level::Info);
+        assert_eq!(event.data.message, "An application event");
+        assert_eq!(event.data.tags["facility"], "20");
+        assert_eq!(event.data.tags["msgid"], "ID47");
+        assert_eq!(event.data.tags["exampleSDID@32473.iut"], "3");
+        assert_eq!(
+            event.data.tags["exampleSDID@32473.eventSource"],
+            "Application \"x\""
+        );
+        assert_eq!(
+            event.timestamp.unwrap().to_rfc3339(),
+            "2003-10-11T22:14:15.003+00:00"
+        );
+
+        let event = parse("<34>1 - - - - - -", &host, &app);
+        assert_eq!(event.level, Level::Error);
+        assert_eq!(event.host, host);
+        assert_eq!(event.app, app);
+        assert_eq!(event.data.message, "");
+
+        let event = parse(
+            "<12>Oct 11 22:14:15 my-host sshd[1234]: Failed password",
+            &host,
+            &app,
+        );
+        assert_eq!(event.host.to_string(), "myhost");
+        assert_eq!(event.app.to_string(), "sshd");
+        assert_eq!(event.level, Level::Warn);
+        assert_eq!(event.data.message, "Failed password");
+        assert!(event.timestamp.is_some());
+
+        let event = parse("just a message", &host, &app);
+        assert_eq!(event.level, Level::Info);
+        assert_eq!(event.data.message, "just a message");
+    }
+
+    #[tokio::test]
+    async fn test_read_frame() {
+        let mut input: &[u8] = b"11 <13>1 - - -first\n<13>second\n";
+        let mut frames = Vec::new();
+        while let Some(frame) = read_frame(&mut input).await.unwrap() {
+            frames.push(String::from_utf8(frame).unwrap());
+        }
+        assert_eq!(frames, vec!["<13>1 - - -", "first\n", "<13>second\n"]);
+
+        // a line without an end is cut off rather than buffered forever
+        let long = vec![b'a'; MAX_LINE as usize + 1];
+        assert!(read_frame(&mut long.as_slice()).await.is_err());
+        let digits = vec![b'1'; MAX_LINE as usize + 1];
+        assert!(read_frame(&mut digits.as_slice()).await.is_err());
+    }
+}
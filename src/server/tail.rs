@@ -0,0 +1,270 @@
+use super::*;
+use futures_util::{stream, Stream, StreamExt};
+use tokio::sync::broadcast;
+use warp::Reply;
+
+/// Fans out every stored log row to the currently connected `/tail` clients.
+///
+/// The hub is fed by wrapping the storage given to the submission endpoint with
+/// [`TailHub::storage`], so rows are only published once they have been stored.
+#[derive(Clone)]
+pub struct TailHub {
+    sender: broadcast::Sender<sync::Arc<QueryResponse>>,
+}
+
+impl TailHub {
+    /// `capacity` is the number of rows buffered for each client, a client that
+    /// falls further behind than this will skip the oldest rows.
+    pub fn new(capacity: usize) -> TailHub {
+        let (sender, _) = broadcast::channel(capacity);
+        TailHub { sender }
+    }
+
+    pub fn storage<S>(&self, storage: S) -> Tailed<S>
+    where
+        S: storage::Storage,
+    {
+        Tailed {
+            storage,
+            hub: self.clone(),
+        }
+    }
+
+    pub fn publish(&self, host: &Host, app: &App, level: &Level, batch: &LogBatch) {
+        // avoid cloning the batch when nobody is listening
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+
+        for (id, data) in batch {
+            // an error here only means that all receivers have since hung up
+            let _ = self.sender.send(sync::Arc::new(QueryResponse {
+                host: host.clone(),
+                app: app.clone(),
+                level: level.clone(),
+                id: *id,
+                data: data.clone(),
+            }));
+        }
+    }
+
+    fn subscribe(
+        &self,
+        filter: TailFilter,
+    ) -> impl Stream<Item = result::Result<sync::Arc<QueryResponse>, u64>> {
+        stream::unfold(
+            (self.sender.subscribe(), filter),
+            |(mut receiver, filter)| async move {
+                loop {
+                    match receiver.recv().await {
+                        Ok(row) if filter.matches(&row) => {
+                            return Some((Ok(row), (receiver, filter)))
+                        }
+                        Ok(_) => continue,
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            return Some((Err(skipped), (receiver, filter)))
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    }
+                }
+            },
+        )
+    }
+}
+
+/// Storage wrapper which publishes each submitted batch to a [`TailHub`]
+#[derive(Clone)]
+pub struct Tailed<S> {
+    storage: S,
+    hub: TailHub,
+}
+
+#[async_trait::async_trait]
+impl<S> storage::Storage for Tailed<S>
+where
+    S: storage::Storage,
+{
+    async fn submit(
+        &self,
+        host: &Host,
+        app: &App,
+        level: Level,
+        log_batch: LogBatch,
+    ) -> Result<()> {
+        if self.hub.sender.receiver_count() == 0 {
+            return self.storage.submit(host, app, level, log_batch).await;
+        }
+
+        let published = log_batch.clone();
+        self.storage
+            .submit(host, app, level.clone(), log_batch)
+            .await?;
+        self.hub.publish(host, app, &level, &published);
+
+        Ok(())
+    }
+
+    async fn query(
+        &self,
+        params: QueryParams,
+        scope: &storage::Scope,
+    ) -> Result<Vec<QueryResponse>> {
+        self.storage.query(params, scope).await
+    }
+
+    async fn detail(&self, host: &Host, app: &App, level: Level) -> Result<LogTreeDetail> {
+        self.storage.detail(host, app, level).await
+    }
+
+    async fn info(&self) -> Result<Vec<result::Result<LogTreeInfo, ParseLogTreeInfoError>>> {
+        self.storage.info().await
+    }
+
+    async fn flush(&self, host: &Host, app: &App) -> Result<()> {
+        self.storage.flush(host, app).await
+    }
+}
+
+struct TailFilter {
+    params: TailParams,
+    key: ApiKey,
+    must_match: Option<regex::Regex>,
+    must_not_match: Option<regex::Regex>,
+}
+
+impl TailFilter {
+    fn new(params: TailParams, key: ApiKey) -> Result<TailFilter> {
+        let must_match = params
+            .message_matches
+            .as_deref()
+            .map(regex::Regex::new)
+            .transpose()?;
+        let must_not_match = params
+            .message_not_matches
+            .as_deref()
+            .map(regex::Regex::new)
+            .transpose()?;
+        Ok(TailFilter {
+            params,
+            key,
+            must_match,
+            must_not_match,
+        })
+    }
+
+    fn matches(&self, row: &QueryResponse) -> bool {
+        row.level <= self.params.max_log_level.clone().unwrap_or(Level::Info)
+            && self.key.permits(&row.host, &row.app)
+            && storage::filter_with_option(&row.host, &self.params.host_contains)
+            && storage::filter_with_option(&row.app, &self.params.app_contains)
+            && self
+                .must_match
+                .as_ref()
+                .map(|m| m.is_match(&row.data.message))
+                .unwrap_or(true)
+            && !self
+                .must_not_match
+                .as_ref()
+                .map(|n| n.is_match(&row.data.message))
+                .unwrap_or(false)
+            && self
+                .params
+                .tags
+                .as_ref()
+                .map(|t| t.matches(&row.data.tags))
+                .unwrap_or(true)
+    }
+}
+
+async fn tail<K>(
+    api_key: String,
+    params: TailParams,
+    hub: TailHub,
+    api_keys: sync::Arc<K>,
+) -> Result<warp::reply::Response>
+where
+    K: ApiKeyStore + ?Sized,
+{
+    // ensure the request's API key is allowed
+    let key = auth::authorize(&*api_keys, api_key, Role::Read).await?;
+
+    let events = hub
+        .subscribe(TailFilter::new(params, key)?)
+        .map(|row| match row {
+            Ok(row) => warp::sse::Event::default()
+                .id(row.id.to_string())
+                .event(TAIL_EVENT)
+                .json_data(&*row),
+            Err(skipped) => {
+                Ok(warp::sse::Event::default().comment(format!("skipped {skipped} rows")))
+            }
+        });
+
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)).into_response())
+}
+
+/// Streams newly submitted logs matching the [`TailParams`] query string as server-sent events.
+/// Each row is a `log` event holding the JSON encoded [`QueryResponse`].
+pub fn create_tail_endpoint<K>(
+    hub: TailHub,
+    api_keys: sync::Arc<K>,
+) -> impl warp::Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone
+where
+    K: ApiKeyStore + ?Sized + 'static,
+{
+    warp::path("tail")
+        .and(warp::get())
+        .and(warp::path::end())
+        .and(warp::header(API_KEY_HEADER))
+        .and(warp::query())
+        .and(add(hub))
+        .and(add(api_keys))
+        .and_then(|key, params, hub, keys| {
+            tail(key, params, hub, keys).map(|r| {
+                Ok::<_, convert::Infallible>(
+                    r.unwrap_or_else(|e| e.into_reply::<()>(Default::default()).into_response()),
+                )
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_tail_filter() {
+        let hub = TailHub::new(16);
+        let filter = TailFilter::new(
+            TailParams {
+                max_log_level: Some(Level::Warn),
+                message_not_matches: Some("ignored".to_string()),
+                ..Default::default()
+            },
+            ApiKey::new(Role::Read),
+        )
+        .unwrap();
+        let rows = hub.subscribe(filter);
+
+        let host = "abc".parse::<Host>().unwrap();
+        let app = "def".parse::<App>().unwrap();
+        let mut gen = ulid::Generator::new();
+        let mut batch = |message: &str| {
+            let data = LogData {
+                message: message.to_string(),
+                code_module: None,
+                code_file: None,
+                code_line: None,
+                tags: collections::HashMap::new(),
+            };
+            iter::once((gen.generate().unwrap(), data)).collect::<LogBatch>()
+        };
+
+        hub.publish(&host, &app, &Level::Info, &batch("too verbose"));
+        hub.publish(&host, &app, &Level::Error, &batch("ignored"));
+        hub.publish(&host, &app, &Level::Warn, &batch("wanted"));
+
+        let row = Box::pin(rows).next().await.unwrap().unwrap();
+        assert_eq!(row.data.message, "wanted");
+    }
+}
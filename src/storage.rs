@@ -16,7 +16,8 @@ pub trait Storage: Clone + Send + Sync {
     async fn submit(&self, host: &Host, app: &App, level: Level, log_batch: LogBatch)
         -> Result<()>;
 
-    async fn query(&self, params: QueryParams) -> Result<Vec<QueryResponse>>;
+    /// Only logs within `scope` may be returned, and only those count towards `max_results`
+    async fn query(&self, params: QueryParams, scope: &Scope) -> Result<Vec<QueryResponse>>;
 
     async fn detail(&self, host: &Host, app: &App, level: Level) -> Result<LogTreeDetail>;
 
@@ -25,6 +26,22 @@ pub trait Storage: Clone + Send + Sync {
     async fn flush(&self, host: &Host, app: &App) -> Result<()>;
 }
 
+/// The hosts and apps a query may read, which the server takes from the API key of the request
+#[derive(Clone, Debug, Default)]
+pub struct Scope {
+    /// when set, only logs from these hosts may be read
+    pub hosts: Option<collections::BTreeSet<Host>>,
+    /// when set, only logs from these apps may be read
+    pub apps: Option<collections::BTreeSet<App>>,
+}
+
+impl Scope {
+    pub fn permits(&self, host: &Host, app: &App) -> bool {
+        self.hosts.as_ref().is_none_or(|h| h.contains(host))
+            && self.apps.as_ref().is_none_or(|a| a.contains(app))
+    }
+}
+
 pub fn filter_with_option<T: AsRef<str>>(input: &T, filter: &Option<T>) -> bool {
     filter
         .as_ref()
@@ -26,13 +26,14 @@ impl Storage for sled::Db {
         Ok(())
     }
 
-    async fn query(&self, params: QueryParams) -> Result<Vec<QueryResponse>> {
+    async fn query(&self, params: QueryParams, scope: &Scope) -> Result<Vec<QueryResponse>> {
         let relevant_trees = self
             .tree_names()
             .iter()
             .filter_map(|t| TreeName::from_bytes(t).ok())
             .filter(|t| filter_with_option(&t.host, &params.host_contains))
             .filter(|t| filter_with_option(&t.app, &params.app_contains))
+            .filter(|t| scope.permits(&t.host, &t.app))
             .filter(|t| t.level <= params.max_log_level.clone().unwrap_or(Level::Info))
             .collect::<Vec<_>>();
 
@@ -94,6 +95,16 @@ impl Storage for sled::Db {
                     continue;
                 }
 
+                let tags_match = params
+                    .tags
+                    .as_ref()
+                    .map(|t| t.matches(&data.tags))
+                    .unwrap_or(true);
+
+                if !tags_match {
+                    continue;
+                }
+
                 response.push(QueryResponse {
                     host: tree_name.host.clone(),
                     app: tree_name.app.clone(),
@@ -1,24 +1,171 @@
 use super::*;
-use futures_channel::mpsc;
+use futures_channel::{mpsc, oneshot};
+use futures_util::FutureExt;
+use std::time;
 
+mod channel;
 #[cfg(feature = "local-subscriber")]
 pub mod local;
 #[cfg(feature = "remote-subscriber")]
 pub mod remote;
+#[cfg(feature = "remote-subscriber")]
+mod spill;
+
+pub use channel::OverflowPolicy;
+#[cfg(feature = "remote-subscriber")]
+pub use spill::SpillBuffer;
 
 pub struct Subscriber {
     // send a new log message
-    sender: mpsc::UnboundedSender<(log::Level, LogData)>,
+    sender: channel::Sender,
 
     // request a flush, will recieve () back when done
     // inner sender is sync as this has to be handled from sync context
-    flush_requester: mpsc::UnboundedSender<std::sync::mpsc::SyncSender<()>>,
+    flush_requester: mpsc::UnboundedSender<FlushRequest>,
+
+    // how long a flush waits for the records to be sent or saved
+    flush_timeout: time::Duration,
 
     on_result: Box<dyn Fn(&'static str) + Sync + Send>,
 
     level: log::LevelFilter,
+
+    // how long dropping the subscriber blocks for, to give the sender time to empty the cache
+    drop_delay: Option<time::Duration>,
+}
+
+/// Asks the `DataSender` or `DataSaver` to write out everything logged so far
+struct FlushRequest {
+    done: std::sync::mpsc::SyncSender<()>,
+    // the flushing thread stops waiting after this
+    deadline: Timestamp,
+}
+
+// unless changed with `Subscriber::with_flush_timeout`
+const DEFAULT_FLUSH_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+
+// how much longer than its timeout `ShutdownHandle::shutdown_blocking` waits for the report
+#[cfg(not(feature = "wasm"))]
+const SHUTDOWN_GRACE: time::Duration = time::Duration::from_secs(1);
+
+/// Stops the subscriber it was created with: new records are refused, everything already
+/// logged is sent or saved, and what couldn't be is reported back. The `DataSender` or
+/// `DataSaver` returns once it is done.
+pub struct ShutdownHandle {
+    closer: channel::Closer,
+    request: oneshot::Sender<ShutdownRequest>,
 }
+
+impl ShutdownHandle {
+    fn new(closer: channel::Closer) -> (ShutdownHandle, oneshot::Receiver<ShutdownRequest>) {
+        let (tx, rx) = oneshot::channel();
+        (
+            ShutdownHandle {
+                closer,
+                request: tx,
+            },
+            rx,
+        )
+    }
+
+    /// Gives up on whatever hasn't been delivered once the timeout is up
+    pub async fn shutdown(self, timeout: time::Duration) -> Result<ShutdownReport> {
+        let (tx, rx) = oneshot::channel();
+        self.request(timeout, ReplyTo::Async(tx))?;
+        rx.await.map_err(|_| Error::LogSubscriberClosed)
+    }
+
+    /// The same as [`ShutdownHandle::shutdown`] for synchronous code, which must not be
+    /// running on the thread the `DataSender` or `DataSaver` is polled on. Not available
+    /// with the `wasm` feature, as the browser's thread can't wait.
+    #[cfg(not(feature = "wasm"))]
+    pub fn shutdown_blocking(self, timeout: time::Duration) -> Result<ShutdownReport> {
+        let (tx, rx) = std::sync::mpsc::sync_channel(1);
+        self.request(timeout, ReplyTo::Blocking(tx))?;
+        rx.recv_timeout(timeout + SHUTDOWN_GRACE)
+            .map_err(|e| match e {
+                std::sync::mpsc::RecvTimeoutError::Timeout => Error::ShutdownTimeout,
+                std::sync::mpsc::RecvTimeoutError::Disconnected => Error::LogSubscriberClosed,
+            })
+    }
+
+    fn request(self, timeout: time::Duration, reply_to: ReplyTo) -> Result<()> {
+        // sent before closing, so the request is there once the receiver ends
+        self.request
+            .send(ShutdownRequest {
+                deadline: after(timeout),
+                reply_to,
+            })
+            .map_err(|_| Error::LogSubscriberClosed)?;
+        self.closer.close();
+        Ok(())
+    }
+}
+
+/// What became of the records which hadn't been delivered when the shutdown began
+#[derive(Debug, Default)]
+pub struct ShutdownReport {
+    /// records which were refused or couldn't be delivered in time
+    pub undelivered: Vec<(log::Level, LogData)>,
+    /// records written to the spill buffer, to be sent on the next run
+    pub spilled: usize,
+    /// records whose submission was still in progress at the deadline, which the server
+    /// may or may not have received. With a spill buffer these are spilled instead.
+    pub abandoned: usize,
+}
+
+struct ShutdownRequest {
+    deadline: Timestamp,
+    reply_to: ReplyTo,
+}
+
+enum ReplyTo {
+    Async(oneshot::Sender<ShutdownReport>),
+    #[cfg(not(feature = "wasm"))]
+    Blocking(std::sync::mpsc::SyncSender<ShutdownReport>),
+}
+
+impl ShutdownRequest {
+    fn reply(self, report: ShutdownReport) {
+        // the caller may have given up waiting
+        let _ = match self.reply_to {
+            ReplyTo::Async(tx) => tx.send(report).map_err(|_| ()),
+            #[cfg(not(feature = "wasm"))]
+            ReplyTo::Blocking(tx) => tx.send(report).map_err(|_| ()),
+        };
+    }
+}
+
 impl Subscriber {
+    /// How many records may be queued for sending or saving, and what happens to new
+    /// ones once that many are. Defaults to 100,000, dropping the oldest.
+    pub fn with_capacity(self, capacity: usize, policy: OverflowPolicy) -> Subscriber {
+        self.sender.set_capacity(capacity, policy);
+        self
+    }
+
+    /// How long `log::logger().flush()` waits for everything logged so far to be sent or
+    /// saved, before giving up and returning. Defaults to 30 seconds.
+    pub fn with_flush_timeout(mut self, flush_timeout: time::Duration) -> Subscriber {
+        self.flush_timeout = flush_timeout;
+        self
+    }
+
+    /// Makes dropping the subscriber block for the given time, so that a sender running on
+    /// another thread can deliver what is left. Prefer [`ShutdownHandle`], which knows when
+    /// it is done.
+    pub fn with_drop_delay(mut self, drop_delay: time::Duration) -> Subscriber {
+        self.drop_delay = Some(drop_delay);
+        self
+    }
+
+    /// Queues a record which didn't come through the `log` crate, such as a `tracing` event
+    pub(crate) fn submit(&self, level: log::Level, data: LogData) {
+        if self.sender.send(level, data).is_err() {
+            (self.on_result)("log_record_send_failure");
+        }
+    }
+
     pub fn set_logger(self) -> Result<()> {
         let lf = self.level;
         log::set_boxed_logger(Box::new(self))?;
@@ -36,6 +183,20 @@ pub struct CacheLimit {
     pub info: usize,
     pub debug: usize,
     pub trace: usize,
+    /// how long a message may wait in the cache, even if its batch isn't full
+    pub max_age: MaxAge,
+    /// everything is sent once the cached messages add up to this many bytes
+    pub max_bytes: usize,
+}
+
+/// How long the oldest message of a given level
+/// may be held in the cache before its batch is sent
+pub struct MaxAge {
+    pub error: time::Duration,
+    pub warn: time::Duration,
+    pub info: time::Duration,
+    pub debug: time::Duration,
+    pub trace: time::Duration,
 }
 
 impl CacheLimit {
@@ -55,6 +216,80 @@ impl CacheLimit {
     ) -> bool {
         batch.len() >= self.get_limit(level)
     }
+    fn get_max_age(&self, level: log::Level) -> time::Duration {
+        match level {
+            log::Level::Trace => self.max_age.trace,
+            log::Level::Debug => self.max_age.debug,
+            log::Level::Info => self.max_age.info,
+            log::Level::Warn => self.max_age.warn,
+            log::Level::Error => self.max_age.error,
+        }
+    }
+    /// Whether the batch is full or its oldest message has waited long enough
+    fn is_due(
+        &self,
+        level: log::Level,
+        batch: &collections::BTreeMap<ulid::Ulid, LogData>,
+        now: Timestamp,
+    ) -> bool {
+        self.should_send(level, batch)
+            || self
+                .expires_at(level, batch)
+                .is_some_and(|expires_at| expires_at <= now)
+    }
+    /// When the batch has to be sent, going by the time its oldest message was logged
+    fn expires_at(
+        &self,
+        level: log::Level,
+        batch: &collections::BTreeMap<ulid::Ulid, LogData>,
+    ) -> Option<Timestamp> {
+        let oldest = batch.keys().next()?;
+        let max_age = chrono::Duration::from_std(self.get_max_age(level)).ok()?;
+        oldest.datetime().checked_add_signed(max_age)
+    }
+}
+
+/// Roughly how many bytes the message takes up once sent, used for [`CacheLimit::max_bytes`]
+fn approximate_size(data: &LogData) -> usize {
+    data.message.len()
+        + data.code_module.as_ref().map_or(0, String::len)
+        + data.code_file.as_ref().map_or(0, String::len)
+        + data
+            .tags
+            .iter()
+            .map(|(k, v)| k.len() + v.len())
+            .sum::<usize>()
+}
+
+/// A point in time for the subscriber's timers and deadlines. `std::time::Instant` and
+/// `SystemTime` panic on `wasm32-unknown-unknown`, which `wasm-subscriber` targets, while
+/// chrono's clock works there too.
+type Timestamp = chrono::DateTime<chrono::Utc>;
+
+fn now() -> Timestamp {
+    chrono::Utc::now()
+}
+
+/// The time once `duration` has passed, or the end of time if that is too far off
+fn after(duration: time::Duration) -> Timestamp {
+    chrono::Duration::from_std(duration)
+        .ok()
+        .and_then(|duration| now().checked_add_signed(duration))
+        .unwrap_or(chrono::DateTime::<chrono::Utc>::MAX_UTC)
+}
+
+/// How long until `at`, nothing once it has passed
+fn until(at: Timestamp) -> time::Duration {
+    (at - now()).to_std().unwrap_or_default()
+}
+
+/// Whichever of the two futures completes first
+fn first<A, B>(a: A, b: B) -> impl futures_util::Future<Output = A::Output>
+where
+    A: futures_util::Future + Unpin,
+    B: futures_util::Future<Output = A::Output> + Unpin,
+{
+    futures_util::future::select(a, b).map(|either| either.factor_first().0)
 }
 
 impl Default for CacheLimit {
@@ -65,6 +300,20 @@ impl Default for CacheLimit {
             info: 10,
             debug: 100,
             trace: 100,
+            max_age: MaxAge::default(),
+            max_bytes: 1024 * 1024,
+        }
+    }
+}
+
+impl Default for MaxAge {
+    fn default() -> MaxAge {
+        MaxAge {
+            error: time::Duration::from_secs(1),
+            warn: time::Duration::from_secs(1),
+            info: time::Duration::from_secs(10),
+            debug: time::Duration::from_secs(30),
+            trace: time::Duration::from_secs(30),
         }
     }
 }
@@ -74,28 +323,40 @@ impl log::Log for Subscriber {
         self.level >= metadata.level()
     }
     fn log(&self, record: &log::Record) {
-        let res = self.sender.unbounded_send((record.level(), record.into()));
-        if res.is_err() {
-            (self.on_result)("log_record_send_failure");
-        }
+        self.submit(record.level(), record.into());
     }
     fn flush(&self) {
-        let (tx, rx) = std::sync::mpsc::sync_channel(0);
-        let res = self.flush_requester.unbounded_send(tx);
+        // buffered, so that replying never blocks the sender
+        let (tx, rx) = std::sync::mpsc::sync_channel(1);
+        let res = self.flush_requester.unbounded_send(FlushRequest {
+            done: tx,
+            deadline: after(self.flush_timeout),
+        });
         if res.is_err() {
             (self.on_result)("flush_request_failure");
         }
-        let res = rx.recv();
-        if res.is_err() {
-            (self.on_result)("flush_response_failure");
+        // in the browser the sender runs on this thread, so waiting for it would never end,
+        // and waiting with a timeout panics as it reads `Instant`. The cache is still sent
+        // once the sender is polled again.
+        #[cfg(feature = "wasm")]
+        drop(rx);
+        #[cfg(not(feature = "wasm"))]
+        match rx.recv_timeout(self.flush_timeout) {
+            Ok(()) => {}
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => (self.on_result)("flush_timeout"),
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => {
+                (self.on_result)("flush_response_failure")
+            }
         }
     }
 }
 
 impl Drop for Subscriber {
     fn drop(&mut self) {
-        eprintln!("Dropping log subscriber - sleeing the thread to give the sender time to empty the cache");
-        std::thread::sleep(std::time::Duration::from_secs(10));
+        if let Some(drop_delay) = self.drop_delay {
+            eprintln!("Dropping log subscriber - sleeping the thread to give the sender time to empty the cache");
+            std::thread::sleep(drop_delay);
+        }
     }
 }
 
@@ -113,28 +374,22 @@ mod tests {
             tags: collections::HashMap::new(),
         };
 
-        let batch_1 = iter::repeat(log_data.clone())
-            .take(1)
+        let batch_1 = iter::repeat_n(log_data.clone(), 1)
             .map(|ld| (gen.generate().unwrap(), ld))
             .collect::<collections::BTreeMap<_, _>>();
-        let batch_5 = iter::repeat(log_data.clone())
-            .take(5)
+        let batch_5 = iter::repeat_n(log_data.clone(), 5)
             .map(|ld| (gen.generate().unwrap(), ld))
             .collect::<collections::BTreeMap<_, _>>();
-        let batch_9 = iter::repeat(log_data.clone())
-            .take(9)
+        let batch_9 = iter::repeat_n(log_data.clone(), 9)
             .map(|ld| (gen.generate().unwrap(), ld))
             .collect::<collections::BTreeMap<_, _>>();
-        let batch_10 = iter::repeat(log_data.clone())
-            .take(10)
+        let batch_10 = iter::repeat_n(log_data.clone(), 10)
             .map(|ld| (gen.generate().unwrap(), ld))
             .collect::<collections::BTreeMap<_, _>>();
-        let batch_99 = iter::repeat(log_data.clone())
-            .take(99)
+        let batch_99 = iter::repeat_n(log_data.clone(), 99)
             .map(|ld| (gen.generate().unwrap(), ld))
             .collect::<collections::BTreeMap<_, _>>();
-        let batch_100 = iter::repeat(log_data)
-            .take(100)
+        let batch_100 = iter::repeat_n(log_data, 100)
             .map(|ld| (gen.generate().unwrap(), ld))
             .collect::<collections::BTreeMap<_, _>>();
 
@@ -174,5 +429,14 @@ mod tests {
         assert!(!limit.should_send(log::Level::Trace, &batch_10));
         assert!(!limit.should_send(log::Level::Trace, &batch_99));
         assert!(limit.should_send(log::Level::Trace, &batch_100));
+
+        // a batch expires once its oldest message is older than the level's max age
+        let logged = now();
+        let expires_at = limit.expires_at(log::Level::Info, &batch_5).unwrap();
+        assert!(expires_at > logged + chrono::Duration::seconds(9));
+        assert!(expires_at <= logged + chrono::Duration::seconds(10));
+        assert!(limit
+            .expires_at(log::Level::Info, &collections::BTreeMap::new())
+            .is_none());
     }
 }
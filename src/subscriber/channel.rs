@@ -0,0 +1,359 @@
+use super::*;
+use futures_util::task::AtomicWaker;
+use std::{pin, task, time};
+
+// queued records beyond which the overflow policy kicks in, unless changed with
+// `Subscriber::with_capacity`
+const DEFAULT_CAPACITY: usize = 100_000;
+
+// during a long log storm, dropped records are reported at most this often
+const REPORT_INTERVAL: time::Duration = time::Duration::from_secs(10);
+
+// how many times the capacity errors and warnings may fill with `KeepErrorsAndWarnings`
+const KEEP_LIMIT: usize = 2;
+
+/// What the subscriber does with a new record once its queue is full
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// the new record is dropped
+    DropNewest,
+    /// the oldest queued record is dropped to make room
+    DropOldest,
+    /// the logging thread waits for room, which must not be the thread the queue is emptied on
+    Block,
+    /// errors and warnings push out the oldest record of a lower level, or go over the capacity
+    /// if there is none, while records of lower levels are dropped. Past twice the capacity
+    /// the oldest warnings are dropped, then the oldest errors.
+    KeepErrorsAndWarnings,
+}
+
+// queued records are kept apart by level, so the oldest of each can be dropped straight away
+const ERRORS: usize = 0;
+const WARNINGS: usize = 1;
+const THE_REST: usize = 2;
+
+struct State {
+    // errors, warnings and the rest, each tagged with the order they were sent in
+    queues: [collections::VecDeque<(u64, log::Level, LogData)>; 3],
+    sent: u64,
+    capacity: usize,
+    policy: OverflowPolicy,
+    // records dropped since the last report, and when the first of them was
+    dropped: u64,
+    dropping_since: Option<Timestamp>,
+    sender_closed: bool,
+    receiver_closed: bool,
+}
+
+impl State {
+    fn note_dropped(&mut self) {
+        self.dropped += 1;
+        self.dropping_since.get_or_insert_with(now);
+    }
+
+    fn len(&self) -> usize {
+        self.queues.iter().map(|q| q.len()).sum()
+    }
+
+    fn push(&mut self, level: log::Level, data: LogData) {
+        let queue = match level {
+            log::Level::Error => ERRORS,
+            log::Level::Warn => WARNINGS,
+            _ => THE_REST,
+        };
+        self.queues[queue].push_back((self.sent, level, data));
+        self.sent += 1;
+    }
+
+    /// Takes the record which was sent first out of all the queues
+    fn pop_oldest(&mut self) -> Option<(log::Level, LogData)> {
+        let oldest = self
+            .queues
+            .iter_mut()
+            .filter(|q| !q.is_empty())
+            .min_by_key(|q| q.front().map(|(sent, _, _)| *sent))?;
+        oldest.pop_front().map(|(_, level, data)| (level, data))
+    }
+}
+
+struct Shared {
+    state: sync::Mutex<State>,
+    not_full: sync::Condvar,
+    waker: AtomicWaker,
+}
+
+impl Shared {
+    fn lock(&self) -> sync::MutexGuard<'_, State> {
+        // a panic while holding the lock can't leave the queue inconsistent, so carry on
+        self.state
+            .lock()
+            .unwrap_or_else(sync::PoisonError::into_inner)
+    }
+}
+
+pub(crate) fn bounded() -> (Sender, Receiver, Closer) {
+    let shared = sync::Arc::new(Shared {
+        state: sync::Mutex::new(State {
+            queues: Default::default(),
+            sent: 0,
+            capacity: DEFAULT_CAPACITY,
+            policy: OverflowPolicy::DropOldest,
+            dropped: 0,
+            dropping_since: None,
+            sender_closed: false,
+            receiver_closed: false,
+        }),
+        not_full: sync::Condvar::new(),
+        waker: AtomicWaker::new(),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver {
+            shared: shared.clone(),
+        },
+        Closer { shared },
+    )
+}
+
+pub(crate) struct Sender {
+    shared: sync::Arc<Shared>,
+}
+
+impl Sender {
+    pub(crate) fn set_capacity(&self, capacity: usize, policy: OverflowPolicy) {
+        let mut state = self.shared.lock();
+        state.capacity = capacity.max(1);
+        state.policy = policy;
+        self.shared.not_full.notify_all();
+    }
+
+    /// Queues the record, or applies the overflow policy if the queue is full.
+    /// Fails only once the receiving side is gone.
+    pub(crate) fn send(&self, level: log::Level, data: LogData) -> result::Result<(), ()> {
+        let mut state = self.shared.lock();
+        if state.receiver_closed || state.sender_closed {
+            return Err(());
+        }
+
+        if state.len() >= state.capacity {
+            match state.policy {
+                OverflowPolicy::DropNewest => {
+                    state.note_dropped();
+                    return Ok(());
+                }
+                OverflowPolicy::DropOldest => {
+                    state.pop_oldest();
+                    state.note_dropped();
+                }
+                OverflowPolicy::Block => {
+                    state = self
+                        .shared
+                        .not_full
+                        .wait_while(state, |s| {
+                            s.len() >= s.capacity && !s.receiver_closed && !s.sender_closed
+                        })
+                        .unwrap_or_else(sync::PoisonError::into_inner);
+                    if state.receiver_closed || state.sender_closed {
+                        return Err(());
+                    }
+                }
+                OverflowPolicy::KeepErrorsAndWarnings => {
+                    if level > log::Level::Warn {
+                        state.note_dropped();
+                        return Ok(());
+                    }
+                    if state.queues[THE_REST].pop_front().is_some() {
+                        state.note_dropped();
+                    } else if state.len() >= state.capacity.saturating_mul(KEEP_LIMIT) {
+                        // a new warning doesn't push out an error
+                        let queue = match (state.queues[WARNINGS].is_empty(), level) {
+                            (false, _) => WARNINGS,
+                            (true, log::Level::Warn) => {
+                                state.note_dropped();
+                                return Ok(());
+                            }
+                            (true, _) => ERRORS,
+                        };
+                        state.queues[queue].pop_front();
+                        state.note_dropped();
+                    }
+                }
+            }
+        }
+
+        state.push(level, data);
+        drop(state);
+        self.shared.waker.wake();
+        Ok(())
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        self.shared.lock().sender_closed = true;
+        self.shared.waker.wake();
+    }
+}
+
+/// Stops the sender from taking any more records, after which the receiver ends once it has
+/// yielded the ones already queued
+pub(crate) struct Closer {
+    shared: sync::Arc<Shared>,
+}
+
+impl Closer {
+    pub(crate) fn close(&self) {
+        self.shared.lock().sender_closed = true;
+        self.shared.not_full.notify_all();
+        self.shared.waker.wake();
+    }
+}
+
+/// Yields the queued records, along with a warning whenever some had to be dropped
+pub(crate) struct Receiver {
+    shared: sync::Arc<Shared>,
+}
+
+impl futures_util::Stream for Receiver {
+    type Item = (log::Level, LogData);
+
+    fn poll_next(
+        self: pin::Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> task::Poll<Option<Self::Item>> {
+        // registered first, so a record sent after the queue is checked still wakes us
+        self.shared.waker.register(cx.waker());
+        let mut state = self.shared.lock();
+
+        // reported once the storm is over, or every so often while it lasts
+        let report_due = state
+            .dropping_since
+            .is_some_and(|since| state.len() == 0 || since <= now() - REPORT_INTERVAL);
+        if report_due {
+            let dropped = std::mem::take(&mut state.dropped);
+            state.dropping_since = None;
+            return task::Poll::Ready(Some((log::Level::Warn, dropped_record(dropped))));
+        }
+
+        match state.pop_oldest() {
+            Some(record) => {
+                drop(state);
+                self.shared.not_full.notify_one();
+                task::Poll::Ready(Some(record))
+            }
+            None if state.sender_closed => task::Poll::Ready(None),
+            None => task::Poll::Pending,
+        }
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.shared.lock().receiver_closed = true;
+        self.shared.not_full.notify_all();
+    }
+}
+
+fn dropped_record(dropped: u64) -> LogData {
+    LogData {
+        message: format!(
+            "{} log messages were dropped as the subscriber's queue was full",
+            dropped
+        ),
+        code_module: None,
+        code_line: None,
+        code_file: None,
+        tags: [
+            ("target".to_string(), "eigenlog".to_string()),
+            ("dropped".to_string(), dropped.to_string()),
+        ]
+        .into_iter()
+        .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+
+    fn record(message: &str) -> LogData {
+        LogData {
+            message: message.to_string(),
+            code_module: None,
+            code_line: None,
+            code_file: None,
+            tags: collections::HashMap::new(),
+        }
+    }
+
+    async fn drain(receiver: &mut Receiver, count: usize) -> Vec<String> {
+        let mut messages = Vec::new();
+        for _ in 0..count {
+            let (_, data) = receiver.next().await.unwrap();
+            messages.push(data.message);
+        }
+        messages
+    }
+
+    #[tokio::test]
+    async fn test_overflow_policies() {
+        let (sender, mut receiver, closer) = bounded();
+        sender.set_capacity(2, OverflowPolicy::DropNewest);
+        for message in ["a", "b", "c"] {
+            sender.send(log::Level::Info, record(message)).unwrap();
+        }
+        let messages = drain(&mut receiver, 3).await;
+        assert_eq!(messages[..2], ["a", "b"]);
+        assert!(messages[2].starts_with("1 log messages were dropped"));
+
+        sender.set_capacity(2, OverflowPolicy::DropOldest);
+        for message in ["a", "b", "c"] {
+            sender.send(log::Level::Info, record(message)).unwrap();
+        }
+        // the report waits until the interval is up, or the queue has been emptied
+        assert_eq!(drain(&mut receiver, 2).await, ["b", "c"]);
+        assert!(drain(&mut receiver, 1).await[0].starts_with("1 log"));
+
+        sender.set_capacity(2, OverflowPolicy::KeepErrorsAndWarnings);
+        sender.send(log::Level::Info, record("a")).unwrap();
+        sender.send(log::Level::Error, record("b")).unwrap();
+        sender.send(log::Level::Debug, record("c")).unwrap();
+        sender.send(log::Level::Warn, record("d")).unwrap();
+        sender.send(log::Level::Error, record("e")).unwrap();
+        assert_eq!(drain(&mut receiver, 3).await, ["b", "d", "e"]);
+        assert!(drain(&mut receiver, 1).await[0].starts_with("2 log"));
+
+        // errors and warnings are only kept up to twice the capacity
+        sender.send(log::Level::Error, record("a")).unwrap();
+        sender.send(log::Level::Warn, record("b")).unwrap();
+        sender.send(log::Level::Error, record("c")).unwrap();
+        sender.send(log::Level::Error, record("d")).unwrap();
+        sender.send(log::Level::Error, record("e")).unwrap();
+        // with no warnings left, new warnings are dropped rather than older errors
+        sender.send(log::Level::Warn, record("f")).unwrap();
+        sender.send(log::Level::Error, record("g")).unwrap();
+        assert_eq!(drain(&mut receiver, 4).await, ["c", "d", "e", "g"]);
+        assert!(drain(&mut receiver, 1).await[0].starts_with("3 log"));
+
+        // a blocked caller continues once there's room
+        sender.set_capacity(1, OverflowPolicy::Block);
+        sender.send(log::Level::Info, record("a")).unwrap();
+        let blocked = std::thread::spawn(move || {
+            sender.send(log::Level::Info, record("b")).unwrap();
+            sender
+        });
+        assert_eq!(drain(&mut receiver, 2).await, ["a", "b"]);
+
+        let sender = blocked.join().unwrap();
+
+        // once closed, queued records are still received but no new ones are taken
+        sender.send(log::Level::Info, record("c")).unwrap();
+        closer.close();
+        assert!(sender.send(log::Level::Info, record("d")).is_err());
+        assert_eq!(drain(&mut receiver, 1).await, ["c"]);
+        assert!(receiver.next().await.is_none());
+    }
+}
@@ -1,5 +1,5 @@
 use super::*;
-use futures_util::{future, StreamExt};
+use futures_util::{future, FutureExt, StreamExt};
 
 impl Subscriber {
     pub fn new_local<S>(
@@ -8,27 +8,38 @@ impl Subscriber {
         app: App,
         level: log::LevelFilter,
         storage: S,
-    ) -> (Subscriber, DataSaver<S>)
+        cache_limit: CacheLimit,
+    ) -> (Subscriber, DataSaver<S>, ShutdownHandle)
     where
         S: storage::Storage,
     {
-        let (tx1, rx1) = mpsc::unbounded();
+        let (tx1, rx1, closer) = channel::bounded();
         let (tx2, rx2) = mpsc::unbounded();
+        let (shutdown_handle, shutdown) = ShutdownHandle::new(closer);
 
         (
             Subscriber {
                 sender: tx1,
                 flush_requester: tx2,
+                flush_timeout: DEFAULT_FLUSH_TIMEOUT,
                 on_result,
                 level,
+                drop_delay: None,
             },
             DataSaver {
                 receiver: rx1,
                 flush_request: rx2,
+                shutdown: Some(shutdown),
                 host,
                 app,
                 storage,
+                cache_limit,
+                cache: Default::default(),
+                cached_bytes: 0,
+                stderr_on_drop: true,
+                generator: ulid::Generator::new(),
             },
+            shutdown_handle,
         )
     }
 }
@@ -37,57 +48,402 @@ pub struct DataSaver<S>
 where
     S: storage::Storage,
 {
-    receiver: mpsc::UnboundedReceiver<(log::Level, LogData)>,
+    receiver: channel::Receiver,
 
-    flush_request: mpsc::UnboundedReceiver<std::sync::mpsc::SyncSender<()>>,
+    flush_request: mpsc::UnboundedReceiver<FlushRequest>,
+
+    // `None` once the handle has been dropped without shutting down
+    shutdown: Option<oneshot::Receiver<ShutdownRequest>>,
 
     host: Host,
 
     app: App,
 
     storage: S,
+
+    cache_limit: CacheLimit,
+
+    cache: collections::HashMap<log::Level, LogBatch>,
+
+    // the approximate size of everything in the cache, checked against `CacheLimit::max_bytes`
+    cached_bytes: usize,
+
+    // whether logs which are still cached when dropped are printed
+    stderr_on_drop: bool,
+
+    // kept for the life of the saver, so records logged in the same millisecond stay in order
+    generator: ulid::Generator,
+}
+
+impl<S> Drop for DataSaver<S>
+where
+    S: storage::Storage,
+{
+    fn drop(&mut self) {
+        if !self.stderr_on_drop {
+            return;
+        }
+        for (level, logs) in std::mem::take(&mut self.cache) {
+            for (id, data) in logs {
+                eprintln!("[{} {}]: {}", id.datetime(), level, data.message)
+            }
+        }
+    }
+}
+
+/// What woke the saver up
+enum Wake {
+    Message(Option<(log::Level, LogData)>),
+    Flush(Option<FlushRequest>),
+    Timer,
+    Shutdown(result::Result<ShutdownRequest, oneshot::Canceled>),
 }
 
 impl<S> DataSaver<S>
 where
     S: storage::Storage,
 {
+    /// Whether logs which couldn't be saved by the time the saver is dropped are printed to
+    /// stderr, which is the default
+    pub fn with_stderr_on_drop(mut self, stderr_on_drop: bool) -> DataSaver<S> {
+        self.stderr_on_drop = stderr_on_drop;
+        self
+    }
+
     pub async fn run_forever<OnError>(mut self, mut func: OnError)
     where
         OnError: FnMut(Error),
     {
         loop {
-            if let Err(e) = self.run().await {
-                func(e)
+            match self.run().await {
+                Ok(()) => break,
+                Err(e) => func(e),
             }
         }
     }
 
+    /// Saves records until an error, or returns `Ok` once shut down by the [`ShutdownHandle`]
+    /// or once both the subscriber and the handle are dropped
     pub async fn run(&mut self) -> Result<()> {
-        let DataSaver {
-            receiver,
-            flush_request,
-            ref storage,
-            ref host,
-            app,
-        } = self;
-
         loop {
-            match future::select(receiver.next(), flush_request.next()).await {
-                future::Either::Left((Some((level, data)), _)) => {
-                    let mut generator = ulid::Generator::new();
-                    let mut batch = collections::BTreeMap::new();
-                    batch.insert(generator.generate()?, data);
-                    storage.submit(host, app, level.into(), batch).await?;
+            // records are saved a batch at a time, once a batch is full or old enough
+            let timer = match self.next_wake() {
+                Some(wait) => {
+                    future::Either::Left(futures_timer::Delay::new(wait).map(|_| Wake::Timer))
+                }
+                None => future::Either::Right(future::pending()),
+            };
+            let shutdown = match self.shutdown.as_mut() {
+                Some(shutdown) => future::Either::Left(shutdown.map(Wake::Shutdown)),
+                None => future::Either::Right(future::pending()),
+            };
+            let message = self.receiver.next().map(Wake::Message);
+            let flush = self.flush_request.next().map(Wake::Flush);
+
+            match first(first(message, flush), first(timer, shutdown)).await {
+                Wake::Message(Some((level, data))) => {
+                    self.add_to_cache(level, data)?;
+                    self.save_due(false).await?;
+                }
+                Wake::Flush(Some(request)) => {
+                    // records logged before the flush may still be queued
+                    while let Some(Some((level, data))) = self.receiver.next().now_or_never() {
+                        self.add_to_cache(level, data)?;
+                    }
+                    self.save_due(true).await?;
+                    self.storage.flush(&self.host, &self.app).await?;
+                    request.done.send(())?;
+                }
+                Wake::Timer => self.save_due(false).await?,
+                Wake::Message(None) | Wake::Flush(None) => {
+                    // either the subscriber was dropped or the handle closed the channel
+                    let pending = self
+                        .shutdown
+                        .as_mut()
+                        .and_then(|s| s.try_recv().ok().flatten());
+                    if let Some(request) = pending {
+                        return self.shut_down(request).await;
+                    }
+                    self.save_due(true).await?;
+                    // the subscriber is gone, but the handle may still ask for a report
+                    if let Some(shutdown) = self.shutdown.as_mut() {
+                        match shutdown.await {
+                            Ok(request) => return self.shut_down(request).await,
+                            Err(oneshot::Canceled) => self.shutdown = None,
+                        }
+                    }
+                    // nothing can log or ask for a shutdown any more, so the saver is done
+                    return Ok(());
                 }
-                future::Either::Right((Some(sender), _)) => {
-                    storage.flush(host, app).await?;
-                    sender.send(())?;
+                Wake::Shutdown(Ok(request)) => return self.shut_down(request).await,
+                Wake::Shutdown(Err(oneshot::Canceled)) => self.shutdown = None,
+            }
+        }
+    }
+
+    fn add_to_cache(&mut self, level: log::Level, data: LogData) -> Result<()> {
+        self.cached_bytes += approximate_size(&data);
+        self.cache
+            .entry(level)
+            .or_default()
+            .insert(self.generator.generate()?, data);
+        Ok(())
+    }
+
+    /// How long until the oldest cached batch is due
+    fn next_wake(&self) -> Option<time::Duration> {
+        let expires_at = self
+            .cache
+            .iter()
+            .filter_map(|(level, batch)| self.cache_limit.expires_at(*level, batch))
+            .min()?;
+        Some(until(expires_at))
+    }
+
+    /// Saves the levels which are due, or every level once the cache is over its size
+    async fn save_due(&mut self, all: bool) -> Result<()> {
+        let now = now();
+        let all = all || self.cached_bytes >= self.cache_limit.max_bytes;
+        let due = self
+            .cache
+            .iter()
+            .filter(|(level, batch)| all || self.cache_limit.is_due(**level, batch, now))
+            .map(|(level, _)| *level)
+            .collect::<Vec<_>>();
+        for level in due {
+            if let Some(batch) = self.take_level(level) {
+                let submitted = self
+                    .storage
+                    .submit(&self.host, &self.app, level.into(), batch.clone())
+                    .await;
+                if let Err(e) = submitted {
+                    // kept to be saved again along with the next batch
+                    self.return_level(level, batch);
+                    return Err(e);
                 }
-                future::Either::Left((None, _)) | future::Either::Right((None, _)) => {
-                    return Err(Error::LogSubscriberClosed)
+            }
+        }
+        Ok(())
+    }
+
+    fn take_level(&mut self, level: log::Level) -> Option<LogBatch> {
+        let batch = self.cache.remove(&level)?;
+        self.cached_bytes = self
+            .cached_bytes
+            .saturating_sub(batch.values().map(approximate_size).sum());
+        Some(batch)
+    }
+
+    fn return_level(&mut self, level: log::Level, batch: LogBatch) {
+        self.cached_bytes += batch.values().map(approximate_size).sum::<usize>();
+        self.cache.entry(level).or_default().extend(batch);
+    }
+
+    async fn shut_down(&mut self, request: ShutdownRequest) -> Result<()> {
+        self.shutdown = None;
+        let report = self.drain(request.deadline).await;
+        request.reply(report);
+        Ok(())
+    }
+
+    /// Saves what is cached or still queued, giving up on the rest at the deadline
+    async fn drain(&mut self, deadline: Timestamp) -> ShutdownReport {
+        let mut report = ShutdownReport::default();
+        while let Some(Some((level, data))) = self.receiver.next().now_or_never() {
+            if let Err(e) = self.add_to_cache(level, data.clone()) {
+                eprintln!("Error caching log message during shutdown: {}", e);
+                report.undelivered.push((level, data));
+            }
+        }
+
+        let levels = self.cache.keys().copied().collect::<Vec<_>>();
+        for level in levels {
+            let batch = match self.take_level(level) {
+                Some(batch) => batch,
+                None => continue,
+            };
+            if now() >= deadline {
+                report
+                    .undelivered
+                    .extend(batch.into_values().map(|data| (level, data)));
+                continue;
+            }
+            let submitted = self
+                .storage
+                .submit(&self.host, &self.app, level.into(), batch.clone())
+                .await;
+            if let Err(e) = submitted {
+                eprintln!("Error saving log messages during shutdown: {}", e);
+                report
+                    .undelivered
+                    .extend(batch.into_values().map(|data| (level, data)));
+            }
+        }
+
+        if let Err(e) = self.storage.flush(&self.host, &self.app).await {
+            eprintln!("Error flushing logs during shutdown: {}", e);
+        }
+        report
+    }
+}
+
+#[cfg(all(test, feature = "sled"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_batches_keep_order() {
+        let storage = sled::Config::new().temporary(true).open().unwrap();
+        let host = "host".parse::<Host>().unwrap();
+        let app = "app".parse::<App>().unwrap();
+        let (subscriber, mut data_saver, shutdown_handle) = Subscriber::new_local(
+            Box::new(|_| {}),
+            host.clone(),
+            app.clone(),
+            log::LevelFilter::Trace,
+            storage.clone(),
+            CacheLimit::default(),
+        );
+        for i in 0..250 {
+            log::Log::log(
+                &subscriber,
+                &log::Record::builder()
+                    .level(log::Level::Trace)
+                    .args(format_args!("{}", i))
+                    .build(),
+            );
+        }
+
+        let shutdown = shutdown_handle.shutdown(time::Duration::from_secs(5));
+        let (saved, report) = future::join(data_saver.run(), shutdown).await;
+        saved.unwrap();
+        assert!(report.unwrap().undelivered.is_empty());
+
+        // logged within a few milliseconds, but read back in the order they were logged
+        let tree = storage
+            .open_tree(Level::Trace.get_tree_name(&host, &app))
+            .unwrap();
+        let messages = tree
+            .iter()
+            .values()
+            .map(|v| {
+                bincode_crate::deserialize::<LogData>(&v.unwrap())
+                    .unwrap()
+                    .message
+            })
+            .collect::<Vec<_>>();
+        let expected = (0..250).map(|i| i.to_string()).collect::<Vec<_>>();
+        assert_eq!(messages, expected);
+    }
+
+    #[tokio::test]
+    async fn test_failed_batch_is_kept() {
+        // fails the first submission only
+        #[derive(Clone)]
+        struct FailOnce(sled::Db, sync::Arc<sync::atomic::AtomicBool>);
+
+        #[async_trait::async_trait]
+        impl storage::Storage for FailOnce {
+            async fn submit(
+                &self,
+                host: &Host,
+                app: &App,
+                level: Level,
+                log_batch: LogBatch,
+            ) -> Result<()> {
+                if !self.1.swap(true, sync::atomic::Ordering::SeqCst) {
+                    return Err(Error::Custom("unavailable".to_string()));
                 }
+                self.0.submit(host, app, level, log_batch).await
+            }
+
+            async fn query(
+                &self,
+                params: QueryParams,
+                scope: &storage::Scope,
+            ) -> Result<Vec<QueryResponse>> {
+                self.0.query(params, scope).await
+            }
+
+            async fn detail(&self, host: &Host, app: &App, level: Level) -> Result<LogTreeDetail> {
+                self.0.detail(host, app, level).await
+            }
+
+            async fn info(
+                &self,
+            ) -> Result<Vec<result::Result<LogTreeInfo, ParseLogTreeInfoError>>> {
+                self.0.info().await
+            }
+
+            async fn flush(&self, host: &Host, app: &App) -> Result<()> {
+                self.0.flush(host, app).await
             }
         }
+
+        let storage = sled::Config::new().temporary(true).open().unwrap();
+        let host = "host".parse::<Host>().unwrap();
+        let app = "app".parse::<App>().unwrap();
+        let (subscriber, mut data_saver, shutdown_handle) = Subscriber::new_local(
+            Box::new(|_| {}),
+            host.clone(),
+            app.clone(),
+            log::LevelFilter::Trace,
+            FailOnce(storage.clone(), Default::default()),
+            CacheLimit::default(),
+        );
+        log::Log::log(
+            &subscriber,
+            &log::Record::builder()
+                .level(log::Level::Error)
+                .args(format_args!("kept"))
+                .build(),
+        );
+
+        // the error is due straight away, and is still cached after failing to save
+        assert!(data_saver.run().await.is_err());
+        assert_eq!(data_saver.cache[&log::Level::Error].len(), 1);
+        assert!(data_saver.cached_bytes > 0);
+
+        let shutdown = shutdown_handle.shutdown(time::Duration::from_secs(5));
+        let (saved, report) = future::join(data_saver.run(), shutdown).await;
+        saved.unwrap();
+        assert!(report.unwrap().undelivered.is_empty());
+        let tree = storage
+            .open_tree(Level::Error.get_tree_name(&host, &app))
+            .unwrap();
+        assert_eq!(tree.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stops_once_everything_dropped() {
+        let storage = sled::Config::new().temporary(true).open().unwrap();
+        let host = "host".parse::<Host>().unwrap();
+        let app = "app".parse::<App>().unwrap();
+        let (subscriber, data_saver, shutdown_handle) = Subscriber::new_local(
+            Box::new(|_| {}),
+            host.clone(),
+            app.clone(),
+            log::LevelFilter::Trace,
+            storage.clone(),
+            CacheLimit::default(),
+        );
+        log::Log::log(
+            &subscriber,
+            &log::Record::builder()
+                .level(log::Level::Trace)
+                .args(format_args!("last"))
+                .build(),
+        );
+        drop(subscriber);
+        drop(shutdown_handle);
+
+        // returns rather than reporting the closed subscriber over and over
+        let mut errors = Vec::new();
+        data_saver.run_forever(|e| errors.push(e.to_string())).await;
+        assert!(errors.is_empty(), "{:?}", errors);
+        let tree = storage
+            .open_tree(Level::Trace.get_tree_name(&host, &app))
+            .unwrap();
+        assert_eq!(tree.len(), 1);
     }
 }
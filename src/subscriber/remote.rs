@@ -1,8 +1,8 @@
 use super::*;
-use futures_channel::mpsc;
-use futures_util::{future, StreamExt, TryFutureExt};
+use futures_channel::{mpsc, oneshot};
+use futures_util::{future, stream, stream::FusedStream, FutureExt, StreamExt, TryFutureExt};
 use reqwest::header;
-use std::{ops, pin};
+use std::{ops, pin, time};
 
 impl Subscriber {
     pub fn new_remote<T>(
@@ -12,43 +12,127 @@ impl Subscriber {
         app: App,
         level: log::LevelFilter,
         cache_limit: CacheLimit,
-    ) -> (Subscriber, DataSender<T>)
+    ) -> (Subscriber, DataSender<T>, ShutdownHandle)
     where
         T: ConnectionProxy,
     {
-        let (tx1, rx1) = mpsc::unbounded();
+        let (tx1, rx1, closer) = channel::bounded();
         let (tx2, rx2) = mpsc::unbounded();
+        let (shutdown_handle, shutdown) = ShutdownHandle::new(closer);
 
         (
             Subscriber {
                 sender: tx1,
                 flush_requester: tx2,
+                flush_timeout: DEFAULT_FLUSH_TIMEOUT,
                 on_result,
                 level,
+                drop_delay: None,
             },
             DataSender {
                 receiver: rx1,
+                receiver_done: false,
+                draining: false,
                 flush_request: rx2,
+                shutdown: Some(shutdown),
+                shutting_down: None,
+                stderr_on_drop: true,
                 api_config,
                 host,
                 app,
                 cache_limit,
                 cache: Default::default(),
-                sender: None,
+                cached_bytes: 0,
+                in_flight: Default::default(),
+                in_flight_batches: Default::default(),
+                in_flight_levels: Default::default(),
+                spill_in_flight: false,
+                spill_next: None,
+                spill_read: None,
+                max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+                flush_waiters: Vec::new(),
+                retries: Default::default(),
+                retry_policy: Default::default(),
+                spill: None,
+                paused_until: None,
                 generator: ulid::Generator::new(),
             },
+            shutdown_handle,
         )
     }
 }
 
+// submissions which may be in flight at once, unless changed with `with_max_in_flight`
+const DEFAULT_MAX_IN_FLIGHT: usize = 4;
+
+/// How failed sends are retried. Each attempt waits twice as long as the one before, up to
+/// `max_backoff`, with up to half of the wait randomised so that restarted servers aren't
+/// hit by every client at once.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// a batch is dropped once it has failed this many times
+    pub max_attempts: u32,
+    pub initial_backoff: time::Duration,
+    pub max_backoff: time::Duration,
+}
+
+impl RetryPolicy {
+    /// How long to wait before the given attempt, counting the first retry as 1
+    fn backoff(&self, attempt: u32) -> time::Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff);
+        // the random part of a new ulid is as good a source of jitter as any
+        let jitter = (ulid::Ulid::new().random() % 1024) as u32;
+        backoff / 2 + backoff / 2 * jitter / 1024
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: time::Duration::from_millis(500),
+            max_backoff: time::Duration::from_secs(60),
+        }
+    }
+}
+
+/// The streams of one request, kept together with their batch id so that a retry can be
+/// recognised by the server
+struct Batch {
+    id: ulid::Ulid,
+    streams: Vec<StreamBatch>,
+    attempts: u32,
+    // read from the spill buffer, where it stays until the server has it
+    spilled: bool,
+}
+
 #[must_use]
 pub struct DataSender<T>
 where
     T: ConnectionProxy + 'static,
 {
-    receiver: mpsc::UnboundedReceiver<(log::Level, LogData)>,
+    receiver: channel::Receiver,
 
-    flush_request: mpsc::UnboundedReceiver<std::sync::mpsc::SyncSender<()>>,
+    // set once every record has been taken off the receiver for the shutdown
+    receiver_done: bool,
+
+    // set once the subscriber is dropped without a shutdown, everything left is then sent
+    // before the sender stops, however long that takes
+    draining: bool,
+
+    flush_request: mpsc::UnboundedReceiver<FlushRequest>,
+
+    // `None` once the handle has been dropped without shutting down
+    shutdown: Option<oneshot::Receiver<ShutdownRequest>>,
+
+    // the shutdown in progress, along with what couldn't be delivered so far
+    shutting_down: Option<(ShutdownRequest, ShutdownReport)>,
+
+    // whether logs which are still cached when dropped are printed, without a spill buffer
+    stderr_on_drop: bool,
 
     api_config: ApiConfig<T>,
 
@@ -60,7 +144,45 @@ where
 
     cache: collections::HashMap<log::Level, collections::BTreeMap<ulid::Ulid, LogData>>,
 
-    sender: Option<pin::Pin<Box<dyn futures_util::Future<Output = Result<()>>>>>,
+    // the approximate size of everything in the cache, checked against `CacheLimit::max_bytes`
+    cached_bytes: usize,
+
+    in_flight:
+        stream::FuturesUnordered<pin::Pin<Box<dyn futures_util::Future<Output = SendResult>>>>,
+
+    // the batches in flight by id, kept here so they can be spilled if the sender stops
+    // before their sends complete
+    in_flight_batches: collections::HashMap<ulid::Ulid, Batch>,
+
+    // a level is only in one submission at a time, so its logs arrive in order
+    in_flight_levels: collections::HashSet<Level>,
+
+    // the oldest spilled batch stays on disk while it is sent, so it isn't read twice
+    spill_in_flight: bool,
+
+    // the oldest spilled batch, once read, until its levels are free to send it
+    spill_next: Option<Batch>,
+
+    // the oldest spilled batch being read, off this task
+    spill_read: Option<pin::Pin<Box<dyn futures_util::Future<Output = SpillRead>>>>,
+
+    max_in_flight: usize,
+
+    // flushes waiting for everything logged before them to be sent, until then the cache
+    // is sent regardless of its limits
+    flush_waiters: Vec<FlushRequest>,
+
+    // batches which failed to send, oldest first, these go out before anything new
+    retries: collections::VecDeque<Batch>,
+
+    retry_policy: RetryPolicy,
+
+    // batches which couldn't be sent are written here, rather than kept in memory or dropped
+    spill: Option<SpillBuffer>,
+
+    // set when the server asks us to back off or a send is being retried,
+    // no batches are sent until then
+    paused_until: Option<Timestamp>,
 
     generator: ulid::Generator,
 }
@@ -70,7 +192,26 @@ where
     T: ConnectionProxy + 'static,
 {
     fn drop(&mut self) {
-        eprintln!("Dropping DataSender");
+        // the sends in flight stop along with their futures
+        self.in_flight_levels.clear();
+        let in_flight = std::mem::take(&mut self.in_flight_batches);
+        if self.spill.is_some() {
+            let retries = std::mem::take(&mut self.retries);
+            let unsent = in_flight
+                .into_values()
+                // batches read from the spill buffer are still on disk
+                .filter(|batch| !batch.spilled)
+                .chain(retries)
+                .chain(self.take_cache());
+            for batch in unsent.collect::<Vec<_>>() {
+                self.spill_batch(batch);
+            }
+            return;
+        }
+        if !self.stderr_on_drop {
+            return;
+        }
+
         let cache = std::mem::take(&mut self.cache);
 
         for (level, logs) in cache {
@@ -78,6 +219,14 @@ where
                 eprintln!("[{} {}]: {}", id.datetime(), level, data.message)
             }
         }
+        for stream in std::mem::take(&mut self.retries)
+            .into_iter()
+            .flat_map(|b| b.streams)
+        {
+            for (id, data) in stream.batch {
+                eprintln!("[{} {}]: {}", id.datetime(), stream.level, data.message)
+            }
+        }
     }
 }
 
@@ -85,117 +234,808 @@ impl<T> DataSender<T>
 where
     T: ConnectionProxy + 'static,
 {
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> DataSender<T> {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Batches which can't be sent are kept in the spill buffer from now on, and any it holds
+    /// from earlier runs are sent before new logs
+    pub fn with_spill(mut self, spill: SpillBuffer) -> DataSender<T> {
+        self.spill = Some(spill);
+        self
+    }
+
+    /// How many submissions may be waiting on the server at once, at least 1
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> DataSender<T> {
+        self.max_in_flight = max_in_flight.max(1);
+        self
+    }
+
+    /// Whether logs which couldn't be sent by the time the sender is dropped are printed to
+    /// stderr, which is the default. With a spill buffer they are written to it instead.
+    pub fn with_stderr_on_drop(mut self, stderr_on_drop: bool) -> DataSender<T> {
+        self.stderr_on_drop = stderr_on_drop;
+        self
+    }
+
+    /// Sends logs until shut down by the [`ShutdownHandle`], or until the subscriber is
+    /// dropped and everything logged before has been sent
     pub async fn run(mut self) {
-        // get message and try to send
-        // always select on both and do the next
         loop {
             if let ops::ControlFlow::Break(()) = self.run_once().await {
-                eprintln!("DataSender<T>'s channel has been hung up, exiting...");
+                if self.shutdown.is_some() {
+                    eprintln!("DataSender<T>'s channel has been hung up, exiting...");
+                }
                 break;
             }
         }
     }
 
-    fn add_to_cache(&mut self, msg: Option<(log::Level, LogData)>) -> Option<()> {
-        if let Some((level, data)) = msg {
-            let mut batch = self.cache.remove(&level).unwrap_or_default();
+    fn add_to_cache(&mut self, level: log::Level, data: LogData) {
+        self.cached_bytes += approximate_size(&data);
+        self.cache
+            .entry(level)
+            .or_default()
+            .insert(self.generator.generate().unwrap(), data);
+    }
 
-            batch.insert(self.generator.generate().unwrap(), data);
+    /// How long until a batch is due, the pause is over or the shutdown deadline, if anything
+    /// is waiting on a timer
+    fn next_wake(&self) -> Option<time::Duration> {
+        let deadline = self
+            .shutting_down
+            .as_ref()
+            .map(|(request, _)| until(request.deadline));
+        [deadline, self.next_send()].into_iter().flatten().min()
+    }
 
-            self.cache.insert(level, batch);
-            None
-        } else {
-            Some(())
+    fn next_send(&self) -> Option<time::Duration> {
+        // a send completing wakes us up, so there's no need for a timer while all slots are taken
+        if self.in_flight.len() >= self.max_in_flight {
+            return None;
         }
+        if let Some(paused_until) = self.paused_until {
+            return Some(until(paused_until));
+        }
+        let expires_at = self
+            .cache
+            .iter()
+            .filter(|(level, _)| !self.in_flight_levels.contains(&(**level).into()))
+            .filter_map(|(level, batch)| self.cache_limit.expires_at(*level, batch))
+            .min()?;
+        Some(until(expires_at))
     }
 
     async fn run_once(&mut self) -> ops::ControlFlow<()> {
-        // check if we have an existing send future that needs polling
-        match self.sender.take() {
-            // if we have an existing send future, poll it while also polling the receiver
-            // this ensures that the send is progressed, while also receiving new log messages
-            Some(fut) => match future::select(Box::pin(self.receiver.next()), fut).await {
-                future::Either::Left((msg, send_fut)) => {
-                    // sender is still in progress, so place it back to be polled again
-                    self.sender = Some(send_fut);
-
-                    if let Some((level, data)) = msg {
-                        let mut batch = self.cache.remove(&level).unwrap_or_default();
-
-                        batch.insert(self.generator.generate().unwrap(), data);
-
-                        self.cache.insert(level, batch);
-                        // once we have added to the cache we want to exit the fn
-                        // and wait again on ether the log message or send completing.
-                        return ops::ControlFlow::Continue(());
-                    } else {
-                        return ops::ControlFlow::Break(());
+        // wait for whichever comes first: a new log message, a flush, a send in progress
+        // completing, a batch becoming old enough to be sent or the shutdown
+        let timer = match self.next_wake() {
+            Some(wait) => {
+                future::Either::Left(futures_timer::Delay::new(wait).map(|_| Wake::Timer))
+            }
+            None => future::Either::Right(future::pending()),
+        };
+        let message = match self.receiver_done {
+            false => future::Either::Left(self.receiver.next().map(Wake::Message)),
+            true => future::Either::Right(future::pending()),
+        };
+        // flushes end with the subscriber, while its last records may still be queued
+        let flush = match self.flush_request.is_terminated() {
+            false => future::Either::Left(self.flush_request.next().map(Wake::Flush)),
+            true => future::Either::Right(future::pending()),
+        };
+        let sent = match self.in_flight.is_empty() {
+            false => future::Either::Left(self.in_flight.next().map(Wake::Sent)),
+            true => future::Either::Right(future::pending()),
+        };
+        let shutdown = match self.shutdown.as_mut() {
+            Some(shutdown) => future::Either::Left(shutdown.map(Wake::Shutdown)),
+            None => future::Either::Right(future::pending()),
+        };
+        let spill_read = match self.spill_read.as_mut() {
+            Some(read) => future::Either::Left(read.map(Wake::SpillRead)),
+            None => future::Either::Right(future::pending()),
+        };
+
+        let woken = first(
+            first(message, flush),
+            first(sent, first(timer, first(shutdown, spill_read))),
+        );
+        match woken.await {
+            Wake::Message(Some((level, data))) => self.add_to_cache(level, data),
+            // either the subscriber was dropped or the handle closed the channel
+            Wake::Message(None) => match self.pending_shutdown() {
+                Some(request) => self.begin_shutdown(request),
+                None => {
+                    self.receiver_done = true;
+                    self.draining = true;
+                }
+            },
+            Wake::Flush(Some(request)) => {
+                // records logged before the flush may still be queued
+                self.drain_receiver();
+                self.flush_waiters.push(request);
+            }
+            Wake::Flush(None) | Wake::Sent(None) | Wake::Timer => {}
+            Wake::Sent(Some((id, completed_send))) => self.handle_send_result(id, completed_send),
+            Wake::Shutdown(Ok(request)) => self.begin_shutdown(request),
+            Wake::Shutdown(Err(oneshot::Canceled)) => self.shutdown = None,
+            Wake::SpillRead((id, read)) => {
+                self.spill_read = None;
+                match read {
+                    Ok(streams) => {
+                        self.spill_next = Some(Batch {
+                            id,
+                            streams,
+                            attempts: 0,
+                            spilled: true,
+                        })
                     }
+                    Err(e) => {
+                        eprintln!("Error reading spilled batch, dropping it: {}", e);
+                        self.finish(id, true);
+                    }
+                }
+            }
+        }
+
+        // flushes which timed out have stopped waiting for a reply
+        let now = now();
+        self.flush_waiters.retain(|request| request.deadline > now);
+
+        self.start_sends();
+
+        let drained = self.in_flight.is_empty() && self.retries.is_empty() && self.cache.is_empty();
+        if drained {
+            for request in self.flush_waiters.drain(..) {
+                // the flushing thread may have given up waiting
+                let _ = request.done.send(());
+            }
+        }
+
+        if let Some((request, _)) = &self.shutting_down {
+            if drained || request.deadline <= now {
+                self.finish_shutdown();
+                return ops::ControlFlow::Break(());
+            }
+        } else if self.draining && drained {
+            return ops::ControlFlow::Break(());
+        }
+
+        ops::ControlFlow::Continue(())
+    }
+
+    fn pending_shutdown(&mut self) -> Option<ShutdownRequest> {
+        self.shutdown.as_mut()?.try_recv().ok().flatten()
+    }
+
+    fn begin_shutdown(&mut self, request: ShutdownRequest) {
+        // the handle has closed the channel, so nothing more will be queued
+        self.shutdown = None;
+        self.drain_receiver();
+        self.receiver_done = true;
+        self.shutting_down = Some((request, ShutdownReport::default()));
+    }
+
+    fn drain_receiver(&mut self) {
+        while let Some(Some((level, data))) = self.receiver.next().now_or_never() {
+            self.add_to_cache(level, data);
+        }
+    }
+
+    /// Gives up on whatever is left, spilling it if possible, and replies to the shutdown
+    fn finish_shutdown(&mut self) {
+        // the submissions still in flight are abandoned along with their futures, and spilled
+        // if possible, the server recognises them by their batch id if they did arrive
+        self.in_flight = Default::default();
+        self.in_flight_levels.clear();
+        self.spill_in_flight = false;
+        self.spill_read = None;
+        let mut abandoned = 0;
+        for batch in std::mem::take(&mut self.in_flight_batches).into_values() {
+            match self.spill {
+                // batches read from the spill buffer are still on disk
+                Some(_) if batch.spilled => {}
+                Some(_) => self.spill_batch(batch),
+                None => abandoned += batch.streams.iter().map(|s| s.batch.len()).sum::<usize>(),
+            }
+        }
+
+        let retries = std::mem::take(&mut self.retries);
+        for batch in retries.into_iter().chain(self.take_cache()) {
+            match self.spill {
+                Some(_) => self.spill_batch(batch),
+                None => self.give_up(batch.streams, "at the shutdown deadline"),
+            }
+        }
+
+        if let Some((request, mut report)) = self.shutting_down.take() {
+            report.abandoned = abandoned;
+            request.reply(report);
+        }
+    }
+
+    /// Starts as many submissions as there are free slots for, oldest logs first
+    fn start_sends(&mut self) {
+        while self.in_flight.len() < self.max_in_flight {
+            let paused = self
+                .paused_until
+                .is_some_and(|paused_until| now() < paused_until);
+            if paused {
+                break;
+            }
+            self.paused_until = None;
+
+            // retries and spilled batches hold older logs than the cache, so nothing newer
+            // is sent while they wait for their levels to be free
+            if let Some(batch) = self.retries.front() {
+                if !self.levels_free(batch) {
+                    break;
                 }
-                future::Either::Right((completed_send, _)) => {
-                    if let Err(e) = completed_send {
-                        eprintln!("Error during sending of log message: {}", e);
+                let batch = self.retries.pop_front().unwrap();
+                self.send(batch);
+                continue;
+            }
+
+            if self.spill_in_flight || self.spill_read.is_some() {
+                break;
+            }
+            if let Some(spill) = self.spill.as_ref() {
+                if let Some(id) = spill.oldest_id() {
+                    match self.spill_next.take() {
+                        Some(batch) if batch.id == id => {
+                            if !self.levels_free(&batch) {
+                                self.spill_next = Some(batch);
+                                break;
+                            }
+                            self.send(batch);
+                            continue;
+                        }
+                        // either nothing was read yet, or the batch read is no longer the
+                        // oldest, as it was dropped for space or an older retry was spilled
+                        _ => {
+                            self.spill_read = Some(Box::pin(spill.read(id).map(move |r| (id, r))));
+                            break;
+                        }
                     }
                 }
-            },
-            None => {
-                if let Some((level, data)) = self.receiver.next().await {
-                    let mut batch = self.cache.remove(&level).unwrap_or_default();
+            }
+
+            // once any level is due, everything cached goes along in the same request,
+            // apart from the levels which are still in flight
+            let now = now();
+            let due = !self.flush_waiters.is_empty()
+                || self.shutting_down.is_some()
+                || self.draining
+                || self.cached_bytes >= self.cache_limit.max_bytes
+                || self.cache.iter().any(|(level, batch)| {
+                    !self.in_flight_levels.contains(&(*level).into())
+                        && self.cache_limit.is_due(*level, batch, now)
+                });
+            if !due {
+                break;
+            }
+            match self.take_cache() {
+                Some(batch) => self.send(batch),
+                None => break,
+            }
+        }
 
-                    batch.insert(self.generator.generate().unwrap(), data);
+        // the cache can't be sent for now, so move it to disk once it is full
+        if self.spill.is_some() && self.cached_bytes >= self.cache_limit.max_bytes {
+            if let Some(batch) = self.take_cache() {
+                self.spill_batch(batch);
+            }
+        }
+    }
 
-                    self.cache.insert(level, batch);
-                } else {
-                    return ops::ControlFlow::Break(());
+    fn levels_free(&self, batch: &Batch) -> bool {
+        batch
+            .streams
+            .iter()
+            .all(|s| !self.in_flight_levels.contains(&s.level))
+    }
+
+    /// The cached levels which aren't in flight as a new batch, `None` if there is nothing
+    /// to send
+    fn take_cache(&mut self) -> Option<Batch> {
+        let levels = self
+            .cache
+            .keys()
+            .filter(|level| !self.in_flight_levels.contains(&(**level).into()))
+            .copied()
+            .collect::<Vec<_>>();
+        let streams = levels
+            .into_iter()
+            .filter_map(|level| Some((level, self.cache.remove(&level)?)))
+            .filter(|(_, batch)| !batch.is_empty())
+            .map(|(level, batch)| StreamBatch {
+                host: self.host.clone(),
+                app: self.app.clone(),
+                level: level.into(),
+                batch,
+            })
+            .collect::<Vec<_>>();
+        self.cached_bytes = self
+            .cache
+            .values()
+            .flat_map(|batch| batch.values())
+            .map(approximate_size)
+            .sum();
+        if streams.is_empty() {
+            return None;
+        }
+        Some(Batch {
+            id: ulid::Ulid::new(),
+            streams,
+            attempts: 0,
+            spilled: false,
+        })
+    }
+
+    fn spill_batch(&mut self, batch: Batch) {
+        let format = self.api_config.serialization_format;
+        let stored = match self.spill.as_mut() {
+            Some(spill) => spill.store(batch.id, format, &batch.streams),
+            None => return,
+        };
+        match stored {
+            Ok(()) => {
+                if let Some((_, report)) = self.shutting_down.as_mut() {
+                    report.spilled += batch.streams.iter().map(|s| s.batch.len()).sum::<usize>();
                 }
             }
+            Err(e) => self.give_up(batch.streams, &format!("as spilling failed: {}", e)),
         }
+    }
 
-        let mut detached_cache = std::mem::take(&mut self.cache);
-        for (level, batch) in detached_cache.iter_mut() {
-            if self.cache_limit.should_send(*level, batch) {
-                let batch = std::mem::take(batch);
-                let req = prepare_without_batch(&self.api_config, &self.host, &self.app, *level);
-                let local_proxy = self.api_config.proxy.clone();
-                let local_format = self.api_config.serialization_format;
-                self.sender = Some(Box::pin(local_proxy.proxy(req).and_then(
-                    move |r| async move {
-                        r.body(local_format.serialize(&batch)?)
-                            .send()
-                            .await?
-                            .error_for_status()?;
-                        Ok(())
-                    },
-                )));
+    /// Drops the logs, which end up in the report if the sender is shutting down
+    fn give_up(&mut self, streams: Vec<StreamBatch>, reason: &str) {
+        eprintln!(
+            "Dropping {} log messages {}",
+            streams.iter().map(|s| s.batch.len()).sum::<usize>(),
+            reason
+        );
+        if let Some((_, report)) = self.shutting_down.as_mut() {
+            report
+                .undelivered
+                .extend(streams.into_iter().flat_map(|stream| {
+                    let level = stream.level.into();
+                    stream.batch.into_values().map(move |data| (level, data))
+                }));
+        }
+    }
 
-                break;
+    /// Called once the server has the batch or refused it, so it isn't replayed again
+    fn finish(&mut self, id: ulid::Ulid, spilled: bool) {
+        if let (true, Some(spill)) = (spilled, self.spill.as_mut()) {
+            if let Err(e) = spill.remove(id) {
+                eprintln!("Error removing spilled batch {}: {}", id, e);
             }
         }
-        self.cache = detached_cache;
+    }
 
-        ops::ControlFlow::Continue(())
+    fn send(&mut self, batch: Batch) {
+        let req = prepare_request(&self.api_config, batch.id);
+        let local_proxy = self.api_config.proxy.clone();
+        let local_format = self.api_config.serialization_format;
+        let local_compression = self.api_config.compression;
+        // serialized up front, so the batch itself stays here while it is sent
+        let body = local_format.serialize(&batch.streams);
+        let id = batch.id;
+        self.in_flight_levels
+            .extend(batch.streams.iter().map(|s| s.level.clone()));
+        self.spill_in_flight |= batch.spilled;
+        self.in_flight_batches.insert(id, batch);
+        self.in_flight.push(Box::pin(async move {
+            let sent = match body {
+                Ok(body) => {
+                    send_streams(local_proxy, req, local_format, local_compression, body).await
+                }
+                Err(e) => Err(e),
+            };
+            (id, sent)
+        }));
+    }
+
+    fn handle_send_result(&mut self, id: ulid::Ulid, sent: Result<Vec<StreamResult>>) {
+        let batch = match self.in_flight_batches.remove(&id) {
+            Some(batch) => batch,
+            None => return,
+        };
+        for stream in &batch.streams {
+            self.in_flight_levels.remove(&stream.level);
+        }
+        if batch.spilled {
+            self.spill_in_flight = false;
+        }
+        let Batch {
+            id,
+            streams,
+            attempts,
+            spilled,
+        } = batch;
+        match sent {
+            Ok(results) if !answers(&streams, &results) => {
+                // which streams were stored can't be told, so they are all sent again
+                let batch = Batch {
+                    id,
+                    streams,
+                    attempts,
+                    spilled,
+                };
+                self.retry(
+                    batch,
+                    None,
+                    "the server's reply doesn't match the streams sent",
+                );
+            }
+            Ok(results) => {
+                // rows the server did store are acknowledged, so only the failed streams
+                // are sent again, under the same batch id
+                let mut failed = Vec::new();
+                let mut refused = Vec::new();
+                let mut retry_after = None;
+                for (stream, result) in streams.into_iter().zip(results) {
+                    if let Some(e) = result.error {
+                        if e.kind.is_retryable() {
+                            if let Some(wait) = e.retry_after_seconds {
+                                let wait = time::Duration::from_secs(wait);
+                                retry_after =
+                                    Some(retry_after.map_or(wait, |r: time::Duration| r.max(wait)));
+                            }
+                            failed.push(stream);
+                        } else {
+                            eprintln!("The server refused {} log messages: {}", stream.level, e);
+                            refused.push(stream);
+                        }
+                    }
+                }
+                if !refused.is_empty() {
+                    self.give_up(refused, "refused by the server");
+                }
+                if failed.is_empty() {
+                    self.finish(id, spilled);
+                } else {
+                    let failed = Batch {
+                        id,
+                        streams: failed,
+                        attempts,
+                        spilled,
+                    };
+                    self.retry(failed, retry_after, "the server failed to store them");
+                }
+            }
+            Err(e) if e.is_retryable() => {
+                let batch = Batch {
+                    id,
+                    streams,
+                    attempts,
+                    spilled,
+                };
+                self.retry(batch, e.retry_after(), &e.to_string());
+            }
+            Err(e) => {
+                self.give_up(streams, &format!("refused by the server: {}", e));
+                self.finish(id, spilled);
+            }
+        }
+    }
+
+    /// Puts the batch at the front of the retry queue, after waiting for however long the
+    /// server asked for or otherwise backing off. Rate limited batches don't use up attempts,
+    /// unless the server asks to wait longer than `max_backoff`, such as until a daily quota
+    /// resets, which counts as being out of attempts. Once out of attempts the batch goes to
+    /// the spill buffer, if there is one.
+    fn retry(&mut self, mut batch: Batch, retry_after: Option<time::Duration>, reason: &str) {
+        let wait = match retry_after {
+            Some(wait) if wait <= self.retry_policy.max_backoff => wait,
+            Some(wait) => {
+                let reason = format!("as the server asked to wait {:?}: {}", wait, reason);
+                self.out_of_attempts(batch, wait, &reason);
+                return;
+            }
+            None => {
+                batch.attempts += 1;
+                if batch.attempts >= self.retry_policy.max_attempts {
+                    let reason = format!("after {} attempts: {}", batch.attempts, reason);
+                    self.out_of_attempts(batch, self.retry_policy.max_backoff, &reason);
+                    return;
+                }
+                self.retry_policy.backoff(batch.attempts)
+            }
+        };
+        self.pause(wait);
+        self.retries.push_front(batch);
+    }
+
+    /// Spills the batch to be replayed from disk once the server is back, after the pause,
+    /// or otherwise gives up on it
+    fn out_of_attempts(&mut self, batch: Batch, pause: time::Duration, reason: &str) {
+        if self.spill.is_some() {
+            self.spill_batch(batch);
+            self.pause(pause);
+        } else {
+            self.give_up(batch.streams, reason);
+        }
     }
+
+    /// Stops sending for at least `wait`
+    fn pause(&mut self, wait: time::Duration) {
+        let resume_at = after(wait);
+        self.paused_until = Some(self.paused_until.map_or(resume_at, |p| p.max(resume_at)));
+    }
+}
+
+/// Whether the server replied with a result for each stream, in the order they were sent
+fn answers(streams: &[StreamBatch], results: &[StreamResult]) -> bool {
+    streams.len() == results.len()
+        && streams
+            .iter()
+            .zip(results)
+            .all(|(s, r)| s.host == r.host && s.app == r.app && s.level == r.level)
+}
+
+/// What woke the sender up
+enum Wake {
+    Message(Option<(log::Level, LogData)>),
+    Flush(Option<FlushRequest>),
+    Sent(Option<SendResult>),
+    Timer,
+    Shutdown(result::Result<ShutdownRequest, oneshot::Canceled>),
+    SpillRead(SpillRead),
 }
 
-fn prepare_without_batch<T>(
-    config: &ApiConfig<T>,
-    host: &Host,
-    app: &App,
-    level: log::Level,
-) -> reqwest::RequestBuilder
+/// The id of the batch sent, which stays with the sender so that it can be sent again if the
+/// server asks us to retry later
+type SendResult = (ulid::Ulid, Result<Vec<StreamResult>>);
+
+/// The id of the spilled batch read, along with its streams
+type SpillRead = (ulid::Ulid, Result<Vec<StreamBatch>>);
+
+async fn send_streams<T>(
+    proxy: sync::Arc<T>,
+    req: reqwest::RequestBuilder,
+    format: SerializationFormat,
+    compression: Option<Compression>,
+    body: Vec<u8>,
+) -> Result<Vec<StreamResult>>
 where
     T: ConnectionProxy,
 {
-    let url = format!(
-        "{base}/submit/{host}/{app}/{level}",
-        base = config.base_url,
-        host = host,
-        app = app,
-        level = level.to_string().to_lowercase()
-    );
-
-    config.client.post(url).header(
-        header::CONTENT_TYPE,
-        config.serialization_format.header_value(),
-    )
+    let req = proxy.proxy(req).await?;
+    let req = match compression {
+        Some(compression) => req
+            .header(header::CONTENT_ENCODING, compression.header_value())
+            .body(compression.compress(&body)?),
+        None => req.body(body),
+    };
+    let response = check_status(req.send().await?).await?;
+    format.deserialize(&read_body(response).await?)
+}
+
+fn prepare_request<T>(config: &ApiConfig<T>, batch_id: ulid::Ulid) -> reqwest::RequestBuilder
+where
+    T: ConnectionProxy,
+{
+    let url = format!("{base}/submit-batch", base = config.base_url);
+
+    config
+        .client
+        .post(url)
+        .header(
+            header::CONTENT_TYPE,
+            config.serialization_format.header_value(),
+        )
+        .header(header::ACCEPT, config.serialization_format.header_value())
+        .header(BATCH_ID_HEADER, batch_id.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_backoff() {
+        let policy = RetryPolicy::default();
+        for attempt in 1..20 {
+            let full =
+                (policy.initial_backoff * 2u32.pow(attempt.min(10) - 1)).min(policy.max_backoff);
+            let backoff = policy.backoff(attempt);
+            assert!(
+                backoff >= full / 2 && backoff <= full,
+                "{:?} for attempt {}",
+                backoff,
+                attempt
+            );
+        }
+
+        assert!(Error::UnexpectedStatus(503, String::new()).is_retryable());
+        assert!(!Error::UnexpectedStatus(400, String::new()).is_retryable());
+        assert!(!Error::Forbidden("tree".to_string()).is_retryable());
+        assert!(ErrorKind::Internal.is_retryable());
+        assert!(!ErrorKind::BadRequest.is_retryable());
+    }
+
+    fn data_sender() -> (Subscriber, DataSender<BasicProxy>, ShutdownHandle) {
+        let api_config = ApiConfig {
+            client: reqwest::Client::new(),
+            // nothing listens here, so every send fails and is retried
+            base_url: "http://127.0.0.1:1/log".parse().unwrap(),
+            proxy: BasicProxy::init("key".to_string()),
+            serialization_format: SerializationFormat::all().next().unwrap(),
+            compression: None,
+        };
+        Subscriber::new_remote(
+            Box::new(|_| {}),
+            api_config,
+            "host".parse().unwrap(),
+            "app".parse().unwrap(),
+            log::LevelFilter::Trace,
+            CacheLimit::default(),
+        )
+    }
+
+    #[test]
+    fn test_long_retry_after() {
+        let (_subscriber, mut data_sender, _shutdown_handle) = data_sender();
+        let batch = || Batch {
+            id: ulid::Ulid::new(),
+            streams: Vec::new(),
+            attempts: 0,
+            spilled: false,
+        };
+        let max_backoff = data_sender.retry_policy.max_backoff;
+
+        // a short wait is retried however often the server asks
+        data_sender.retry(batch(), Some(max_backoff), "rate limited");
+        assert_eq!(data_sender.retries.len(), 1);
+        assert_eq!(data_sender.retries[0].attempts, 0);
+
+        // waiting out a daily quota isn't, the batch is given up on without a spill buffer
+        data_sender.retry(batch(), Some(max_backoff * 60), "over the daily quota");
+        assert_eq!(data_sender.retries.len(), 1);
+    }
+
+    #[test]
+    fn test_unmatched_results_are_retried() {
+        let (_subscriber, mut data_sender, _shutdown_handle) = data_sender();
+        let stream = |level| StreamBatch {
+            host: "host".parse().unwrap(),
+            app: "app".parse().unwrap(),
+            level,
+            batch: iter::once((
+                ulid::Ulid::new(),
+                LogData {
+                    message: "message".to_string(),
+                    code_module: None,
+                    code_line: None,
+                    code_file: None,
+                    tags: Default::default(),
+                },
+            ))
+            .collect(),
+        };
+        let batch = Batch {
+            id: ulid::Ulid::new(),
+            streams: vec![stream(Level::Info), stream(Level::Warn)],
+            attempts: 0,
+            spilled: false,
+        };
+        let id = batch.id;
+        data_sender.in_flight_batches.insert(id, batch);
+
+        // a reply for only the first stream says nothing about the second
+        let results = vec![StreamResult {
+            host: "host".parse().unwrap(),
+            app: "app".parse().unwrap(),
+            level: Level::Info,
+            error: None,
+            ack: Default::default(),
+        }];
+        data_sender.handle_send_result(id, Ok(results));
+        assert_eq!(data_sender.retries.len(), 1);
+        assert_eq!(data_sender.retries[0].streams.len(), 2);
+    }
+
+    #[test]
+    fn test_drop_spills_in_flight() {
+        let dir = std::env::temp_dir().join(format!("eigenlog-spill-{}", ulid::Ulid::new()));
+        let (subscriber, data_sender, _shutdown_handle) = data_sender();
+        let mut data_sender = data_sender.with_spill(SpillBuffer::open(&dir, u64::MAX).unwrap());
+        log::Log::log(
+            &subscriber,
+            &log::Record::builder()
+                .level(log::Level::Error)
+                .args(format_args!("in flight"))
+                .build(),
+        );
+        data_sender.drain_receiver();
+        data_sender.start_sends();
+        assert_eq!(data_sender.in_flight.len(), 1);
+
+        // the send never completes, but its batch is kept for the next run
+        drop(data_sender);
+        assert_eq!(SpillBuffer::open(&dir, u64::MAX).unwrap().len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_drains_once_subscriber_dropped() {
+        // counts the sends, refusing each of them so nothing is retried
+        struct Refuse(sync::atomic::AtomicUsize);
+
+        #[async_trait::async_trait]
+        impl ConnectionProxy for Refuse {
+            async fn proxy(
+                self: sync::Arc<Self>,
+                _: reqwest::RequestBuilder,
+            ) -> Result<reqwest::RequestBuilder> {
+                self.0.fetch_add(1, sync::atomic::Ordering::SeqCst);
+                Err(Error::Custom("refused".to_string()))
+            }
+        }
+
+        let proxy = sync::Arc::new(Refuse(Default::default()));
+        let api_config = ApiConfig {
+            client: reqwest::Client::new(),
+            base_url: "http://127.0.0.1:1/log".parse().unwrap(),
+            proxy: proxy.clone(),
+            serialization_format: SerializationFormat::all().next().unwrap(),
+            compression: None,
+        };
+        let (subscriber, data_sender, _shutdown_handle) = Subscriber::new_remote(
+            Box::new(|_| {}),
+            api_config,
+            "host".parse().unwrap(),
+            "app".parse().unwrap(),
+            log::LevelFilter::Trace,
+            CacheLimit::default(),
+        );
+        log::Log::log(
+            &subscriber,
+            &log::Record::builder()
+                .level(log::Level::Trace)
+                .args(format_args!("cached"))
+                .build(),
+        );
+        drop(subscriber);
+
+        // the trace batch isn't due yet, but is sent before the sender stops
+        data_sender.run().await;
+        assert_eq!(proxy.0.load(sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_reports_undelivered() {
+        let (subscriber, data_sender, shutdown_handle) = data_sender();
+        for message in ["a", "b"] {
+            log::Log::log(
+                &subscriber,
+                &log::Record::builder()
+                    .level(log::Level::Info)
+                    .args(format_args!("{}", message))
+                    .build(),
+            );
+        }
+
+        let shutdown = shutdown_handle.shutdown(time::Duration::from_millis(200));
+        let (_, report) = future::join(data_sender.run(), shutdown).await;
+        let report = report.unwrap();
+        let mut messages = report
+            .undelivered
+            .iter()
+            .map(|(_, data)| data.message.as_str())
+            .collect::<Vec<_>>();
+        messages.sort();
+        assert_eq!(messages, ["a", "b"]);
+        assert_eq!((report.spilled, report.abandoned), (0, 0));
+
+        // the subscriber no longer takes records
+        assert!(subscriber
+            .sender
+            .send(log::Level::Info, report.undelivered[0].1.clone())
+            .is_err());
+    }
 }
@@ -0,0 +1,239 @@
+use super::*;
+use futures_util::FutureExt;
+use std::{fs, io::Write, path, pin};
+
+const EXTENSION: &str = "batch";
+const PARTIAL_EXTENSION: &str = "partial";
+
+/// An on-disk queue of batches the remote subscriber couldn't send, which is replayed oldest
+/// first once the server is reachable again.
+///
+/// Each batch is a file named after its batch id, so the queue survives restarts and the
+/// directory listing gives the order. The first line of a file is the media type the batch
+/// was written with, so the serialization format can change between runs. Once the files add
+/// up to more than `max_bytes`, the oldest are deleted.
+pub struct SpillBuffer {
+    dir: path::PathBuf,
+    max_bytes: u64,
+    files: collections::BTreeMap<ulid::Ulid, u64>,
+    total_bytes: u64,
+}
+
+impl SpillBuffer {
+    /// Creates the directory if needed and picks up any batches left from earlier runs
+    pub fn open(dir: impl Into<path::PathBuf>, max_bytes: u64) -> Result<SpillBuffer> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        let mut files = collections::BTreeMap::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            match path.extension().and_then(|e| e.to_str()) {
+                Some(EXTENSION) => {}
+                // left over from a write which didn't complete
+                Some(PARTIAL_EXTENSION) => {
+                    fs::remove_file(&path)?;
+                    continue;
+                }
+                _ => continue,
+            }
+            let id = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<ulid::Ulid>().ok());
+            if let Some(id) = id {
+                files.insert(id, fs::metadata(&path)?.len());
+            }
+        }
+
+        let mut spill = SpillBuffer {
+            dir,
+            max_bytes,
+            total_bytes: files.values().sum(),
+            files,
+        };
+        spill.enforce_limit();
+        Ok(spill)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    /// The size of all the spilled batches on disk
+    pub fn total_bytes(&self) -> u64 {
+        self.total_bytes
+    }
+
+    /// Writes the batch, replacing an earlier version of it, then drops the oldest batches
+    /// if the buffer is over its size
+    pub(crate) fn store(
+        &mut self,
+        id: ulid::Ulid,
+        format: SerializationFormat,
+        streams: &Vec<StreamBatch>,
+    ) -> Result<()> {
+        let mut contents = format
+            .header_value()
+            .to_str()
+            .unwrap_or_default()
+            .as_bytes()
+            .to_vec();
+        contents.push(b'\n');
+        contents.extend(format.serialize(streams)?);
+
+        // written under another name first, so a crash can't leave half a batch behind
+        let partial = self.path(id).with_extension(PARTIAL_EXTENSION);
+        let mut file = fs::File::create(&partial)?;
+        file.write_all(&contents)?;
+        file.sync_data()?;
+        fs::rename(&partial, self.path(id))?;
+
+        let len = contents.len() as u64;
+        if let Some(previous) = self.files.insert(id, len) {
+            self.total_bytes -= previous;
+        }
+        self.total_bytes += len;
+        self.enforce_limit();
+        Ok(())
+    }
+
+    /// Reads a batch, which stays on disk until it is removed. This happens on another
+    /// thread, so the sender isn't held up by the disk.
+    pub(crate) fn read(
+        &self,
+        id: ulid::Ulid,
+    ) -> pin::Pin<Box<dyn futures_util::Future<Output = Result<Vec<StreamBatch>>>>> {
+        let path = self.path(id);
+
+        #[cfg(not(feature = "wasm"))]
+        {
+            let (tx, rx) = futures_channel::oneshot::channel();
+            std::thread::spawn(move || {
+                // the sender may have stopped waiting
+                let _ = tx.send(load(id, &path));
+            });
+            Box::pin(rx.map(move |read| {
+                read.unwrap_or_else(|_| Err(Error::Custom(format!("reading batch {} failed", id))))
+            }))
+        }
+
+        // there are no threads to spare, nor files to read
+        #[cfg(feature = "wasm")]
+        Box::pin(futures_util::future::ready(load(id, &path)))
+    }
+
+    pub(crate) fn oldest_id(&self) -> Option<ulid::Ulid> {
+        self.files.keys().next().copied()
+    }
+
+    pub(crate) fn remove(&mut self, id: ulid::Ulid) -> Result<()> {
+        if let Some(len) = self.files.remove(&id) {
+            self.total_bytes -= len;
+            match fs::remove_file(self.path(id)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn enforce_limit(&mut self) {
+        while self.total_bytes > self.max_bytes {
+            let oldest = match self.files.keys().next() {
+                Some(id) => *id,
+                None => break,
+            };
+            eprintln!(
+                "Spill buffer is over {} bytes, dropping the batch from {}",
+                self.max_bytes,
+                oldest.datetime()
+            );
+            if let Err(e) = self.remove(oldest) {
+                eprintln!("Error removing spilled batch {}: {}", oldest, e);
+            }
+        }
+    }
+
+    fn path(&self, id: ulid::Ulid) -> path::PathBuf {
+        self.dir.join(format!("{}.{}", id, EXTENSION))
+    }
+}
+
+fn load(id: ulid::Ulid, path: &path::Path) -> Result<Vec<StreamBatch>> {
+    let contents = fs::read(path)?;
+    let (format, body) = contents
+        .iter()
+        .position(|b| *b == b'\n')
+        .map(|i| (&contents[..i], &contents[i + 1..]))
+        .ok_or_else(|| Error::Custom(format!("spilled batch {} has no media type", id)))?;
+    let format = str::from_utf8(format)
+        .map_err(|e| Error::Custom(e.to_string()))?
+        .parse::<SerializationFormat>()?;
+    format.deserialize(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_spill_buffer() {
+        let dir = std::env::temp_dir().join(format!("eigenlog-spill-{}", ulid::Ulid::new()));
+        let format = SerializationFormat::all().next().unwrap();
+        let streams = |message: &str| {
+            let mut generator = ulid::Generator::new();
+            vec![StreamBatch {
+                host: "host".parse().unwrap(),
+                app: "app".parse().unwrap(),
+                level: Level::Info,
+                batch: iter::once((
+                    generator.generate().unwrap(),
+                    LogData {
+                        message: message.to_string(),
+                        code_module: None,
+                        code_line: None,
+                        code_file: None,
+                        tags: collections::HashMap::new(),
+                    },
+                ))
+                .collect(),
+            }]
+        };
+
+        let mut generator = ulid::Generator::new();
+        let ids = (0..3)
+            .map(|_| generator.generate().unwrap())
+            .collect::<Vec<_>>();
+
+        let mut spill = SpillBuffer::open(&dir, u64::MAX).unwrap();
+        for (i, id) in ids.iter().enumerate() {
+            spill.store(*id, format, &streams(&i.to_string())).unwrap();
+        }
+        let size = spill.total_bytes() / 3;
+
+        // batches survive reopening and come back oldest first
+        let mut spill = SpillBuffer::open(&dir, u64::MAX).unwrap();
+        assert_eq!(spill.len(), 3);
+        let id = spill.oldest_id().unwrap();
+        assert_eq!(id, ids[0]);
+        let oldest = spill.read(id).await.unwrap();
+        assert_eq!(oldest[0].batch.values().next().unwrap().message, "0");
+        spill.remove(id).unwrap();
+        assert_eq!(spill.oldest_id(), Some(ids[1]));
+
+        // going over the size drops the oldest
+        let mut spill = SpillBuffer::open(&dir, size * 2).unwrap();
+        spill
+            .store(generator.generate().unwrap(), format, &streams("3"))
+            .unwrap();
+        assert_eq!(spill.len(), 2);
+        assert_eq!(spill.oldest_id(), Some(ids[2]));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,228 @@
+use crate::{subscriber::Subscriber, *};
+use tracing_crate as tracing;
+use tracing_subscriber::{layer, registry};
+
+/// A `tracing-subscriber` layer which turns events into log records for a [`Subscriber`],
+/// so that they are sent or saved by its `DataSender` or `DataSaver` along with those from
+/// the `log` crate.
+///
+/// The `message` field of an event becomes the message and its other fields become tags.
+/// The fields of the spans the event is in are tags too, prefixed with the span's name such
+/// as `request.id`, and the names of the spans are kept in the `spans` tag, outermost first
+/// and separated by `:`. Events are filtered by the subscriber's level, while spans are
+/// always recorded so other layers see them.
+pub struct Layer {
+    subscriber: Subscriber,
+}
+
+impl Layer {
+    pub fn new(subscriber: Subscriber) -> Layer {
+        Layer { subscriber }
+    }
+
+    /// Waits until everything logged so far has been sent or saved
+    pub fn flush(&self) {
+        log::Log::flush(&self.subscriber)
+    }
+}
+
+/// The fields of an event or span as strings, written the same way as the key-values of
+/// records from the `log` crate. Kept in the extensions of each span.
+#[derive(Default)]
+struct Fields(Vec<(String, String)>);
+
+impl tracing::field::Visit for Fields {
+    fn record_f64(&mut self, field: &tracing::field::Field, value: f64) {
+        self.0.push((field.name().to_string(), value.to_string()));
+    }
+
+    fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
+        self.0.push((field.name().to_string(), value.to_string()));
+    }
+
+    fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+        self.0.push((field.name().to_string(), value.to_string()));
+    }
+
+    fn record_bool(&mut self, field: &tracing::field::Field, value: bool) {
+        self.0.push((field.name().to_string(), value.to_string()));
+    }
+
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        self.0.push((field.name().to_string(), value.to_string()));
+    }
+
+    fn record_error(
+        &mut self,
+        field: &tracing::field::Field,
+        value: &(dyn error::Error + 'static),
+    ) {
+        self.0.push((field.name().to_string(), value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn fmt::Debug) {
+        self.0
+            .push((field.name().to_string(), format!("{:?}", value)));
+    }
+}
+
+impl<S> layer::Layer<S> for Layer
+where
+    S: tracing::Subscriber + for<'a> registry::LookupSpan<'a>,
+{
+    fn on_new_span(
+        &self,
+        attrs: &tracing::span::Attributes<'_>,
+        id: &tracing::span::Id,
+        ctx: layer::Context<'_, S>,
+    ) {
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(fields);
+        }
+    }
+
+    fn on_record(
+        &self,
+        id: &tracing::span::Id,
+        values: &tracing::span::Record<'_>,
+        ctx: layer::Context<'_, S>,
+    ) {
+        if let Some(span) = ctx.span(id) {
+            // later values come last, so they replace earlier ones once turned into tags
+            if let Some(fields) = span.extensions_mut().get_mut::<Fields>() {
+                values.record(fields);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &tracing::Event<'_>, ctx: layer::Context<'_, S>) {
+        let metadata = event.metadata();
+        let level = log_level(metadata.level());
+        let enabled = log::Log::enabled(
+            &self.subscriber,
+            &log::Metadata::builder()
+                .level(level)
+                .target(metadata.target())
+                .build(),
+        );
+        if !enabled {
+            return;
+        }
+
+        let mut tags = collections::HashMap::new();
+        let mut names = Vec::new();
+        for span in ctx
+            .event_scope(event)
+            .into_iter()
+            .flat_map(|s| s.from_root())
+        {
+            if let Some(fields) = span.extensions().get::<Fields>() {
+                for (key, value) in &fields.0 {
+                    tags.insert(format!("{}.{}", span.name(), key), value.clone());
+                }
+            }
+            names.push(span.name());
+        }
+        if !names.is_empty() {
+            tags.insert("spans".to_string(), names.join(":"));
+        }
+
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let mut message = String::new();
+        for (key, value) in fields.0 {
+            match key.as_str() {
+                "message" => message = value,
+                _ => {
+                    tags.insert(key, value);
+                }
+            }
+        }
+        tags.insert("target".to_string(), metadata.target().to_string());
+
+        self.subscriber.submit(
+            level,
+            LogData {
+                message,
+                code_module: metadata.module_path().map(ToString::to_string),
+                code_line: metadata.line(),
+                code_file: metadata.file().map(ToString::to_string),
+                tags,
+            },
+        );
+    }
+}
+
+fn log_level(level: &tracing::Level) -> log::Level {
+    match *level {
+        tracing::Level::ERROR => log::Level::Error,
+        tracing::Level::WARN => log::Level::Warn,
+        tracing::Level::INFO => log::Level::Info,
+        tracing::Level::DEBUG => log::Level::Debug,
+        tracing::Level::TRACE => log::Level::Trace,
+    }
+}
+
+#[cfg(all(test, feature = "local-subscriber", feature = "sled"))]
+mod tests {
+    use super::*;
+    use futures_util::future;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[tokio::test]
+    async fn test_tracing_layer() {
+        let storage = sled::Config::new().temporary(true).open().unwrap();
+        let host = "host".parse::<Host>().unwrap();
+        let app = "app".parse::<App>().unwrap();
+        let (subscriber, mut data_saver, shutdown_handle) = Subscriber::new_local(
+            Box::new(|_| {}),
+            host.clone(),
+            app.clone(),
+            log::LevelFilter::Info,
+            storage.clone(),
+            subscriber::CacheLimit::default(),
+        );
+
+        let registry = tracing_subscriber::registry().with(Layer::new(subscriber));
+        tracing::subscriber::with_default(registry, || {
+            let span =
+                tracing::info_span!("request", id = 7, path = "/a", user = tracing::field::Empty);
+            let _entered = span.enter();
+            span.record("user", "abc");
+            let _inner = tracing::debug_span!("query").entered();
+            tracing::info!(rows = 3, cached = false, "done in {}ms", 5);
+            tracing::debug!("not at this level");
+        });
+
+        let shutdown = shutdown_handle.shutdown(std::time::Duration::from_secs(5));
+        let (saved, report) = future::join(data_saver.run(), shutdown).await;
+        saved.unwrap();
+        assert!(report.unwrap().undelivered.is_empty());
+
+        let rows = storage
+            .open_tree(Level::Info.get_tree_name(&host, &app))
+            .unwrap()
+            .iter()
+            .values()
+            .map(|v| bincode_crate::deserialize::<LogData>(&v.unwrap()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(rows.len(), 1);
+        let data = &rows[0];
+        assert_eq!(data.message, "done in 5ms");
+        assert_eq!(data.code_module.as_deref(), Some(module_path!()));
+        assert!(data.code_line.is_some());
+        assert_eq!(data.tags["rows"], "3");
+        assert_eq!(data.tags["cached"], "false");
+        assert_eq!(data.tags["request.id"], "7");
+        assert_eq!(data.tags["request.path"], "/a");
+        assert_eq!(data.tags["request.user"], "abc");
+        assert_eq!(data.tags["spans"], "request:query");
+        assert_eq!(data.tags["target"], module_path!());
+        assert!(storage
+            .open_tree(Level::Debug.get_tree_name(&host, &app))
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -25,7 +25,6 @@ fn ci() -> Result<(), String> {
     for always in ["json", "bincode"]
         .into_iter()
         .powerset()
-        .into_iter()
         .filter(|s| !s.is_empty())
     {
         check_superset_wasm_with_features(&["wasm-client", "wasm-subscriber"], &always)?;
@@ -33,7 +32,6 @@ fn ci() -> Result<(), String> {
     for always in ["json", "bincode"]
         .into_iter()
         .powerset()
-        .into_iter()
         .filter(|s| !s.is_empty())
     {
         check_superset_with_features(
@@ -91,12 +89,7 @@ fn check_wasm_with_features(features: &[&str]) -> Result<(), String> {
 }
 
 fn check_superset_wasm_with_features(features: &[&str], always: &[&str]) -> Result<(), String> {
-    for set in features
-        .iter()
-        .powerset()
-        .into_iter()
-        .filter(|s| !s.is_empty())
-    {
+    for set in features.iter().powerset().filter(|s| !s.is_empty()) {
         check_wasm_with_features(
             set.iter()
                 .copied()
@@ -110,12 +103,7 @@ fn check_superset_wasm_with_features(features: &[&str], always: &[&str]) -> Resu
 }
 
 fn check_superset_with_features(features: &[&str], always: &[&str]) -> Result<(), String> {
-    for set in features
-        .iter()
-        .powerset()
-        .into_iter()
-        .filter(|s| !s.is_empty())
-    {
+    for set in features.iter().powerset().filter(|s| !s.is_empty()) {
         check_with_features(
             set.iter()
                 .copied()